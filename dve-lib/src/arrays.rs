@@ -0,0 +1,397 @@
+use crate::{DataExpr, Range};
+use num_rational::Rational64;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// An axis-aligned box in normalized frame coordinates (0..1 on both axes)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BBox {
+    pub x: f64,
+    pub y: f64,
+    pub w: f64,
+    pub h: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ArrayValue {
+    Null,
+    Bool(bool),
+    Num(Rational64),
+    Str(String),
+    Boxes(Vec<BBox>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ArrayData {
+    Inline(Vec<ArrayValue>),
+    /// A JSON file holding one value per element
+    Json(String),
+    /// A CSV file with a `frame` column and either a `value` column or `x,y,w,h[,label]` columns
+    Csv(String),
+    /// Object detector output with one entry per frame under `frames[i].results[model]`,
+    /// each detection holding normalized `xmin`, `ymin`, `xmax`, `ymax` and a class `name`
+    Detections {
+        path: String,
+        model: String,
+    },
+}
+
+/// A named array declared by a spec. Element `i` holds the value for times in
+/// `[start + i * step, start + (i + 1) * step)`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArrayDecl {
+    pub start: Rational64,
+    pub step: Rational64,
+    pub data: ArrayData,
+}
+
+#[derive(Debug)]
+pub struct ArrayError {
+    pub name: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for ArrayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "array {}: {}", self.name, self.message)
+    }
+}
+
+impl std::error::Error for ArrayError {}
+
+pub type Arrays = BTreeMap<String, ArrayDecl>;
+
+static NULL: ArrayValue = ArrayValue::Null;
+
+impl ArrayDecl {
+    pub fn is_loaded(&self) -> bool {
+        matches!(self.data, ArrayData::Inline(_))
+    }
+
+    /// Value covering time `t`, or `Null` outside of the array
+    pub fn get(&self, t: Rational64) -> &ArrayValue {
+        let values = match &self.data {
+            ArrayData::Inline(values) => values,
+            _ => panic!("array must be loaded before lookups"),
+        };
+        let idx = ((t - self.start) / self.step).floor().to_integer();
+        if idx < 0 {
+            return &NULL;
+        }
+        values.get(idx as usize).unwrap_or(&NULL)
+    }
+
+    /// Read file backed data into memory, resolving relative paths against `base_dir`
+    fn load(&mut self, name: &str, base_dir: &Path) -> Result<(), ArrayError> {
+        let err = |message: String| ArrayError {
+            name: name.to_string(),
+            message,
+        };
+
+        let values = match &self.data {
+            ArrayData::Inline(_) => return Ok(()),
+            ArrayData::Json(path) => {
+                let text = std::fs::read_to_string(base_dir.join(path))
+                    .map_err(|e| err(format!("failed to read {}: {}", path, e)))?;
+                let json: serde_json::Value = serde_json::from_str(&text)
+                    .map_err(|e| err(format!("failed to parse {}: {}", path, e)))?;
+                let elements = json
+                    .as_array()
+                    .ok_or_else(|| err(format!("{} is not a JSON array", path)))?;
+                elements
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| json_value(v).map_err(|e| err(format!("element {}: {}", i, e))))
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            ArrayData::Csv(path) => {
+                let text = std::fs::read_to_string(base_dir.join(path))
+                    .map_err(|e| err(format!("failed to read {}: {}", path, e)))?;
+                csv_values(&text).map_err(|e| err(format!("{}: {}", path, e)))?
+            }
+            ArrayData::Detections { path, model } => {
+                let text = std::fs::read_to_string(base_dir.join(path))
+                    .map_err(|e| err(format!("failed to read {}: {}", path, e)))?;
+                let json: serde_json::Value = serde_json::from_str(&text)
+                    .map_err(|e| err(format!("failed to parse {}: {}", path, e)))?;
+                detection_values(&json, model).map_err(|e| err(format!("{}: {}", path, e)))?
+            }
+        };
+
+        self.data = ArrayData::Inline(values);
+        Ok(())
+    }
+}
+
+/// Load every file backed array in `arrays`
+pub fn load_arrays(arrays: &mut Arrays, base_dir: &Path) -> Result<(), ArrayError> {
+    for (name, decl) in arrays.iter_mut() {
+        decl.load(name, base_dir)?;
+    }
+    Ok(())
+}
+
+fn json_box(v: &serde_json::Value) -> Result<BBox, String> {
+    let coord = |k: &str| {
+        v[k].as_f64()
+            .ok_or_else(|| format!("box is missing numeric `{}`", k))
+    };
+    Ok(BBox {
+        x: coord("x")?,
+        y: coord("y")?,
+        w: coord("w")?,
+        h: coord("h")?,
+        label: v["label"].as_str().map(|s| s.to_string()),
+    })
+}
+
+fn json_value(v: &serde_json::Value) -> Result<ArrayValue, String> {
+    use serde_json::Value;
+    match v {
+        Value::Null => Ok(ArrayValue::Null),
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap();
+            Rational64::approximate_float(n)
+                .map(ArrayValue::Num)
+                .ok_or_else(|| format!("{} can't be represented as a rational", n))
+        }
+        Value::String(s) => Ok(ArrayValue::Str(s.clone())),
+        Value::Object(_) => Ok(ArrayValue::Boxes(vec![json_box(v)?])),
+        Value::Array(boxes) => Ok(ArrayValue::Boxes(
+            boxes.iter().map(json_box).collect::<Result<_, _>>()?,
+        )),
+        Value::Bool(b) => Ok(ArrayValue::Bool(*b)),
+    }
+}
+
+fn detection_values(json: &serde_json::Value, model: &str) -> Result<Vec<ArrayValue>, String> {
+    let frames = json["frames"]
+        .as_array()
+        .ok_or("missing a `frames` array")?;
+    frames
+        .iter()
+        .enumerate()
+        .map(|(i, frame)| {
+            let detections = match frame["results"][model].as_array() {
+                Some(detections) if !detections.is_empty() => detections,
+                _ => return Ok(ArrayValue::Null),
+            };
+            let boxes = detections
+                .iter()
+                .map(|d| {
+                    let coord = |k: &str| {
+                        d[k].as_f64()
+                            .ok_or_else(|| format!("frame {} detection is missing `{}`", i, k))
+                    };
+                    let (xmin, ymin) = (coord("xmin")?, coord("ymin")?);
+                    Ok(BBox {
+                        x: xmin,
+                        y: ymin,
+                        w: coord("xmax")? - xmin,
+                        h: coord("ymax")? - ymin,
+                        label: d["name"].as_str().map(|s| s.to_string()),
+                    })
+                })
+                .collect::<Result<_, String>>()?;
+            Ok(ArrayValue::Boxes(boxes))
+        })
+        .collect()
+}
+
+fn csv_values(text: &str) -> Result<Vec<ArrayValue>, String> {
+    let mut lines = text.lines().filter(|l| !l.trim().is_empty());
+    let header: Vec<&str> = lines
+        .next()
+        .ok_or("empty file")?
+        .split(',')
+        .map(|c| c.trim())
+        .collect();
+    let column = |name: &str| header.iter().position(|c| *c == name);
+
+    let frame_col = column("frame").ok_or("missing a `frame` column")?;
+    let box_cols = match (column("x"), column("y"), column("w"), column("h")) {
+        (Some(x), Some(y), Some(w), Some(h)) => Some([x, y, w, h]),
+        _ => None,
+    };
+    let value_col = column("value");
+    let label_col = column("label");
+    if box_cols.is_none() && value_col.is_none() {
+        return Err("needs either a `value` column or `x,y,w,h` columns".to_string());
+    }
+
+    let mut out: Vec<ArrayValue> = vec![];
+    for (line_idx, line) in lines.enumerate() {
+        let row: Vec<&str> = line.split(',').map(|c| c.trim()).collect();
+        let cell = |i: usize| {
+            row.get(i)
+                .copied()
+                .ok_or_else(|| format!("row {} is too short", line_idx + 2))
+        };
+        let frame: usize = cell(frame_col)?
+            .parse()
+            .map_err(|_| format!("row {} has a bad frame number", line_idx + 2))?;
+        if out.len() <= frame {
+            out.resize(frame + 1, ArrayValue::Null);
+        }
+
+        if let Some([x, y, w, h]) = box_cols {
+            let num = |i: usize| -> Result<f64, String> {
+                cell(i)?
+                    .parse()
+                    .map_err(|_| format!("row {} has a bad coordinate", line_idx + 2))
+            };
+            let bbox = BBox {
+                x: num(x)?,
+                y: num(y)?,
+                w: num(w)?,
+                h: num(h)?,
+                label: match label_col {
+                    Some(l) => Some(cell(l)?.to_string()).filter(|s| !s.is_empty()),
+                    None => None,
+                },
+            };
+            match &mut out[frame] {
+                ArrayValue::Boxes(boxes) => boxes.push(bbox),
+                v => *v = ArrayValue::Boxes(vec![bbox]),
+            }
+        } else {
+            let raw = cell(value_col.unwrap())?;
+            out[frame] = match raw
+                .parse::<f64>()
+                .ok()
+                .and_then(Rational64::approximate_float)
+            {
+                Some(n) => ArrayValue::Num(n),
+                None => ArrayValue::Str(raw.to_string()),
+            };
+        }
+    }
+    Ok(out)
+}
+
+impl DataExpr {
+    /// Value of this expression at time `t`
+    pub fn eval(&self, t: Rational64, arrays: &Arrays) -> ArrayValue {
+        match self {
+            DataExpr::ConstNum(n) => ArrayValue::Num(*n),
+            DataExpr::ConstStr(s) => ArrayValue::Str(s.clone()),
+            DataExpr::ConstBool(b) => ArrayValue::Bool(*b),
+            DataExpr::ArrayIdx(name, idx) => arrays
+                .get(name)
+                .map(|a| a.get(idx.eval(t)).clone())
+                .unwrap_or(ArrayValue::Null),
+        }
+    }
+}
+
+/// Split `domain` into runs of frames over which every arg evaluates to the same value,
+/// returning each run with its args replaced by constants.
+pub(crate) fn resolve_args(
+    domain: &Range,
+    args: &[DataExpr],
+    arrays: &Arrays,
+) -> Vec<(Range, Vec<ArrayValue>)> {
+    if !args.iter().any(|a| matches!(a, DataExpr::ArrayIdx(..))) {
+        let t = domain.start;
+        return vec![(
+            domain.clone(),
+            args.iter().map(|a| a.eval(t, arrays)).collect(),
+        )];
+    }
+
+    let mut out: Vec<(Range, Vec<ArrayValue>)> = vec![];
+    for t in domain.frame_times() {
+        let values: Vec<ArrayValue> = args.iter().map(|a| a.eval(t, arrays)).collect();
+        match out.last_mut() {
+            Some((run, run_values)) if *run_values == values => run.end = t + domain.step,
+            _ => out.push((
+                Range {
+                    start: t,
+                    end: t + domain.step,
+                    step: domain.step,
+                },
+                values,
+            )),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Spec;
+
+    #[test]
+    fn loads_sparse_csv_boxes() {
+        let values =
+            csv_values("frame,x,y,w,h,label\n2,0.1,0.2,0.3,0.4,zebra\n2,0.5,0.5,0.1,0.1,\n")
+                .unwrap();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[0], ArrayValue::Null);
+        match &values[2] {
+            ArrayValue::Boxes(boxes) => {
+                assert_eq!(boxes.len(), 2);
+                assert_eq!(boxes[0].label.as_deref(), Some("zebra"));
+                assert_eq!(boxes[1].label, None);
+            }
+            v => panic!("expected boxes, got {:?}", v),
+        }
+
+        let values = csv_values("frame,value\n0,gblur=sigma=30\n1,2.5\n").unwrap();
+        assert_eq!(values[0], ArrayValue::Str("gblur=sigma=30".to_string()));
+        assert_eq!(values[1], ArrayValue::Num(Rational64::new(5, 2)));
+    }
+
+    #[test]
+    fn loads_detector_output() {
+        let json = serde_json::json!({"frames": [
+            {"results": {"yolov5m": []}},
+            {"results": {"yolov5m": [
+                {"xmin": 0.25, "ymin": 0.5, "xmax": 0.75, "ymax": 1.0, "name": "zebra"}
+            ]}},
+        ]});
+        let values = detection_values(&json, "yolov5m").unwrap();
+        assert_eq!(values[0], ArrayValue::Null);
+        assert_eq!(
+            values[1],
+            ArrayValue::Boxes(vec![BBox {
+                x: 0.25,
+                y: 0.5,
+                w: 0.5,
+                h: 0.5,
+                label: Some("zebra".to_string()),
+            }])
+        );
+        assert!(detection_values(&json, "yolov5s").unwrap()[1] == ArrayValue::Null);
+    }
+
+    #[test]
+    fn resolves_filter_args_per_frame() {
+        let spec: Spec = r#"array fx = inline(0, 1, ["hflip", "hflip", null]);
+            Iter=Range(0, 4, 1/2);
+            Render=Filter(vid<clip.mp4>[t], fx[t]);
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+
+        // the declaration round trips through Display
+        let reparsed: Spec = spec.to_string().parse().unwrap();
+        assert_eq!(reparsed.arrays.len(), 1);
+
+        let args = vec![DataExpr::ArrayIdx(
+            "fx".to_string(),
+            Box::new(crate::TExpr::T),
+        )];
+        let runs = resolve_args(&spec.iter, &args, &spec.arrays);
+        assert_eq!(
+            runs.iter()
+                .map(|(r, v)| format!("{} {}", r, v[0]))
+                .collect::<Vec<_>>(),
+            vec!["Range(0, 2, 1/2) \"hflip\"", "Range(2, 4, 1/2) null"]
+        );
+    }
+}
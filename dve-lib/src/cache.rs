@@ -0,0 +1,401 @@
+//! Outputs of earlier runs, kept so sub-plans that would render the same thing can be skipped.
+//!
+//! Entries are named by a fingerprint of everything that decides what an op renders: the op
+//! itself with its output path left out, the size and modification time of every source it
+//! reads, and for intermediate files, the fingerprint of the op that wrote them.
+
+use crate::{DOp, Op, Plan, SeekOffsets};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const INDEX_FILE: &str = "index.json";
+
+#[derive(Serialize, Deserialize, Default)]
+struct CacheIndex {
+    /// Bumped on every use, so the entry with the lowest `last_used` is the least recently used
+    clock: u64,
+    entries: BTreeMap<String, CacheEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    /// File name inside the cache directory
+    file: String,
+    bytes: u64,
+    last_used: u64,
+}
+
+/// A directory of rendered op outputs, limited to `max_bytes` by evicting the least recently
+/// used ones. Only one process should use a cache directory at a time.
+pub struct OpCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<CacheIndex>,
+    /// Entries a plan is going to copy out, which mustn't be evicted before it runs
+    pinned: Mutex<BTreeSet<String>>,
+}
+
+impl OpCache {
+    pub fn open(dir: &Path, max_bytes: u64) -> std::io::Result<OpCache> {
+        std::fs::create_dir_all(dir)?;
+        let mut index: CacheIndex = match std::fs::read_to_string(dir.join(INDEX_FILE)) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|err| {
+                warn!("Starting over with an empty cache, couldn't read its index: {err}");
+                CacheIndex::default()
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => CacheIndex::default(),
+            Err(err) => return Err(err),
+        };
+        // files deleted by hand are just misses
+        index.entries.retain(|_, e| dir.join(&e.file).exists());
+
+        let cache = OpCache {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            index: Mutex::new(index),
+            pinned: Mutex::new(BTreeSet::new()),
+        };
+        // the limit may have shrunk since the last run
+        let mut index = cache.index.lock().unwrap();
+        cache.evict(&mut index);
+        cache.save(&index);
+        drop(index);
+        Ok(cache)
+    }
+
+    /// Bytes of rendered output held
+    pub fn size(&self) -> u64 {
+        let index = self.index.lock().unwrap();
+        index.entries.values().map(|e| e.bytes).sum()
+    }
+
+    /// Path of the output cached under `key`, which stays until this cache is dropped
+    fn get(&self, key: &str) -> Option<PathBuf> {
+        let mut index = self.index.lock().unwrap();
+        index.clock += 1;
+        let clock = index.clock;
+        let entry = index.entries.get_mut(key)?;
+        entry.last_used = clock;
+        let path = self.dir.join(&entry.file);
+        self.pinned.lock().unwrap().insert(key.to_string());
+        self.save(&index);
+        Some(path)
+    }
+
+    /// Copy `file` into the cache under `key`, then evict down to the size limit
+    fn put(&self, key: &str, file: &Path) {
+        let bytes = match std::fs::metadata(file) {
+            Ok(metadata) => metadata.len(),
+            Err(err) => {
+                warn!("Not caching {}: {err}", file.display());
+                return;
+            }
+        };
+        if bytes > self.max_bytes {
+            debug!("Not caching {}, it's bigger than the cache", file.display());
+            return;
+        }
+        let name = match file.extension() {
+            Some(ext) => format!("{key}.{}", ext.to_string_lossy()),
+            None => key.to_string(),
+        };
+        if let Err(err) = std::fs::copy(file, self.dir.join(&name)) {
+            warn!("Not caching {}: {err}", file.display());
+            return;
+        }
+
+        let mut index = self.index.lock().unwrap();
+        index.clock += 1;
+        let last_used = index.clock;
+        index.entries.insert(
+            key.to_string(),
+            CacheEntry {
+                file: name,
+                bytes,
+                last_used,
+            },
+        );
+        self.evict(&mut index);
+        self.save(&index);
+    }
+
+    fn evict(&self, index: &mut CacheIndex) {
+        let pinned = self.pinned.lock().unwrap();
+        let mut total: u64 = index.entries.values().map(|e| e.bytes).sum();
+        while total > self.max_bytes {
+            let Some(victim) = index
+                .entries
+                .iter()
+                .filter(|(key, _)| !pinned.contains(*key))
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            let entry = index.entries.remove(&victim).unwrap();
+            debug!("Evicting {} from the cache", entry.file);
+            if let Err(err) = std::fs::remove_file(self.dir.join(&entry.file)) {
+                warn!("Couldn't remove {} from the cache: {err}", entry.file);
+            }
+            total -= entry.bytes;
+        }
+    }
+
+    fn save(&self, index: &CacheIndex) {
+        let text = serde_json::to_string(index).unwrap();
+        if let Err(err) = std::fs::write(self.dir.join(INDEX_FILE), text) {
+            warn!("Couldn't save the cache index: {err}");
+        }
+    }
+}
+
+/// FNV-1a, run with two offset bases for 128 bits. It only has to be stable across builds,
+/// which `std`'s hasher isn't.
+pub(crate) struct Fnv([u64; 2]);
+
+impl Fnv {
+    pub(crate) fn new() -> Fnv {
+        Fnv([0xcbf29ce484222325, 0x6c62272e07bb0142])
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        for hash in &mut self.0 {
+            for byte in bytes {
+                *hash ^= *byte as u64;
+                *hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+    }
+
+    pub(crate) fn hex(&self) -> String {
+        format!("{:016x}{:016x}", self.0[0], self.0[1])
+    }
+}
+
+fn fingerprint(text: &str) -> String {
+    let mut hash = Fnv::new();
+    hash.update(text.as_bytes());
+    hash.hex()
+}
+
+/// Fingerprint `dop` and every op under it, recording them by output path in `keys`. Ops that
+/// read a file that doesn't exist yet and isn't written by one of their deps aren't cacheable.
+fn op_keys(dop: &DOp, seeks: &SeekOffsets, keys: &mut BTreeMap<String, String>) -> Option<String> {
+    let mut dep_keys = BTreeMap::new();
+    let mut cacheable = true;
+    for dep in &dop.deps {
+        match op_keys(dep, seeks, keys) {
+            Some(key) => {
+                dep_keys.insert(dep.op.out().to_string(), key);
+            }
+            None => cacheable = false,
+        }
+    }
+    if !cacheable {
+        return None;
+    }
+
+    let mut op = dop.op.clone();
+    *op.out_mut() = String::new();
+    for input in op.inputs_mut() {
+        let identity = match dep_keys.get(input.as_str()) {
+            Some(key) => format!("op {key}"),
+            None => {
+                let metadata = std::fs::metadata(input.as_str()).ok()?;
+                let modified = metadata
+                    .modified()
+                    .ok()?
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .ok()?;
+                format!("file {input} {} {:?}", metadata.len(), modified)
+            }
+        };
+        // where reads of an input are shifted to changes what they see too
+        *input = match seeks.get(input.as_str()) {
+            Some(offset) => format!("{identity} seeked by {offset}"),
+            None => identity,
+        };
+    }
+    let key = fingerprint(&format!("{:?}", op));
+    keys.insert(dop.op.out().to_string(), key.clone());
+    Some(key)
+}
+
+/// Swap sub-plans with a cached output for a copy of it, top down so the biggest ones win
+fn substitute_cached(dop: DOp, cache: &OpCache, keys: &BTreeMap<String, String>) -> DOp {
+    let out = dop.op.out().to_string();
+    if let Some(file) = keys.get(&out).and_then(|key| cache.get(key)) {
+        debug!("Using cached {} for {}", file.display(), out);
+        return DOp {
+            op: Op::Cached {
+                file: file.to_string_lossy().to_string(),
+                out,
+            },
+            deps: vec![],
+        };
+    }
+    DOp {
+        op: dop.op,
+        deps: dop
+            .deps
+            .into_iter()
+            .map(|dep| substitute_cached(dep, cache, keys))
+            .collect(),
+    }
+}
+
+impl Plan {
+    /// Replace sub-plans `cache` already holds the output of with copies of it. The rest are
+    /// added to the cache as they're rendered by `run_cached`.
+    pub fn use_cache(mut self, cache: &OpCache) -> Plan {
+        let mut keys = BTreeMap::new();
+        op_keys(&self.op, &self.seek_offsets, &mut keys);
+        self.op = substitute_cached(self.op, cache, &keys);
+        self.cache_keys = keys;
+        self
+    }
+
+    pub fn run_cached(&self, parallel: bool, cache: &OpCache) {
+        self.op.run(
+            parallel,
+            &self.seek_offsets,
+            Some((cache, &self.cache_keys)),
+        );
+    }
+}
+
+impl DOp {
+    /// Store what this op rendered, if `use_cache` fingerprinted it
+    pub(crate) fn cache_output(&self, cache: &OpCache, keys: &BTreeMap<String, String>) {
+        if matches!(self.op, Op::Cached { .. }) {
+            return;
+        }
+        let out = self.op.out();
+        if let Some(key) = keys.get(out) {
+            cache.put(key, Path::new(out));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FFmpegClipMethod, OutputConfig, Range};
+    use num_rational::Rational64;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("v2v-cache-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn clip(input: &str, start: i64, out: &str) -> DOp {
+        DOp {
+            op: Op::FFmpegClip {
+                input: input.to_string(),
+                range: Range {
+                    start: Rational64::from_integer(start),
+                    end: Rational64::from_integer(start + 10),
+                    step: Rational64::new(1, 24),
+                },
+                out: out.to_string(),
+                method: FFmpegClipMethod::Transcode,
+                config: OutputConfig::default(),
+            },
+            deps: vec![],
+        }
+    }
+
+    fn plan(source: &Path, scratch: [&str; 2], out: &str) -> Plan {
+        let source = source.to_string_lossy();
+        Plan {
+            op: DOp {
+                op: Op::FFmpegConcat {
+                    inputs: scratch.iter().map(|s| s.to_string()).collect(),
+                    out: out.to_string(),
+                },
+                deps: vec![clip(&source, 0, scratch[0]), clip(&source, 20, scratch[1])],
+            },
+            seek_offsets: SeekOffsets::new(),
+            cache_keys: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used_outputs() {
+        let dir = temp_dir();
+        let cache = OpCache::open(&dir.join("cache"), 25).unwrap();
+        for (name, bytes) in [("a", 10), ("b", 10), ("c", 10)] {
+            let file = dir.join(format!("{name}.mp4"));
+            std::fs::write(&file, vec![0; bytes]).unwrap();
+            cache.put(name, &file);
+            if name == "a" {
+                // reading "a" makes "b" the oldest
+                assert!(cache.get("a").is_some());
+            }
+        }
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.size(), 20);
+        assert!(cache.get("a").unwrap().exists());
+        assert!(!dir.join("cache/b.mp4").exists());
+
+        // reopening keeps what was there, and applies a smaller limit
+        drop(cache);
+        let cache = OpCache::open(&dir.join("cache"), 15).unwrap();
+        // "a" was read after "c" was added
+        assert_eq!(cache.size(), 10);
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn substitutes_cached_sub_plans() {
+        let dir = temp_dir();
+        let source = dir.join("source.mp4");
+        std::fs::write(&source, b"frames").unwrap();
+        let cache = OpCache::open(&dir.join("cache"), 1 << 20).unwrap();
+
+        // the same plan with different intermediate files has the same fingerprints
+        let cold = plan(&source, ["/scratch/a.mp4", "/scratch/b.mp4"], "out.mp4").use_cache(&cache);
+        let warm = plan(&source, ["/scratch/c.mp4", "/scratch/d.mp4"], "out.mp4");
+        let mut warm_keys = BTreeMap::new();
+        op_keys(&warm.op, &warm.seek_offsets, &mut warm_keys);
+        assert_eq!(cold.cache_keys["out.mp4"], warm_keys["out.mp4"]);
+        assert_eq!(
+            cold.cache_keys["/scratch/b.mp4"],
+            warm_keys["/scratch/d.mp4"]
+        );
+        assert_ne!(
+            cold.cache_keys["/scratch/a.mp4"],
+            cold.cache_keys["/scratch/b.mp4"]
+        );
+
+        // pretend the first clip was rendered
+        let rendered = dir.join("a.mp4");
+        std::fs::write(&rendered, b"clip").unwrap();
+        cache.put(&cold.cache_keys["/scratch/a.mp4"], &rendered);
+        let warm = warm.use_cache(&cache);
+        match &warm.op.deps[..] {
+            [DOp {
+                op: Op::Cached { out, .. },
+                ..
+            }, DOp {
+                op: Op::FFmpegClip { .. },
+                ..
+            }] => assert_eq!(out, "/scratch/c.mp4"),
+            deps => panic!("expected a cached first clip, got {:?}", deps),
+        }
+
+        // changing the source invalidates everything read from it
+        std::fs::write(&source, b"other frames").unwrap();
+        let changed =
+            plan(&source, ["/scratch/a.mp4", "/scratch/b.mp4"], "out.mp4").use_cache(&cache);
+        assert_ne!(changed.cache_keys["out.mp4"], cold.cache_keys["out.mp4"]);
+        assert!(matches!(changed.op.deps[0].op, Op::FFmpegClip { .. }));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,4 +1,7 @@
-use crate::{DOp, DataExpr, FrameExpr, Op, Plan, Range, SourceType, Spec, TExpr};
+use crate::{
+    ArrayData, ArrayDecl, ArrayValue, DOp, DataExpr, Diagnostic, DiagnosticKind, FrameExpr, Op,
+    OutputConfig, Plan, Range, Severity, SourceType, Spec, TExpr,
+};
 use num_rational::Rational64;
 
 fn pretty_frac(n: Rational64) -> String {
@@ -9,6 +12,10 @@ fn pretty_frac(n: Rational64) -> String {
     }
 }
 
+fn escape_str(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
 impl std::fmt::Display for Plan {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Plan({})", self.op)
@@ -40,22 +47,48 @@ impl std::fmt::Display for Op {
             Op::FFmpegClip {
                 input,
                 range,
-                out,
                 method,
-                codec,
+                ..
             } => {
                 write!(
                     f,
                     "FFmpegClip({:?} clip on {} from {} to {})",
                     method,
                     input,
-                    crate::ffmpeg_time(&range.start, false),
-                    crate::ffmpeg_time(&range.end, false)
+                    crate::ffmpeg_time(&range.start),
+                    crate::ffmpeg_time(&range.end)
+                )
+            }
+            Op::FFmpegRetime {
+                input,
+                range,
+                out_step,
+                ..
+            } => {
+                write!(
+                    f,
+                    "FFmpegRetime({}x on {} from {} to {})",
+                    pretty_frac(range.step / out_step),
+                    input,
+                    crate::ffmpeg_time(&range.start),
+                    crate::ffmpeg_time(&range.end)
                 )
             }
-            Op::FFmpegConcat { inputs, out } => {
+            Op::FFmpegFreeze {
+                input, t, frames, ..
+            } => {
+                write!(
+                    f,
+                    "FFmpegFreeze({} frames of {} at {})",
+                    frames,
+                    input,
+                    crate::ffmpeg_time(t)
+                )
+            }
+            Op::FFmpegConcat { .. } => {
                 write!(f, "FFmpegConcat(...)")
             }
+            Op::Cached { file, .. } => write!(f, "Cached({})", file),
             _ => write!(f, "{:?}", self),
         }
     }
@@ -69,6 +102,20 @@ impl std::fmt::Display for TExpr {
             TExpr::Add(texpr, t) => write!(f, "({} + {})", texpr, t),
             TExpr::Sub(texpr, t) => write!(f, "({} - {})", texpr, t),
             TExpr::Mul(texpr, t) => write!(f, "({} * {})", texpr, t),
+            TExpr::Mod(texpr, d) => write!(f, "({} % {})", texpr, d),
+            TExpr::Floor(texpr, q) => write!(f, "floor({}, {})", texpr, q),
+            TExpr::Remap(texpr, keyframes) => {
+                write!(f, "remap({}, [", texpr)?;
+                let mut comma = false;
+                for (from, to) in keyframes {
+                    if comma {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{} -> {}", from, to)?;
+                    comma = true;
+                }
+                write!(f, "])")
+            }
         }
     }
 }
@@ -133,16 +180,111 @@ impl std::fmt::Display for DataExpr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             DataExpr::ConstNum(n) => write!(f, "{}", pretty_frac(*n)),
-            DataExpr::ConstStr(s) => write!(f, "\"{}\"", s),
+            DataExpr::ConstStr(s) => write!(f, "\"{}\"", escape_str(s)),
             DataExpr::ConstBool(b) => write!(f, "{}", b),
             DataExpr::ArrayIdx(name, idx) => write!(f, "{}[{}]", name, idx),
         }
     }
 }
 
+impl std::fmt::Display for ArrayValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArrayValue::Null => write!(f, "null"),
+            ArrayValue::Bool(b) => write!(f, "{}", b),
+            ArrayValue::Num(n) => write!(f, "{}", pretty_frac(*n)),
+            ArrayValue::Str(s) => write!(f, "\"{}\"", escape_str(s)),
+            ArrayValue::Boxes(boxes) => {
+                write!(f, "[")?;
+                let mut comma = false;
+                for b in boxes {
+                    if comma {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "box({}, {}, {}, {}", b.x, b.y, b.w, b.h)?;
+                    if let Some(label) = &b.label {
+                        write!(f, ", \"{}\"", escape_str(label))?;
+                    }
+                    write!(f, ")")?;
+                    comma = true;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ArrayDecl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (start, step) = (pretty_frac(self.start), pretty_frac(self.step));
+        match &self.data {
+            ArrayData::Json(path) => {
+                write!(f, "json(\"{}\", {}, {})", escape_str(path), start, step)
+            }
+            ArrayData::Csv(path) => write!(f, "csv(\"{}\", {}, {})", escape_str(path), start, step),
+            ArrayData::Detections { path, model } => write!(
+                f,
+                "detections(\"{}\", \"{}\", {}, {})",
+                escape_str(path),
+                escape_str(model),
+                start,
+                step
+            ),
+            ArrayData::Inline(values) => {
+                write!(f, "inline({}, {}, [", start, step)?;
+                let mut comma = false;
+                for value in values {
+                    if comma {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                    comma = true;
+                }
+                write!(f, "])")
+            }
+        }
+    }
+}
+
 impl std::fmt::Display for Spec {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Iter={};Render={})", self.iter, self.render,)
+        for (name, decl) in &self.arrays {
+            write!(f, "array {} = {};", name, decl)?;
+        }
+        write!(
+            f,
+            "Iter={};Render={};Output=\"{}\"",
+            self.iter,
+            self.render,
+            escape_str(&self.output)
+        )?;
+        if !self.output_config.is_default() {
+            write!(f, "{}", self.output_config)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for OutputConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{width={}, height={}, pix_fmt=\"{}\", codec=\"{}\"",
+            self.width,
+            self.height,
+            escape_str(&self.pix_fmt),
+            self.codec.name()
+        )?;
+        if let Some(crf) = self.crf {
+            write!(f, ", crf={}", crf)?;
+        }
+        if let Some(bitrate) = &self.bitrate {
+            write!(f, ", bitrate=\"{}\"", escape_str(bitrate))?;
+        }
+        if let Some(preset) = &self.preset {
+            write!(f, ", preset=\"{}\"", escape_str(preset))?;
+        }
+        write!(f, ", container=\"{}\"}}", self.container.name())
     }
 }
 
@@ -157,3 +299,130 @@ impl std::fmt::Display for Range {
         )
     }
 }
+
+impl std::fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiagnosticKind::UnalignedRange { range } => {
+                write!(f, "{} does not contain a whole number of steps", range)
+            }
+            DiagnosticKind::EmptyRange { range } => write!(f, "{} is empty", range),
+            DiagnosticKind::UnknownSource { source } => {
+                write!(f, "source \"{}\" is not in the datastore", source)
+            }
+            DiagnosticKind::SourceOutOfBounds {
+                source,
+                requested,
+                available,
+            } => write!(
+                f,
+                "source \"{}\" is read over {} but only {} exists",
+                source, requested, available
+            ),
+            DiagnosticKind::EmptyMatch => write!(f, "match has no cases"),
+            DiagnosticKind::CaseStepMismatch { case, expected } => write!(
+                f,
+                "case {} does not use the enclosing step of {}",
+                case,
+                pretty_frac(*expected)
+            ),
+            DiagnosticKind::CaseOutsideDomain { case, domain } => {
+                write!(f, "case {} extends outside of {}", case, domain)
+            }
+            DiagnosticKind::CaseGap { start, end } => write!(
+                f,
+                "no case covers t in [{}, {})",
+                pretty_frac(*start),
+                pretty_frac(*end)
+            ),
+            DiagnosticKind::CaseOverlap { start, end } => write!(
+                f,
+                "multiple cases cover t in [{}, {})",
+                pretty_frac(*start),
+                pretty_frac(*end)
+            ),
+            DiagnosticKind::F2fArity {
+                func,
+                min,
+                max,
+                found,
+            } => match max {
+                Some(max) if max == min => write!(
+                    f,
+                    "{:?} takes {} source(s) but was given {}",
+                    func, min, found
+                ),
+                Some(max) => write!(
+                    f,
+                    "{:?} takes {} to {} sources but was given {}",
+                    func, min, max, found
+                ),
+                None => write!(
+                    f,
+                    "{:?} takes at least {} source(s) but was given {}",
+                    func, min, found
+                ),
+            },
+            DiagnosticKind::GridTooSmall { cells, sources } => write!(
+                f,
+                "grid has {} cell(s) but was given {} sources",
+                cells, sources
+            ),
+            DiagnosticKind::F2fArgCount {
+                func,
+                min,
+                max,
+                found,
+            } => {
+                if min == max {
+                    write!(
+                        f,
+                        "{:?} takes {} argument(s) but was given {}",
+                        func, min, found
+                    )
+                } else {
+                    write!(
+                        f,
+                        "{:?} takes {} to {} arguments but was given {}",
+                        func, min, max, found
+                    )
+                }
+            }
+            DiagnosticKind::F2fArgType {
+                func,
+                index,
+                expected,
+            } => write!(f, "{:?} argument {} must be {}", func, index, expected),
+            DiagnosticKind::BadTimeExpr { expr, reason } => {
+                write!(f, "time expression {}: {}", expr, reason)
+            }
+            DiagnosticKind::BadCrop { t, reason } => {
+                write!(f, "crop rectangle at t={} {}", pretty_frac(*t), reason)
+            }
+            DiagnosticKind::BadOutputConfig { reason } => {
+                write!(f, "invalid output settings: {}", reason)
+            }
+            DiagnosticKind::UnknownDims { source } => write!(
+                f,
+                "{} has no dimensions in the datastore, re-add it to crop it",
+                source
+            ),
+            DiagnosticKind::UnknownArray { name } => {
+                write!(f, "array \"{}\" is not declared by the spec", name)
+            }
+            DiagnosticKind::ArrayNotLoaded { name } => {
+                write!(f, "array \"{}\" was never loaded", name)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{} at {}: {}", severity, self.location, self.kind)
+    }
+}
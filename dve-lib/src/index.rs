@@ -0,0 +1,907 @@
+//! Keyframe discovery straight from MP4 and Matroska indexes, without running ffprobe.
+//!
+//! MP4 sample tables (`stts`/`ctts`/`stss`, shifted by `elst`) already list every sample's time
+//! and whether it's a sync sample, so only the `moov` box is read. Matroska has no sample table,
+//! so cluster and block headers are walked, skipping over the frame data itself.
+
+use crate::meta::{first_presented, frame_gops, presented_end, Edit};
+use crate::{Range, SourceGopBound};
+use num_rational::Rational64;
+use num_traits::Signed;
+use std::io::{Read, Seek, SeekFrom};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexError {
+    pub message: String,
+}
+
+impl std::fmt::Display for IndexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "container index: {}", self.message)
+    }
+}
+
+impl std::error::Error for IndexError {}
+
+impl From<std::io::Error> for IndexError {
+    fn from(err: std::io::Error) -> Self {
+        IndexError {
+            message: err.to_string(),
+        }
+    }
+}
+
+type IResult<T> = Result<T, IndexError>;
+
+fn error<T>(message: impl Into<String>) -> IResult<T> {
+    Err(IndexError {
+        message: message.into(),
+    })
+}
+
+/// Presentation times and keyframe flags of every frame in a video track
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerIndex {
+    /// Seconds per timestamp tick
+    pub time_base: Rational64,
+    /// Frame duration, if the container declares one
+    pub step: Option<Rational64>,
+    /// `(pts, keyframe)` in container order, after any edit list is applied
+    pub frames: Vec<(i64, bool)>,
+    /// The track's MP4 edit list
+    pub edits: Vec<Edit>,
+}
+
+impl ContainerIndex {
+    /// The same bounds `load_meta` finds from an ffprobe of the file
+    pub fn gops(&self) -> (Range, Vec<SourceGopBound>) {
+        let step = self.step.unwrap_or_else(|| {
+            let mut times: Vec<i64> = self.frames.iter().map(|f| f.0).collect();
+            times.sort();
+            self.time_base * times.into_iter().find(|t| *t > 0).unwrap()
+        });
+        let frames: Vec<(Rational64, bool)> = self
+            .frames
+            .iter()
+            .map(|(pts, key)| (self.time_base * *pts, *key))
+            .collect();
+        let origin = first_presented(&frames, &self.edits);
+        frame_gops(frames, step, origin, presented_end(&self.edits))
+    }
+
+    /// The frames as ffprobe `-show_packets` entries in `time_base`
+    pub(crate) fn packets_json(&self, time_base: Rational64) -> serde_json::Value {
+        self.frames
+            .iter()
+            .map(|(pts, key)| {
+                serde_json::json!({
+                    "pts": (self.time_base * *pts / time_base).round().to_integer(),
+                    "flags": if *key { "K__" } else { "___" },
+                })
+            })
+            .collect()
+    }
+}
+
+/// Read the index of an MP4/MOV or Matroska/WebM file, whichever it turns out to be
+pub fn read_index(path: &std::path::Path) -> IResult<ContainerIndex> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    if magic[..4] == [0x1a, 0x45, 0xdf, 0xa3] {
+        read_mkv(&mut file)
+    } else if &magic[4..] == b"ftyp" {
+        read_mp4(&mut file)
+    } else {
+        error(format!("{} isn't an MP4 or Matroska file", path.display()))
+    }
+}
+
+/// Frame rates cameras and encoders actually use, for snapping rounded frame durations
+const NOMINAL_RATES: [(i64, i64); 11] = [
+    (24000, 1001),
+    (24, 1),
+    (25, 1),
+    (30000, 1001),
+    (30, 1),
+    (48, 1),
+    (50, 1),
+    (60000, 1001),
+    (60, 1),
+    (100, 1),
+    (120, 1),
+];
+
+/// `duration` rounded to a container's precision, replaced by the nominal frame duration it's
+/// within 0.1% of
+fn nominal_step(duration: Rational64) -> Rational64 {
+    NOMINAL_RATES
+        .iter()
+        .map(|(num, den)| Rational64::new(*den, *num))
+        .find(|step| ((*step - duration) / duration).abs() < Rational64::new(1, 1000))
+        .unwrap_or(duration)
+}
+
+fn be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u64)
+}
+
+/// Bounds-checked reads from a box payload
+struct Payload<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Payload<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Payload { data, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> IResult<&'a [u8]> {
+        if self.pos + n > self.data.len() {
+            return error("box is truncated");
+        }
+        self.pos += n;
+        Ok(&self.data[self.pos - n..self.pos])
+    }
+
+    fn u32(&mut self) -> IResult<u32> {
+        Ok(be(self.take(4)?) as u32)
+    }
+
+    fn u64(&mut self) -> IResult<u64> {
+        Ok(be(self.take(8)?))
+    }
+
+    /// Version byte of a full box, skipping its flags
+    fn version(&mut self) -> IResult<u8> {
+        let version = self.take(4)?[0];
+        Ok(version)
+    }
+}
+
+/// `(type, payload)` of each box in `data`
+fn boxes(data: &[u8]) -> IResult<Vec<([u8; 4], &[u8])>> {
+    let mut out = vec![];
+    let mut p = Payload::new(data);
+    while p.pos < data.len() {
+        let start = p.pos;
+        let size = p.u32()? as u64;
+        let kind: [u8; 4] = p.take(4)?.try_into().unwrap();
+        let size = match size {
+            0 => (data.len() - start) as u64,
+            1 => p.u64()?,
+            size => size,
+        };
+        let header = (p.pos - start) as u64;
+        if size < header || start as u64 + size > data.len() as u64 {
+            return error(format!(
+                "{} box has a bad size",
+                String::from_utf8_lossy(&kind)
+            ));
+        }
+        out.push((kind, &data[p.pos..start + size as usize]));
+        p.pos = start + size as usize;
+    }
+    Ok(out)
+}
+
+fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> IResult<Option<&'a [u8]>> {
+    Ok(boxes(data)?.into_iter().find(|b| &b.0 == kind).map(|b| b.1))
+}
+
+fn require<'a>(data: &'a [u8], kind: &[u8; 4]) -> IResult<&'a [u8]> {
+    match child(data, kind)? {
+        Some(payload) => Ok(payload),
+        None => error(format!("missing {} box", String::from_utf8_lossy(kind))),
+    }
+}
+
+/// Read the `moov` box without touching the media data around it
+fn read_moov<R: Read + Seek>(file: &mut R) -> IResult<Vec<u8>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut pos = 0;
+    while pos + 8 <= len {
+        file.seek(SeekFrom::Start(pos))?;
+        let mut header = [0u8; 16];
+        file.read_exact(&mut header[..8])?;
+        let (size, header_len) = match be(&header[..4]) {
+            0 => (len - pos, 8),
+            1 => {
+                file.read_exact(&mut header[8..])?;
+                (be(&header[8..]), 16)
+            }
+            size => (size, 8),
+        };
+        if size < header_len || size > len - pos {
+            return error("top level box has a bad size");
+        }
+        if &header[4..8] == b"moov" {
+            let mut moov = vec![0u8; (size - header_len) as usize];
+            file.read_exact(&mut moov)?;
+            return Ok(moov);
+        }
+        pos += size;
+    }
+    error("no moov box")
+}
+
+fn read_mp4<R: Read + Seek>(file: &mut R) -> IResult<ContainerIndex> {
+    let moov = read_moov(file)?;
+
+    let mut mvhd = Payload::new(require(&moov, b"mvhd")?);
+    // skip the creation and modification times
+    let times = if mvhd.version()? == 1 { 16 } else { 8 };
+    mvhd.take(times)?;
+    let movie_timescale = mvhd.u32()? as i64;
+
+    let mut video = None;
+    for (kind, trak) in boxes(&moov)? {
+        if &kind != b"trak" {
+            continue;
+        }
+        let mdia = require(trak, b"mdia")?;
+        let mut hdlr = Payload::new(require(mdia, b"hdlr")?);
+        hdlr.version()?;
+        hdlr.take(4)?;
+        if hdlr.take(4)? == b"vide" {
+            video = Some((trak, mdia));
+            break;
+        }
+    }
+    let Some((trak, mdia)) = video else {
+        return error("no video track");
+    };
+
+    let mut mdhd = Payload::new(require(mdia, b"mdhd")?);
+    let times = if mdhd.version()? == 1 { 16 } else { 8 };
+    mdhd.take(times)?;
+    let timescale = mdhd.u32()? as i64;
+    if timescale == 0 || movie_timescale == 0 {
+        return error("zero timescale");
+    }
+
+    let stbl = require(require(mdia, b"minf")?, b"stbl")?;
+
+    // decode times
+    let mut stts = Payload::new(require(stbl, b"stts")?);
+    stts.version()?;
+    let mut deltas = vec![];
+    // real sample tables spend more than a byte of the moov box on every sample, so counts past
+    // that are corrupt rather than something to allocate for
+    let max_samples = moov.len();
+    for _ in 0..stts.u32()? {
+        let (count, delta) = (stts.u32()? as usize, stts.u32()? as i64);
+        if count > max_samples - deltas.len() {
+            return error("stts box has more samples than the moov box can describe");
+        }
+        deltas.extend(std::iter::repeat_n(delta, count));
+    }
+    if deltas.is_empty() {
+        return error("no samples in the moov box, fragmented MP4s aren't supported");
+    }
+
+    // composition offsets, for B-frames
+    let mut offsets = vec![0i64; deltas.len()];
+    if let Some(ctts) = child(stbl, b"ctts")? {
+        let mut ctts = Payload::new(ctts);
+        ctts.version()?;
+        let mut i = 0;
+        for _ in 0..ctts.u32()? {
+            let count = ctts.u32()? as usize;
+            // version 0 offsets are unsigned on paper but written signed in practice
+            let offset = ctts.u32()? as i32 as i64;
+            for slot in offsets.iter_mut().skip(i).take(count) {
+                *slot = offset;
+            }
+            i = i.saturating_add(count);
+        }
+    }
+
+    // without a sync sample table every sample is a keyframe
+    let mut keyframes = vec![true; deltas.len()];
+    if let Some(stss) = child(stbl, b"stss")? {
+        let mut stss = Payload::new(stss);
+        stss.version()?;
+        keyframes = vec![false; deltas.len()];
+        for _ in 0..stss.u32()? {
+            let sample = stss.u32()? as usize;
+            if sample >= 1 && sample <= keyframes.len() {
+                keyframes[sample - 1] = true;
+            }
+        }
+    }
+
+    // Like ffmpeg, empty edits delay the track and the real edit picks where in the media
+    // presentation starts
+    let mut shift = 0i64;
+    let mut edits = vec![];
+    let elst = match child(trak, b"edts")? {
+        Some(edts) => child(edts, b"elst")?,
+        None => None,
+    };
+    if let Some(elst) = elst {
+        let mut elst = Payload::new(elst);
+        let version = elst.version()?;
+        for _ in 0..elst.u32()? {
+            let (duration, media_time) = match version {
+                1 => (elst.u64()? as i64, elst.u64()? as i64),
+                _ => (elst.u32()? as i64, elst.u32()? as i32 as i64),
+            };
+            // media rate
+            elst.take(4)?;
+            if media_time != -1 && edits.iter().any(|e: &Edit| e.media_time.is_some()) {
+                // ffmpeg splices these back together, which sample tables alone can't
+                return error("edit lists with more than one edit aren't supported");
+            }
+            if media_time == -1 {
+                shift += duration * timescale / movie_timescale;
+            } else {
+                shift -= media_time;
+            }
+            edits.push(Edit {
+                duration: Rational64::new(duration, movie_timescale),
+                media_time: (media_time != -1).then(|| Rational64::new(media_time, timescale)),
+            });
+        }
+    }
+
+    let mut dts = 0i64;
+    let mut frames = Vec::with_capacity(deltas.len());
+    for i in 0..deltas.len() {
+        frames.push((dts + offsets[i] + shift, keyframes[i]));
+        dts += deltas[i];
+    }
+
+    // the most common sample duration is the frame duration
+    let mut counts = std::collections::BTreeMap::new();
+    for delta in &deltas {
+        *counts.entry(*delta).or_insert(0usize) += 1;
+    }
+    let delta = counts.into_iter().max_by_key(|(_, n)| *n).unwrap().0;
+
+    Ok(ContainerIndex {
+        time_base: Rational64::new(1, timescale),
+        step: (delta > 0).then(|| Rational64::new(delta, timescale)),
+        frames,
+        edits,
+    })
+}
+
+const EBML_SEGMENT: u64 = 0x18538067;
+const EBML_INFO: u64 = 0x1549a966;
+const EBML_TIMECODE_SCALE: u64 = 0x2ad7b1;
+const EBML_TRACKS: u64 = 0x1654ae6b;
+const EBML_TRACK_ENTRY: u64 = 0xae;
+const EBML_TRACK_NUMBER: u64 = 0xd7;
+const EBML_TRACK_TYPE: u64 = 0x83;
+const EBML_DEFAULT_DURATION: u64 = 0x23e383;
+const EBML_CLUSTER: u64 = 0x1f43b675;
+const EBML_CLUSTER_TIMECODE: u64 = 0xe7;
+const EBML_SIMPLE_BLOCK: u64 = 0xa3;
+const EBML_BLOCK_GROUP: u64 = 0xa0;
+const EBML_BLOCK: u64 = 0xa1;
+const EBML_REFERENCE_BLOCK: u64 = 0xfb;
+const EBML_CUES: u64 = 0x1c53bb6b;
+const EBML_CUE_POINT: u64 = 0xbb;
+const EBML_CUE_TIME: u64 = 0xb3;
+const EBML_CUE_TRACK_POSITIONS: u64 = 0xb7;
+const EBML_CUE_TRACK: u64 = 0xf7;
+
+/// Element size meaning "until the parent ends"
+const UNKNOWN_SIZE: u64 = u64::MAX;
+
+/// An EBML variable length integer. IDs keep their length marker, sizes don't.
+fn read_vint<R: Read>(r: &mut R, keep_marker: bool) -> IResult<Option<(u64, usize)>> {
+    let mut first = [0u8; 1];
+    if r.read(&mut first)? == 0 {
+        return Ok(None);
+    }
+    let len = first[0].leading_zeros() as usize + 1;
+    if len > 8 {
+        return error("bad EBML integer");
+    }
+    let mut rest = [0u8; 7];
+    r.read_exact(&mut rest[..len - 1])?;
+    let marker = if keep_marker {
+        first[0]
+    } else {
+        first[0] & 0xffu8.checked_shr(len as u32).unwrap_or(0)
+    };
+    let value = rest[..len - 1]
+        .iter()
+        .fold(marker as u64, |acc, b| (acc << 8) | *b as u64);
+    // all ones means the size is unknown
+    let all_ones = (1u64 << (7 * len)) - 1;
+    if !keep_marker && value == all_ones {
+        return Ok(Some((UNKNOWN_SIZE, len)));
+    }
+    Ok(Some((value, len)))
+}
+
+fn read_uint<R: Read>(r: &mut R, size: u64) -> IResult<u64> {
+    if size > 8 {
+        return error("EBML integer is too long");
+    }
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes[..size as usize])?;
+    Ok(be(&bytes[..size as usize]))
+}
+
+#[derive(Default)]
+struct MkvState {
+    timecode_scale: Option<u64>,
+    /// `(number, default duration in ns)` of the first video track
+    video_track: Option<(u64, Option<u64>)>,
+    cluster_timecode: i64,
+    /// `(track, pts, keyframe)` of every block
+    blocks: Vec<(u64, i64, bool)>,
+    /// `(track, time)` of every cue point
+    cues: Vec<(u64, i64)>,
+}
+
+/// Walk the children of an element in `[pos, end)`, calling `visit` with each child's ID, the
+/// position of its data and its size. `visit` returns whether it consumed the data itself.
+fn walk<R: Read + Seek>(
+    r: &mut R,
+    mut pos: u64,
+    end: u64,
+    visit: &mut dyn FnMut(&mut R, u64, u64, u64) -> IResult<()>,
+) -> IResult<()> {
+    while pos < end {
+        r.seek(SeekFrom::Start(pos))?;
+        let Some((id, id_len)) = read_vint(r, true)? else {
+            break;
+        };
+        let Some((size, size_len)) = read_vint(r, false)? else {
+            break;
+        };
+        let data = pos + (id_len + size_len) as u64;
+        let size = if size == UNKNOWN_SIZE {
+            if id != EBML_SEGMENT {
+                return error("elements of unknown size other than the segment aren't supported");
+            }
+            end - data
+        } else {
+            size
+        };
+        visit(r, id, data, size)?;
+        pos = data + size;
+    }
+    Ok(())
+}
+
+fn read_mkv<R: Read + Seek>(file: &mut R) -> IResult<ContainerIndex> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut state = MkvState::default();
+
+    fn segment<R: Read + Seek>(
+        r: &mut R,
+        state: &mut MkvState,
+        id: u64,
+        data: u64,
+        size: u64,
+    ) -> IResult<()> {
+        match id {
+            EBML_INFO => walk(r, data, data + size, &mut |r, id, _, size| {
+                if id == EBML_TIMECODE_SCALE {
+                    state.timecode_scale = Some(read_uint(r, size)?);
+                }
+                Ok(())
+            }),
+            EBML_TRACKS => walk(r, data, data + size, &mut |r, id, data, size| {
+                if id != EBML_TRACK_ENTRY {
+                    return Ok(());
+                }
+                let (mut number, mut kind, mut duration) = (None, None, None);
+                walk(r, data, data + size, &mut |r, id, _, size| {
+                    match id {
+                        EBML_TRACK_NUMBER => number = Some(read_uint(r, size)?),
+                        EBML_TRACK_TYPE => kind = Some(read_uint(r, size)?),
+                        EBML_DEFAULT_DURATION => duration = Some(read_uint(r, size)?),
+                        _ => {}
+                    }
+                    Ok(())
+                })?;
+                if let (Some(number), Some(1), None) = (number, kind, state.video_track) {
+                    state.video_track = Some((number, duration));
+                }
+                Ok(())
+            }),
+            EBML_CLUSTER => walk(r, data, data + size, &mut |r, id, data, size| {
+                match id {
+                    EBML_CLUSTER_TIMECODE => state.cluster_timecode = read_uint(r, size)? as i64,
+                    EBML_SIMPLE_BLOCK => {
+                        let (track, pts, flags) = block_header(r, state.cluster_timecode)?;
+                        state.blocks.push((track, pts, flags & 0x80 != 0));
+                    }
+                    // blocks in groups are keyframes unless they reference another block
+                    EBML_BLOCK_GROUP => {
+                        let cluster_timecode = state.cluster_timecode;
+                        let (mut block, mut references) = (None, false);
+                        walk(r, data, data + size, &mut |r, id, _, _| {
+                            match id {
+                                EBML_BLOCK => block = Some(block_header(r, cluster_timecode)?),
+                                EBML_REFERENCE_BLOCK => references = true,
+                                _ => {}
+                            }
+                            Ok(())
+                        })?;
+                        if let Some((track, pts, _)) = block {
+                            state.blocks.push((track, pts, !references));
+                        }
+                    }
+                    _ => {}
+                }
+                Ok(())
+            }),
+            EBML_CUES => walk(r, data, data + size, &mut |r, id, data, size| {
+                if id != EBML_CUE_POINT {
+                    return Ok(());
+                }
+                let (mut time, mut tracks) = (None, vec![]);
+                walk(r, data, data + size, &mut |r, id, data, size| {
+                    match id {
+                        EBML_CUE_TIME => time = Some(read_uint(r, size)? as i64),
+                        EBML_CUE_TRACK_POSITIONS => {
+                            walk(r, data, data + size, &mut |r, id, _, size| {
+                                if id == EBML_CUE_TRACK {
+                                    tracks.push(read_uint(r, size)?);
+                                }
+                                Ok(())
+                            })?
+                        }
+                        _ => {}
+                    }
+                    Ok(())
+                })?;
+                if let Some(time) = time {
+                    state
+                        .cues
+                        .extend(tracks.into_iter().map(|track| (track, time)));
+                }
+                Ok(())
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    let mut found_segment = false;
+    walk(file, 0, len, &mut |r, id, data, size| {
+        if id == EBML_SEGMENT {
+            found_segment = true;
+            walk(r, data, data + size, &mut |r, id, data, size| {
+                segment(r, &mut state, id, data, size)
+            })?;
+        }
+        Ok(())
+    })?;
+    if !found_segment {
+        return error("no Matroska segment");
+    }
+
+    let Some((track, default_duration)) = state.video_track else {
+        return error("no video track");
+    };
+    let timecode_scale = state.timecode_scale.unwrap_or(1_000_000) as i64;
+    let time_base = Rational64::new(timecode_scale, 1_000_000_000);
+
+    // Seeks land on cue points, so when there are cues only cued keyframes start GOPs
+    let cued: std::collections::BTreeSet<i64> = state
+        .cues
+        .iter()
+        .filter(|(t, _)| *t == track)
+        .map(|(_, time)| *time)
+        .collect();
+    let frames: Vec<(i64, bool)> = state
+        .blocks
+        .into_iter()
+        .filter(|(t, _, _)| *t == track)
+        .map(|(_, pts, key)| (pts, key && (cued.is_empty() || cued.contains(&pts))))
+        .collect();
+    if frames.is_empty() {
+        return error("no video blocks");
+    }
+
+    Ok(ContainerIndex {
+        time_base,
+        step: default_duration.map(|ns| nominal_step(Rational64::new(ns as i64, 1_000_000_000))),
+        frames,
+        // Matroska has no edit lists, timestamps are already presentation times
+        edits: vec![],
+    })
+}
+
+/// Track, timestamp and flags of a block, whose header starts at the reader's position
+fn block_header<R: Read>(r: &mut R, cluster_timecode: i64) -> IResult<(u64, i64, u8)> {
+    let Some((track, _)) = read_vint(r, false)? else {
+        return error("block is truncated");
+    };
+    let mut header = [0u8; 3];
+    r.read_exact(&mut header)?;
+    let relative = i16::from_be_bytes([header[0], header[1]]) as i64;
+    Ok((track, cluster_timecode + relative, header[2]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::parse_meta;
+
+    /// `(pts index, keyframe)` of each frame in decode order: two GOPs with B-frames
+    const DECODE_ORDER: [(i64, bool); 10] = [
+        (0, true),
+        (3, false),
+        (1, false),
+        (2, false),
+        (6, false),
+        (4, false),
+        (5, false),
+        (7, true),
+        (9, false),
+        (8, false),
+    ];
+
+    fn ffprobe_gops(time_base: &str, pts: &dyn Fn(i64) -> i64) -> (Range, Vec<SourceGopBound>) {
+        let packets: Vec<serde_json::Value> = DECODE_ORDER
+            .iter()
+            .map(|(i, key)| {
+                serde_json::json!({"pts": pts(*i), "flags": if *key { "K_" } else { "__" }})
+            })
+            .collect();
+        let meta = parse_meta(&serde_json::json!({
+            "streams": [{
+                "codec_type": "video",
+                "codec_name": "h264",
+                "time_base": time_base,
+                "r_frame_rate": "30000/1001",
+            }],
+            "packets": packets,
+        }));
+        (meta.range, meta.gops)
+    }
+
+    fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut out = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend(kind);
+        out.extend(payload);
+        out
+    }
+
+    fn full_box(kind: &[u8; 4], version: u8, fields: &[u32]) -> Vec<u8> {
+        let mut payload = vec![version, 0, 0, 0];
+        for field in fields {
+            payload.extend(field.to_be_bytes());
+        }
+        mp4_box(kind, &payload)
+    }
+
+    fn trak(handler: &[u8; 4], stbl: &[Vec<u8>], edts: Option<Vec<u8>>) -> Vec<u8> {
+        let hdlr = {
+            let mut payload = vec![0; 8];
+            payload.extend(handler);
+            payload.extend([0; 12]);
+            mp4_box(b"hdlr", &payload)
+        };
+        let mdia = [
+            full_box(b"mdhd", 0, &[0, 0, 30000, 0, 0]),
+            hdlr,
+            mp4_box(b"minf", &mp4_box(b"stbl", &stbl.concat())),
+        ]
+        .concat();
+        let mut children = vec![];
+        if let Some(edts) = edts {
+            children.push(edts);
+        }
+        children.push(mp4_box(b"mdia", &mdia));
+        mp4_box(b"trak", &children.concat())
+    }
+
+    /// An MP4 of `DECODE_ORDER` at 30000/1001, with `elst` as its video track's edit list
+    fn mp4_file(elst: &[u32]) -> Vec<u8> {
+        let mut ctts = vec![DECODE_ORDER.len() as u32];
+        for (i, (pts, _)) in DECODE_ORDER.iter().enumerate() {
+            // composition is one frame behind decode, which the edit list takes back out
+            ctts.extend([1, ((pts + 1 - i as i64) * 1001) as u32]);
+        }
+        let keyframes: Vec<u32> = DECODE_ORDER
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, key))| *key)
+            .map(|(i, _)| i as u32 + 1)
+            .collect();
+        let video = trak(
+            b"vide",
+            &[
+                full_box(b"stts", 0, &[1, DECODE_ORDER.len() as u32, 1001]),
+                full_box(b"ctts", 0, &ctts),
+                full_box(
+                    b"stss",
+                    0,
+                    &[&[keyframes.len() as u32][..], &keyframes].concat(),
+                ),
+            ],
+            Some(mp4_box(b"edts", &full_box(b"elst", 0, elst))),
+        );
+        let audio = trak(b"soun", &[full_box(b"stts", 0, &[1, 5, 1024])], None);
+        let moov = [full_box(b"mvhd", 0, &[0, 0, 1000, 0]), audio, video].concat();
+        [
+            mp4_box(b"ftyp", b"isom\0\0\0\0"),
+            mp4_box(b"mdat", &[0xab; 64]),
+            mp4_box(b"moov", &moov),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn reads_mp4_sample_tables_like_ffprobe() {
+        // one second in the 1000 movie timescale, starting one frame into the media
+        let file = mp4_file(&[1, 1000, 1001, 1 << 16]);
+
+        let index = read_mp4(&mut std::io::Cursor::new(file)).unwrap();
+        assert_eq!(index.time_base, Rational64::new(1, 30000));
+        assert_eq!(index.step, Some(Rational64::new(1001, 30000)));
+        assert_eq!(index.gops(), ffprobe_gops("1/30000", &|i| i * 1001));
+    }
+
+    #[test]
+    fn applies_mp4_edit_lists() {
+        let step = Rational64::new(1001, 30000);
+        // half a second of nothing, then 200ms starting at the second frame
+        let file = mp4_file(&[2, 500, u32::MAX, 1 << 16, 200, 2002, 1 << 16]);
+
+        let index = read_mp4(&mut std::io::Cursor::new(file)).unwrap();
+        assert_eq!(
+            index.edits,
+            vec![
+                Edit {
+                    duration: Rational64::new(1, 2),
+                    media_time: None,
+                },
+                Edit {
+                    duration: Rational64::new(1, 5),
+                    media_time: Some(Rational64::new(2002, 30000)),
+                },
+            ]
+        );
+        let (range, gops) = index.gops();
+        assert_eq!(range.start, Rational64::from_integer(0));
+        // 200ms is six frames, and the frame cut from the start still begins the GOP
+        assert_eq!(range.end, step * 6);
+        let gops: Vec<(Rational64, Rational64)> = gops.iter().map(|g| (g.start, g.end)).collect();
+        assert_eq!(gops, vec![(-step, step * 5)]);
+
+        // ffmpeg splices multiple edits together, so those are left to ffprobe
+        let file = mp4_file(&[2, 100, 0, 1 << 16, 100, 5005, 1 << 16]);
+        assert!(read_mp4(&mut std::io::Cursor::new(file)).is_err());
+    }
+
+    #[test]
+    fn rejects_corrupt_mp4_sizes() {
+        // a moov box claiming to run past the end of the file
+        let mut file = mp4_file(&[1, 1000, 1001, 1 << 16]);
+        let kind = file.windows(4).rposition(|w| w == b"moov").unwrap();
+        file[kind - 4..kind].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(read_mp4(&mut std::io::Cursor::new(file)).is_err());
+
+        // four billion samples in a few bytes of sample table
+        let video = trak(
+            b"vide",
+            &[full_box(b"stts", 0, &[2, 1, 1001, u32::MAX, 1001])],
+            None,
+        );
+        let moov = [full_box(b"mvhd", 0, &[0, 0, 1000, 0]), video].concat();
+        let file = mp4_box(b"moov", &moov);
+        assert!(read_mp4(&mut std::io::Cursor::new(file)).is_err());
+    }
+
+    fn ebml(id: u64, payload: &[u8]) -> Vec<u8> {
+        let mut out: Vec<u8> = id
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        // always an 8 byte size
+        out.push(0x01);
+        out.extend(&(payload.len() as u64).to_be_bytes()[1..]);
+        out.extend(payload);
+        out
+    }
+
+    fn ebml_uint(id: u64, value: u64) -> Vec<u8> {
+        ebml(id, &value.to_be_bytes())
+    }
+
+    fn block(track: u8, relative: i16, flags: u8) -> Vec<u8> {
+        let mut out = vec![0x80 | track];
+        out.extend(relative.to_be_bytes());
+        out.push(flags);
+        out.extend([0xcd; 16]);
+        out
+    }
+
+    #[test]
+    fn reads_mkv_blocks_like_ffprobe() {
+        // Matroska times are rounded to the millisecond
+        let ms = |i: i64| (Rational64::new(1001, 30) * i).round().to_integer();
+
+        let mut clusters = vec![];
+        for gop in DECODE_ORDER.chunks(7) {
+            let cluster_time = ms(gop[0].0);
+            let mut children = vec![ebml_uint(EBML_CLUSTER_TIMECODE, cluster_time as u64)];
+            for (i, key) in gop {
+                let relative = (ms(*i) - cluster_time) as i16;
+                if *key || i % 2 == 0 {
+                    let flags = if *key { 0x80 } else { 0 };
+                    children.push(ebml(EBML_SIMPLE_BLOCK, &block(1, relative, flags)));
+                } else {
+                    // keyframe-ness of grouped blocks comes from their references
+                    children.push(ebml(
+                        EBML_BLOCK_GROUP,
+                        &[
+                            ebml(EBML_BLOCK, &block(1, relative, 0)),
+                            ebml_uint(EBML_REFERENCE_BLOCK, 1),
+                        ]
+                        .concat(),
+                    ));
+                }
+                // audio blocks are all keyframes and must be ignored
+                children.push(ebml(EBML_SIMPLE_BLOCK, &block(2, relative, 0x80)));
+            }
+            clusters.push(ebml(EBML_CLUSTER, &children.concat()));
+        }
+        let cues: Vec<u8> = [0, 7]
+            .into_iter()
+            .map(|i| {
+                ebml(
+                    EBML_CUE_POINT,
+                    &[
+                        ebml_uint(EBML_CUE_TIME, ms(i) as u64),
+                        ebml(EBML_CUE_TRACK_POSITIONS, &ebml_uint(EBML_CUE_TRACK, 1)),
+                    ]
+                    .concat(),
+                )
+            })
+            .collect::<Vec<_>>()
+            .concat();
+        let tracks = [
+            ebml(
+                EBML_TRACK_ENTRY,
+                &[
+                    ebml_uint(EBML_TRACK_NUMBER, 2),
+                    ebml_uint(EBML_TRACK_TYPE, 2),
+                ]
+                .concat(),
+            ),
+            ebml(
+                EBML_TRACK_ENTRY,
+                &[
+                    ebml_uint(EBML_TRACK_NUMBER, 1),
+                    ebml_uint(EBML_TRACK_TYPE, 1),
+                    ebml_uint(EBML_DEFAULT_DURATION, 33_366_667),
+                ]
+                .concat(),
+            ),
+        ]
+        .concat();
+        let segment = [
+            ebml(EBML_INFO, &ebml_uint(EBML_TIMECODE_SCALE, 1_000_000)),
+            ebml(EBML_TRACKS, &tracks),
+            clusters.concat(),
+            ebml(EBML_CUES, &cues),
+        ]
+        .concat();
+        // the segment is written with an unknown size, like a live recording
+        let mut file = ebml(0x1a45dfa3, &ebml(0x4282, b"matroska"));
+        file.extend([
+            0x18, 0x53, 0x80, 0x67, 0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        ]);
+        file.extend(segment);
+
+        let index = read_mkv(&mut std::io::Cursor::new(file)).unwrap();
+        assert_eq!(index.time_base, Rational64::new(1, 1000));
+        assert_eq!(index.step, Some(Rational64::new(1001, 30000)));
+        assert_eq!(index.gops(), ffprobe_gops("1/1000", &ms));
+    }
+}
@@ -1,21 +1,52 @@
 use log::*;
 use num_rational::Rational64;
 use num_traits::cast::ToPrimitive;
+use num_traits::Signed;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use uuid::Uuid;
 
+mod arrays;
+mod cache;
 mod fmt;
-
-const TARGET_WIDTH: usize = 1280;
-const TARGET_HEIGHT: usize = 720;
+mod index;
+mod meta;
+mod output;
+mod parse;
+mod store;
+mod validate;
+
+pub use arrays::{ArrayData, ArrayDecl, ArrayError, ArrayValue, Arrays, BBox};
+pub use cache::OpCache;
+pub use index::{read_index, ContainerIndex, IndexError};
+use meta::load_meta;
+pub use meta::{
+    probe_is_current, profile_video, AudioStream, Edit, FrameTimes, SourceTiming, StreamParams,
+};
+pub use output::{Container, OutputConfig};
+pub use parse::ParseError;
+pub use store::{DatastoreError, FileStamp, GopStats, VideoStatus};
+pub use validate::{Diagnostic, DiagnosticKind, Severity};
+
+/// Filters over more frames than this are split into parallel shards
+const SHARD_FRAMES: i64 = 300;
+
+/// Most frames with detections drawn by a single filter, keeps filter strings manageable
+const MAX_BOX_FRAMES: usize = 250;
+
+/// Format version written by `Datastore::save`
+pub const DATASTORE_VERSION: u32 = 1;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Datastore {
+    /// 0 for datastores written before the format was versioned
+    #[serde(default)]
+    pub version: u32,
     pub videos: BTreeMap<String, Video>,
-    pub tree_idxs: BTreeMap<String, (BTreeSet<Rational64>, BTreeSet<Rational64>)>,
+    /// Names of every video derived from each root video, however indirectly
+    pub tree_idxs: BTreeMap<String, BTreeSet<String>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -24,12 +55,67 @@ pub struct Video {
     pub ffprobe_path: String,
     pub range: Range,
     pub gops: Vec<SourceGopBound>,
+    pub stream: StreamParams,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audio: Vec<AudioStream>,
+    /// Datastores written before this was recorded assume sources start at the file's start
+    #[serde(default)]
+    pub timing: SourceTiming,
+    /// Real frame times of variable frame rate sources, which `range` only approximates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame_times: Option<FrameTimes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub derived_from: Option<Derivation>,
+    /// `None` for videos added before files were stamped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<FileStamp>,
+}
+
+/// How a video was made from another one in the datastore, e.g. a transcode with shorter GOPs
+/// or a copy with detections drawn on
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Derivation {
+    /// Name of the video it was made from
+    pub parent: String,
+    /// Parent time shown at this video's time 0
+    pub offset: Rational64,
+    /// What was drawn over the parent's frames, `None` for copies showing the same picture
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overlay: Option<String>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub enum Codec {
     H264,
+    HEVC,
     VP9,
+    AV1,
+    /// MPEG-4 Part 2, as written by older cameras
+    MPEG4,
+}
+
+impl Codec {
+    /// ffprobe's `codec_name` for the codec
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::H264 => "h264",
+            Codec::HEVC => "hevc",
+            Codec::VP9 => "vp9",
+            Codec::AV1 => "av1",
+            Codec::MPEG4 => "mpeg4",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Codec> {
+        match name {
+            "h264" => Some(Codec::H264),
+            "hevc" => Some(Codec::HEVC),
+            "vp9" => Some(Codec::VP9),
+            "av1" => Some(Codec::AV1),
+            "mpeg4" => Some(Codec::MPEG4),
+            _ => None,
+        }
+    }
 }
 
 pub struct VideoSource {
@@ -54,63 +140,179 @@ impl Datastore {
     pub fn load(file_path: &std::path::Path) -> Self {
         let file = std::fs::File::open(file_path).unwrap();
         let reader = std::io::BufReader::new(file);
-        serde_json::from_reader(reader).unwrap()
+        let value: serde_json::Value = serde_json::from_reader(reader).unwrap();
+        Datastore::from_json(value)
     }
 
-    fn path_to_vid_key(&self, path: &str) -> String {
-        self.videos
-            .iter()
-            .filter(|x| x.1.path == path)
-            .take(1)
-            .next()
-            .expect(format!("Failed to find video from path \"{}\"", path).as_str())
-            .0
-            .to_string()
+    /// Read a datastore written by this or any older version
+    fn from_json(mut value: serde_json::Value) -> Self {
+        let version = value["version"].as_u64().unwrap_or(0) as u32;
+        assert!(
+            version <= DATASTORE_VERSION,
+            "datastore is format version {}, but this build only reads up to {}",
+            version,
+            DATASTORE_VERSION
+        );
+
+        if version < 1 {
+            // Unversioned datastores only held H.264 videos and recorded nothing else about their
+            // streams. They also ended each range on its last frame rather than one step past it.
+            for video in value["videos"]
+                .as_object_mut()
+                .into_iter()
+                .flat_map(|videos| videos.values_mut())
+            {
+                video["stream"] = serde_json::json!({"codec": Codec::H264});
+                let range = &mut video["range"];
+                let end: Rational64 = serde_json::from_value(range["end"].take()).unwrap();
+                let step: Rational64 = serde_json::from_value(range["step"].clone()).unwrap();
+                range["end"] = serde_json::to_value(end + step).unwrap();
+            }
+            // They kept a pair of time sets per video here, and never recorded which videos were
+            // made from which, so every video starts out as its own root
+            value["tree_idxs"] = serde_json::json!({});
+        }
+
+        let mut datastore: Datastore = serde_json::from_value(value).unwrap();
+        datastore.version = DATASTORE_VERSION;
+        datastore
+    }
+
+    fn find_video_by_path(&self, path: &str) -> Option<(&String, &Video)> {
+        self.videos.iter().find(|x| x.1.path == path)
     }
 
-    fn vid_key_to_path(&self, key: &str) -> String {
+    /// Where `-ss` has to seek to for time 0 of each source that doesn't start at the file's start
+    fn seek_offsets(&self) -> SeekOffsets {
         self.videos
-            .get(key)
-            .expect("Failed to find video from key")
-            .path
-            .to_string()
+            .values()
+            .filter(|v| v.timing.start != Rational64::from_integer(0))
+            .map(|v| (v.path.clone(), v.timing.start))
+            .collect()
     }
 
-    pub fn add_new_video(&mut self, source: &VideoSource) {
-        cleanup();
+    fn path_to_vid_key(&self, path: &str) -> String {
+        self.find_video_by_path(path)
+            .unwrap_or_else(|| panic!("Failed to find video from path \"{}\"", path))
+            .0
+            .to_string()
+    }
 
+    pub fn add_new_video(&mut self, source: &VideoSource) -> Result<(), DatastoreError> {
         if self.videos.contains_key(&source.name) {
-            info!(
-                "Skipping video {} since it's already in the datastore",
-                source.name
-            );
-            return;
+            return Err(DatastoreError::NameTaken(source.name.clone()));
         }
 
-        let (range, gops, _codec) = load_meta(&source.ffprobe_path);
+        cleanup();
+
+        let meta = load_meta(&source.ffprobe_path);
 
         self.videos.insert(
             source.name.to_string(),
             Video {
                 path: source.path.to_string(),
                 ffprobe_path: source.ffprobe_path.to_string(),
-                range,
-                gops,
+                range: meta.range,
+                gops: meta.gops,
+                stream: meta.stream,
+                audio: meta.audio,
+                timing: meta.timing,
+                frame_times: meta.frame_times,
+                derived_from: None,
+                file: FileStamp::of(&source.path).ok(),
             },
         );
 
         cleanup();
+        Ok(())
+    }
+
+    /// Add a video made from `derivation.parent`, which must already be in the datastore
+    pub fn add_derived_video(
+        &mut self,
+        source: &VideoSource,
+        derivation: Derivation,
+    ) -> Result<(), DatastoreError> {
+        if !self.videos.contains_key(&derivation.parent) {
+            return Err(DatastoreError::NoSuchVideo(derivation.parent));
+        }
+
+        self.add_new_video(source)?;
+        let (root, _) = self.root_of(&derivation.parent);
+        self.tree_idxs
+            .entry(root)
+            .or_default()
+            .insert(source.name.clone());
+        self.videos.get_mut(&source.name).unwrap().derived_from = Some(derivation);
+        Ok(())
     }
 
-    pub fn add_new_video_tree(&mut self, root: &VideoSource, children: &[VideoSource]) {
-        self.add_new_video(root);
+    /// Add a root video and plain copies of it, like transcodes, that start at the same time
+    pub fn add_new_video_tree(
+        &mut self,
+        root: &VideoSource,
+        children: &[VideoSource],
+    ) -> Result<(), DatastoreError> {
+        self.add_new_video(root)?;
 
         for child in children {
-            self.add_new_video(child);
+            self.add_derived_video(
+                child,
+                Derivation {
+                    parent: root.name.clone(),
+                    offset: Rational64::from_integer(0),
+                    overlay: None,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The root video `name` was derived from, and the root's time at `name`'s time 0
+    fn root_of(&self, name: &str) -> (String, Rational64) {
+        let mut name = name.to_string();
+        let mut offset = Rational64::from_integer(0);
+        while let Some(derivation) = &self.videos[&name].derived_from {
+            offset += derivation.offset;
+            name = derivation.parent.clone();
+        }
+        (name, offset)
+    }
+
+    /// Videos showing the same picture as the video at `path`, with the time in each that lines
+    /// up with its time 0. Includes the video itself.
+    fn same_picture_videos(&self, path: &str) -> Vec<(&Video, Rational64)> {
+        let Some((name, _)) = self.find_video_by_path(path) else {
+            return vec![];
+        };
+        let (root, name_offset) = self.root_of(name);
+        let plain = |name: &str| {
+            let mut name = name;
+            while let Some(derivation) = &self.videos[name].derived_from {
+                if derivation.overlay.is_some() {
+                    return false;
+                }
+                name = &derivation.parent;
+            }
+            true
+        };
+        if !plain(name) {
+            return vec![(&self.videos[name], Rational64::from_integer(0))];
         }
+
+        let descendants = self.tree_idxs.get(&root).into_iter().flatten();
+        std::iter::once(&root)
+            .chain(descendants)
+            .filter(|other| plain(other))
+            .map(|other| {
+                let (_, other_offset) = self.root_of(other);
+                (&self.videos[other], name_offset - other_offset)
+            })
+            .collect()
     }
 }
 
+/// Frames at `start`, `start + step`, ... up to but not including `end`
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Range {
     pub start: Rational64,
@@ -119,10 +321,16 @@ pub struct Range {
 }
 
 impl Range {
+    /// Frame times from `start` up to but excluding `end`, the way spec domains are rendered
+    fn frame_times(&self) -> impl Iterator<Item = Rational64> + '_ {
+        let cnt = ((self.end - self.start) / self.step).to_integer();
+        (0..cnt).map(move |i| self.start + self.step * i)
+    }
+
     fn len(&self) -> i64 {
         let cnt = (self.end - self.start) / self.step;
         assert!(*cnt.denom() == 1);
-        *cnt.numer() + 1 // inclusive range, so add one
+        *cnt.numer()
     }
 
     fn split_at(&self, split_pt: Rational64) -> (Range, Range) {
@@ -132,15 +340,11 @@ impl Range {
             step: self.step,
         };
         let right = Range {
-            start: split_pt + self.step,
+            start: split_pt,
             end: self.end,
             step: self.step,
         };
         assert!(left.start <= left.end);
-        // if !(right.start <= right.end) {
-        //     dbg!(self, split_pt, &left, &right);
-        // }
-        // assert!(right.start <= right.end);
         assert!(left.len() + right.len() == self.len());
         (left, right)
     }
@@ -154,22 +358,21 @@ pub enum DataExpr {
     ArrayIdx(String, Box<TExpr>),
 }
 
-impl DataExpr {
-    fn unwrap_const_str(&self) -> String {
-        match self {
-            DataExpr::ConstStr(s) => s.clone(),
-            _ => panic!("unwrap_const_str"),
-        }
-    }
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum TExpr {
     Const(Rational64),
     T,
     Add(Box<TExpr>, Rational64),
     Sub(Box<TExpr>, Rational64),
     Mul(Box<TExpr>, Rational64),
+    /// Wraps into `[0, d)`, e.g. to loop a clip of length `d`
+    Mod(Box<TExpr>, Rational64),
+    /// Rounds down to a multiple of `q`; holds each value for `q` seconds
+    Floor(Box<TExpr>, Rational64),
+    /// Piecewise-linear remapping through `(from, to)` keyframes sorted by `from`,
+    /// clamped to the first and last keyframe. Without keyframes time passes through unchanged,
+    /// though validation rejects that.
+    Remap(Box<TExpr>, Vec<(Rational64, Rational64)>),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -177,12 +380,65 @@ pub enum SourceType {
     ReadFrame,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum F2FType {
+    /// A 2x2 `Grid` of four sources, kept for older specs
     Quadrents,
     Filter,
+    /// Draws the boxes from a per-frame array: `(source, boxes[, color[, show_labels]])`
+    BoundingBoxes,
+    /// Tiles any number of sources row-major:
+    /// `(sources.., rows, cols[, padding[, background[, cell_width, cell_height]]])`
+    Grid,
+    /// Insets the second source over the first:
+    /// `(main, inset, x, y, w, h[, opacity[, border_width[, border_color]]])` in normalized
+    /// coordinates, where `x` and `y` may be per-frame arrays
+    PictureInPicture,
+    /// Blends from the first source into the second with an xfade transition:
+    /// `(from, to, kind, start, duration)`. The frame at `t` is `(t - start) / duration` of the
+    /// way through the transition.
+    Transition,
+    /// Hides the boxes from a per-frame array: `(source, boxes[, style[, strength]])` where
+    /// `style` is `"blur"` (box blur radius), `"pixelate"` (block size) or `"fill"` (black)
+    Redact,
+    /// `(source[, brightness[, contrast[, saturation]]])`, where brightness is an offset in
+    /// `-1..1` and contrast and saturation are multipliers
+    Adjust,
+    Grayscale,
+    /// `(source[, amount])` unsharp mask strength, `1` by default
+    Sharpen,
+    /// `(source, sigma)` gaussian blur
+    Blur,
+    /// `(source, "in" | "out", start, duration)` fade from or to black over
+    /// `[start, start + duration)`
+    Fade,
+    /// `(source, degrees)` clockwise, filling uncovered corners with black
+    Rotate,
+    /// `(source, "horizontal" | "vertical")`
+    Flip,
+    /// Crops to a per-frame rectangle and scales it back up: `(source, rects[, smoothing])`.
+    /// The first box of each frame is used, widened to the output aspect ratio, and frames
+    /// without one keep the last rectangle. `smoothing` averages rectangles over that many
+    /// frames on either side.
+    Crop,
 }
 
+/// xfade transitions usable by `F2FType::Transition`
+const TRANSITIONS: &[&str] = &[
+    "fade",
+    "fadeblack",
+    "fadewhite",
+    "dissolve",
+    "wipeleft",
+    "wiperight",
+    "wipeup",
+    "wipedown",
+    "slideleft",
+    "slideright",
+    "circleopen",
+    "circleclose",
+];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum FrameExpr {
     MatchT(Vec<(Range, Box<FrameExpr>)>),
@@ -204,6 +460,10 @@ pub struct Spec {
     pub iter: Range,
     pub render: FrameExpr,
     pub output: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub arrays: Arrays,
+    #[serde(default, skip_serializing_if = "OutputConfig::is_default")]
+    pub output_config: OutputConfig,
 }
 
 #[derive(Debug)]
@@ -224,6 +484,63 @@ impl Spec {
     pub fn set_all_sources(&mut self, source: &str) {
         self.render.set_all_sources(source);
     }
+
+    /// Paths of every source video the spec reads
+    pub fn source_paths(&self) -> BTreeSet<String> {
+        let mut paths = BTreeSet::new();
+        self.render.source_paths(&mut paths);
+        paths
+    }
+
+    /// Read file backed arrays into the spec, resolving relative paths against `base_dir`
+    pub fn load_arrays(&mut self, base_dir: &std::path::Path) -> Result<(), ArrayError> {
+        arrays::load_arrays(&mut self.arrays, base_dir)
+    }
+}
+
+/// Replace the first `duration` of case `after + 1` with a transition from case `after`, which is
+/// read past its own end to blend over the next case's first frames
+fn insert_transition(
+    cases: &mut Vec<(Range, Box<FrameExpr>)>,
+    after: usize,
+    kind: &str,
+    duration: Rational64,
+) -> Result<(), String> {
+    let (from_range, from) = cases[after].clone();
+    let (to_range, to) = cases[after + 1].clone();
+    if from_range.end != to_range.start {
+        return Err(format!(
+            "transition cases {} and {} are not adjacent",
+            from_range, to_range
+        ));
+    }
+    if duration <= Rational64::from_integer(0)
+        || !(duration / to_range.step).is_integer()
+        || to_range.start + duration >= to_range.end
+    {
+        return Err(format!(
+            "a {} transition doesn't fit in whole frames of {}",
+            duration, to_range
+        ));
+    }
+
+    let window = Range {
+        start: to_range.start,
+        end: to_range.start + duration,
+        step: to_range.step,
+    };
+    let transition = FrameExpr::F2fFunction {
+        func: F2FType::Transition,
+        sources: vec![*from, *to],
+        args: vec![
+            DataExpr::ConstStr(kind.to_string()),
+            DataExpr::ConstNum(window.start),
+            DataExpr::ConstNum(duration),
+        ],
+    };
+    cases[after + 1].0.start = window.end;
+    cases.insert(after + 1, (window, Box::new(transition)));
+    Ok(())
 }
 
 impl FrameExpr {
@@ -280,15 +597,56 @@ impl FrameExpr {
                 sources,
                 args,
             } => {
-                // We're not flattening through a f2f here since it's really hard logic
-                out.push((
-                    domain.clone(),
-                    FrameExpr::F2fFunction {
-                        func: func.clone(),
-                        sources: sources.iter().map(|x| (*x).clone()).collect(),
-                        args: args.clone(),
-                    },
-                ));
+                // Flatten each source on its own, then split the domain wherever any of them
+                // switches cases so every piece applies the F2F to match-free sources
+                let source_pieces: Vec<Vec<(Range, FrameExpr)>> =
+                    sources.iter().map(|s| s.flatten_matches(domain)).collect();
+
+                let mut bounds: BTreeSet<Rational64> = BTreeSet::new();
+                bounds.insert(domain.start);
+                bounds.insert(domain.end);
+                for pieces in &source_pieces {
+                    for (piece_range, _) in pieces {
+                        for pt in [piece_range.start, piece_range.end] {
+                            if pt > domain.start && pt < domain.end {
+                                bounds.insert(pt);
+                            }
+                        }
+                    }
+                }
+
+                let bounds: Vec<Rational64> = bounds.into_iter().collect();
+                for window in bounds.windows(2) {
+                    let piece_domain = Range {
+                        start: window[0],
+                        end: window[1],
+                        step: domain.step,
+                    };
+
+                    let piece_sources: Option<Vec<FrameExpr>> = source_pieces
+                        .iter()
+                        .map(|pieces| {
+                            pieces
+                                .iter()
+                                .find(|(r, _)| {
+                                    r.start <= piece_domain.start && piece_domain.end <= r.end
+                                })
+                                .map(|(_, expr)| expr.clone())
+                        })
+                        .collect();
+
+                    // A source with no case here has nothing to render, just like a gap in a match
+                    if let Some(piece_sources) = piece_sources {
+                        out.push((
+                            piece_domain,
+                            FrameExpr::F2fFunction {
+                                func: func.clone(),
+                                sources: piece_sources,
+                                args: args.clone(),
+                            },
+                        ));
+                    }
+                }
             }
             FrameExpr::SourceFunction {
                 func,
@@ -327,6 +685,24 @@ impl FrameExpr {
             }
         }
     }
+
+    fn source_paths(&self, paths: &mut BTreeSet<String>) {
+        match self {
+            FrameExpr::MatchT(cases) => {
+                for (_, expr) in cases {
+                    expr.source_paths(paths);
+                }
+            }
+            FrameExpr::F2fFunction { sources, .. } => {
+                for source in sources {
+                    source.source_paths(paths);
+                }
+            }
+            FrameExpr::SourceFunction { source, .. } => {
+                paths.insert(source.clone());
+            }
+        }
+    }
 }
 
 impl TExpr {
@@ -357,7 +733,122 @@ impl TExpr {
                 range.step *= t;
                 range
             }
+            // Non-linear mappings don't have a single step, so report the span they touch
+            TExpr::Mod(..) | TExpr::Floor(..) | TExpr::Remap(..) => {
+                let (lo, hi) = self.bounds(domain);
+                Range {
+                    start: lo,
+                    end: hi + domain.step,
+                    step: domain.step,
+                }
+            }
+        }
+    }
+
+    fn eval(&self, t: Rational64) -> Rational64 {
+        match self {
+            TExpr::Const(c) => *c,
+            TExpr::T => t,
+            TExpr::Add(texpr, c) => texpr.eval(t) + c,
+            TExpr::Sub(texpr, c) => texpr.eval(t) - c,
+            TExpr::Mul(texpr, c) => texpr.eval(t) * c,
+            TExpr::Mod(texpr, d) => {
+                let v = texpr.eval(t);
+                v - d * (v / d).floor()
+            }
+            TExpr::Floor(texpr, q) => {
+                let v = texpr.eval(t);
+                q * (v / q).floor()
+            }
+            TExpr::Remap(texpr, keyframes) => {
+                let v = texpr.eval(t);
+                let (Some(&first), Some(&last)) = (keyframes.first(), keyframes.last()) else {
+                    return v;
+                };
+                if v <= first.0 {
+                    return first.1;
+                }
+                if v >= last.0 {
+                    return last.1;
+                }
+                let i = keyframes.iter().rposition(|k| k.0 <= v).unwrap();
+                let (x0, y0) = keyframes[i];
+                let (x1, y1) = keyframes[i + 1];
+                y0 + (v - x0) * (y1 - y0) / (x1 - x0)
+            }
+        }
+    }
+
+    /// Smallest and largest value taken over the frames of `domain`
+    fn bounds(&self, domain: &Range) -> (Rational64, Rational64) {
+        if self.is_affine() {
+            let range = self.range(domain);
+            let first = range.start;
+            let last = range.end - range.step;
+            return (std::cmp::min(first, last), std::cmp::max(first, last));
+        }
+
+        let mut values = domain.frame_times().map(|t| self.eval(t));
+        let first = values.next().unwrap_or_else(|| self.eval(domain.start));
+        values.fold((first, first), |(lo, hi), v| {
+            (std::cmp::min(lo, v), std::cmp::max(hi, v))
+        })
+    }
+
+    fn is_affine(&self) -> bool {
+        match self {
+            TExpr::Const(_) | TExpr::T => true,
+            TExpr::Add(texpr, _) | TExpr::Sub(texpr, _) | TExpr::Mul(texpr, _) => texpr.is_affine(),
+            TExpr::Mod(..) | TExpr::Floor(..) | TExpr::Remap(..) => false,
+        }
+    }
+
+    /// Split `domain` into runs of frames over which this expression is affine, returning an
+    /// equivalent affine expression for each run.
+    fn linear_pieces(&self, domain: &Range) -> Vec<(Range, TExpr)> {
+        if self.is_affine() {
+            return vec![(domain.clone(), self.clone())];
+        }
+
+        let frames: Vec<(Rational64, Rational64)> =
+            domain.frame_times().map(|t| (t, self.eval(t))).collect();
+
+        let mut out = vec![];
+        let mut run_start = 0;
+        while run_start < frames.len() {
+            let mut run_end = run_start + 1;
+            if run_end < frames.len() {
+                let delta = frames[run_end].1 - frames[run_start].1;
+                while run_end < frames.len() && frames[run_end].1 - frames[run_end - 1].1 == delta {
+                    run_end += 1;
+                }
+            }
+
+            let (t0, v0) = frames[run_start];
+            let scale = if run_end - run_start > 1 {
+                (frames[run_start + 1].1 - v0) / domain.step
+            } else {
+                Rational64::new(0, 1)
+            };
+            let piece_domain = Range {
+                start: t0,
+                end: t0 + domain.step * (run_end - run_start) as i64,
+                step: domain.step,
+            };
+            let piece_expr = if scale == Rational64::new(0, 1) {
+                TExpr::Const(v0)
+            } else if scale == Rational64::new(1, 1) {
+                TExpr::Add(Box::new(TExpr::T), v0 - t0)
+            } else {
+                TExpr::Add(
+                    Box::new(TExpr::Mul(Box::new(TExpr::T), scale)),
+                    v0 - t0 * scale,
+                )
+            };
+            out.push((piece_domain, piece_expr));
+            run_start = run_end;
         }
+        out
     }
 }
 
@@ -365,6 +856,9 @@ impl TExpr {
 enum FFmpegClipMethod {
     Transcode,
     StreamCopy,
+    /// Transcode a variable frame rate source onto the frame grid of its `Range`, showing the
+    /// nearest real frame at each grid time
+    ConstantRate,
 }
 
 #[derive(Debug, Clone)]
@@ -374,7 +868,27 @@ enum Op {
         range: Range,
         out: String,
         method: FFmpegClipMethod,
-        codec: Codec,
+        config: OutputConfig,
+    },
+    /// Reads `range` from `input` where `range.step` may be negative or differ from the
+    /// source's native `source_step`, writing one frame per `out_step` of output time.
+    /// Always re-encodes, so it can never be stream copied.
+    FFmpegRetime {
+        input: String,
+        range: Range,
+        source_step: Rational64,
+        out_step: Rational64,
+        out: String,
+        config: OutputConfig,
+    },
+    /// Holds the frame of `input` at time `t` for `frames` output frames
+    FFmpegFreeze {
+        input: String,
+        t: Rational64,
+        frames: i64,
+        out_step: Rational64,
+        out: String,
+        config: OutputConfig,
     },
     FFmpegConcat {
         inputs: Vec<String>,
@@ -382,144 +896,718 @@ enum Op {
     },
     FFmpegFilter {
         inputs: Vec<(String, Option<Range>)>,
-        filter: String,
-        complex: bool,
+        filter: FilterGraph,
         approx: bool,
         out: String,
+        config: OutputConfig,
+    },
+    /// Copies an output rendered by an earlier run out of the `OpCache`
+    Cached {
+        file: String,
+        out: String,
     },
 }
 
-fn ffmpeg_time(t: &Rational64, duration: bool) -> String {
-    // todo: don't hard-code 24FPS
-    let duration_epsilon = if duration { 0.5 / 24.0 } else { 0.0 };
-    format!(
-        "{:0.6}",
-        (*t.numer() as f64) / (*t.denom() as f64) + if duration { duration_epsilon } else { 0.0 }
-    )
+/// Filter graph run by an `FFmpegFilter`, kept structured until the command line is built so
+/// the approx mode can change its frame rate
+#[derive(Debug, Clone)]
+enum FilterGraph {
+    /// A filter chain applied to a single input after scaling it to the target size
+    Chain(String),
+    Grid(GridLayout),
+    PictureInPicture(PipLayout),
+    Transition(TransitionLayout),
+    Crop(CropLayout),
+    /// Typed adjustments applied in order after scaling to the target size
+    Adjust(Vec<Adjustment>),
 }
 
-impl Op {
-    fn run(&self) {
-        match self {
-            Op::FFmpegClip {
-                input,
-                range,
-                out,
-                method,
-                codec,
-            } => {
-                let mut cmd = std::process::Command::new("ffmpeg");
-                cmd.arg("-hide_banner");
-                cmd.arg("-loglevel").arg("error");
-
-                cmd.arg("-ss").arg(ffmpeg_time(&range.start, false));
-                cmd.arg("-i").arg(input);
-                cmd.arg("-t")
-                    .arg(ffmpeg_time(&(range.end - range.start), true));
+/// Image adjustments the optimizer understands, unlike the opaque strings of `FilterGraph::Chain`
+#[derive(Debug, Clone, PartialEq)]
+enum Adjustment {
+    Eq {
+        brightness: f64,
+        contrast: f64,
+        saturation: f64,
+    },
+    Grayscale,
+    Sharpen(f64),
+    Blur(f64),
+    Rotate(f64),
+    Flip {
+        horizontal: bool,
+    },
+    /// Fade over `[start, start + duration)`, relative to the start of the filtered clip
+    Fade {
+        fade_in: bool,
+        start: Rational64,
+        duration: Rational64,
+    },
+}
 
-                match method {
-                    FFmpegClipMethod::StreamCopy => {
-                        cmd.arg("-c:v").arg("copy");
-                        cmd.arg("-c:a").arg("copy");
-                    }
-                    FFmpegClipMethod::Transcode => {
-                        match codec {
-                            Codec::H264 => {
-                                cmd.arg("-c:v").arg("libx264");
-                                cmd.arg("-preset").arg("ultrafast");
-                            }
-                            Codec::VP9 => {
-                                cmd.arg("-c:v").arg("libvpx-vp9");
-                                cmd.arg("-deadline").arg("realtime");
-                                cmd.arg("-speed").arg("8");
-                            }
-                        }
-                        cmd.arg("-vf")
-                            .arg(format!("scale={TARGET_WIDTH}:{TARGET_HEIGHT}"));
-                    }
-                }
+impl Adjustment {
+    fn filter(&self) -> String {
+        match self {
+            Adjustment::Eq {
+                brightness,
+                contrast,
+                saturation,
+            } => format!("eq=brightness={brightness}:contrast={contrast}:saturation={saturation}"),
+            Adjustment::Grayscale => "hue=s=0".to_string(),
+            Adjustment::Sharpen(amount) => format!("unsharp=5:5:{amount}"),
+            Adjustment::Blur(sigma) => format!("gblur=sigma={sigma}"),
+            Adjustment::Rotate(degrees) => format!("rotate={degrees}*PI/180:fillcolor=black"),
+            Adjustment::Flip { horizontal: true } => "hflip".to_string(),
+            Adjustment::Flip { horizontal: false } => "vflip".to_string(),
+            Adjustment::Fade {
+                fade_in,
+                start,
+                duration,
+            } => format!(
+                "fade=t={}:st={}:d={}",
+                if *fade_in { "in" } else { "out" },
+                ffmpeg_time(start),
+                ffmpeg_time(duration)
+            ),
+        }
+    }
 
-                cmd.arg(out);
-                cmd.arg("-y");
+    /// Whether each output frame only depends on the same input frame, so the clip can be split
+    /// anywhere without changing the result
+    fn is_frame_local(&self) -> bool {
+        !matches!(self, Adjustment::Fade { .. })
+    }
+}
 
-                info!("{cmd:?}",);
+impl FilterGraph {
+    /// Whether the graph can be run on shards of its input and concatenated
+    fn is_shardable(&self) -> bool {
+        match self {
+            // a transition can't restart part way through
+            FilterGraph::Transition(_) => false,
+            FilterGraph::Adjust(adjustments) => adjustments.iter().all(|a| a.is_frame_local()),
+            _ => true,
+        }
+    }
 
-                // run cmd
-                let status = cmd.status().expect("failed to execute process");
-                assert!(status.success());
+    /// The same graph for a shard starting `frames` frames into this filter's output
+    fn skip_frames(&self, frames: usize) -> FilterGraph {
+        match self {
+            FilterGraph::PictureInPicture(pip) => {
+                let mut pip = pip.clone();
+                pip.positions
+                    .drain(..std::cmp::min(frames, pip.positions.len() - 1));
+                FilterGraph::PictureInPicture(pip)
             }
-            Op::FFmpegConcat { inputs: input, out } => {
-                let job_file_path = format!("/scratch/tmp_{}.txt", Uuid::new_v4());
-                let job_file_content = input
-                    .iter()
-                    .map(|f| format!("file '{f}'",))
-                    .collect::<Vec<String>>()
-                    .join("\n");
-                fs::write(&job_file_path, job_file_content).expect("Unable to write to file");
-
-                let mut cmd = std::process::Command::new("ffmpeg");
-                cmd.arg("-hide_banner");
-                cmd.arg("-loglevel").arg("error");
+            FilterGraph::Crop(crop) => {
+                let mut crop = crop.clone();
+                crop.rects
+                    .drain(..std::cmp::min(frames, crop.rects.len() - 1));
+                FilterGraph::Crop(crop)
+            }
+            _ => self.clone(),
+        }
+    }
+}
 
-                cmd.arg("-f").arg("concat");
-                cmd.arg("-safe").arg("0");
-                cmd.arg("-i").arg(job_file_path);
-                cmd.arg("-c").arg("copy");
+#[derive(Debug, Clone, PartialEq)]
+struct GridLayout {
+    rows: usize,
+    cols: usize,
+    cell_width: usize,
+    cell_height: usize,
+    padding: usize,
+    background: String,
+    /// Output frame duration, which the background source has to be generated at
+    step: Rational64,
+}
 
-                cmd.arg(out);
-                cmd.arg("-y");
+#[derive(Debug, Clone, PartialEq)]
+struct CropLayout {
+    /// `(x, y, w, h)` in source pixels for every output frame
+    rects: Vec<(usize, usize, usize, usize)>,
+    step: Rational64,
+}
 
-                info!("{cmd:?}",);
-                let status = cmd.status().expect("failed to execute process");
-                assert!(status.success());
+impl CropLayout {
+    /// `-vf` chain cropping the input, updated with `sendcmd` whenever the rectangle changes
+    fn filter(&self, approx: bool, config: &OutputConfig) -> String {
+        let mut commands = vec![];
+        for (i, rect) in self.rects.iter().enumerate().skip(1) {
+            let last = self.rects[i - 1];
+            let changed: Vec<String> = [
+                ("w", rect.2, last.2),
+                ("h", rect.3, last.3),
+                ("x", rect.0, last.0),
+                ("y", rect.1, last.1),
+            ]
+            .iter()
+            .filter(|(_, new, old)| new != old)
+            .map(|(param, new, _)| format!("crop@zoom {param} {new}"))
+            .collect();
+            if !changed.is_empty() {
+                let t = ffmpeg_time(&(self.step * i as i64));
+                commands.push(format!("{t} {}", changed.join(", ")));
             }
-            Op::FFmpegFilter {
-                inputs,
-                filter,
-                complex,
-                approx,
-                out,
-            } => {
-                let mut cmd = std::process::Command::new("ffmpeg");
-                cmd.arg("-hide_banner");
-                cmd.arg("-loglevel").arg("error");
+        }
 
-                for (input, input_range) in inputs {
-                    if let Some(input_range) = input_range {
-                        cmd.arg("-ss").arg(ffmpeg_time(&input_range.start, false));
-                    }
-                    cmd.arg("-i").arg(input);
-                }
+        let mut chain = vec![];
+        if approx {
+            chain.push(format!("fps={}", approx_rate(&self.step)));
+        }
+        if !commands.is_empty() {
+            chain.push(format!("sendcmd=c='{}'", commands.join(";")));
+        }
+        let (x, y, w, h) = self.rects[0];
+        chain.push(format!("crop@zoom=w={w}:h={h}:x={x}:y={y}"));
+        chain.push(config.scale());
+        chain.join(",")
+    }
+}
 
-                if let Some(input_range) = &inputs[0].1 {
-                    cmd.arg("-t")
-                        .arg(ffmpeg_time(&(input_range.end - input_range.start), true));
+/// Pixel crop rectangles for every frame of `range`, smoothed over `smoothing` frames either side
+/// and widened to the output aspect ratio within a `dims` sized frame
+fn crop_rects(
+    rects: &[Option<BBox>],
+    smoothing: usize,
+    dims: (usize, usize),
+    aspect: f64,
+) -> Vec<(usize, usize, usize, usize)> {
+    let full = BBox {
+        x: 0.0,
+        y: 0.0,
+        w: 1.0,
+        h: 1.0,
+        label: None,
+    };
+    let held: Vec<[f64; 4]> = rects
+        .iter()
+        .scan(full, |last, rect| {
+            if let Some(rect) = rect {
+                *last = rect.clone();
+            }
+            Some([last.x, last.y, last.w, last.h])
+        })
+        .collect();
+
+    let (frame_w, frame_h) = (dims.0 as f64, dims.1 as f64);
+    let even = |v: f64| ((v / 2.0).round() as usize * 2).max(2);
+
+    (0..held.len())
+        .map(|i| {
+            let window =
+                &held[i.saturating_sub(smoothing)..std::cmp::min(held.len(), i + smoothing + 1)];
+            let mut avg = [0.0; 4];
+            for rect in window {
+                for (a, v) in avg.iter_mut().zip(rect) {
+                    *a += v / window.len() as f64;
                 }
+            }
 
-                if *complex {
-                    // complex filters should handle their own resolution
-
-                    if *approx {
-                        cmd.arg("-filter_complex").arg(filter.replace(
-                            &format!("setpts=PTS-STARTPTS, scale={TARGET_WIDTH}x{TARGET_HEIGHT}"),
-                            &format!(
-                                "setpts=PTS-STARTPTS, fps=12, scale={TARGET_WIDTH}x{TARGET_HEIGHT}"
-                            ),
-                        ));
-                    } else {
-                        cmd.arg("-filter_complex").arg(filter);
-                    }
-                } else if *approx {
-                    cmd.arg("-vf").arg(format!(
-                        "fps=12,scale={TARGET_WIDTH}:{TARGET_HEIGHT},{filter}"
-                    ));
-                } else {
-                    cmd.arg("-vf")
-                        .arg(format!("scale={TARGET_WIDTH}:{TARGET_HEIGHT},{filter}"));
-                }
+            let (cx, cy) = (
+                (avg[0] + avg[2] / 2.0) * frame_w,
+                (avg[1] + avg[3] / 2.0) * frame_h,
+            );
+            let (mut w, mut h) = (avg[2] * frame_w, avg[3] * frame_h);
+            if w / h < aspect {
+                w = h * aspect;
+            } else {
+                h = w / aspect;
+            }
+            if w > frame_w {
+                (w, h) = (frame_w, frame_w / aspect);
+            }
+            if h > frame_h {
+                (w, h) = (frame_h * aspect, frame_h);
+            }
 
-                cmd.arg("-c:v").arg("libx264");
-                cmd.arg("-preset").arg("ultrafast");
+            let (w, h) = (
+                std::cmp::min(even(w), dims.0 - dims.0 % 2),
+                std::cmp::min(even(h), dims.1 - dims.1 % 2),
+            );
+            let x = (cx - w as f64 / 2.0).clamp(0.0, (dims.0 - w) as f64) as usize;
+            let y = (cy - h as f64 / 2.0).clamp(0.0, (dims.1 - h) as f64) as usize;
+            (x - x % 2, y - y % 2, w, h)
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct TransitionLayout {
+    kind: String,
+    duration: Rational64,
+    /// Part of the transition to output, relative to its start
+    start: Rational64,
+    end: Rational64,
+    step: Rational64,
+}
+
+impl TransitionLayout {
+    /// `-filter_complex` graph running the whole transition from input 0 to input 1 and
+    /// trimming it to the requested part
+    fn filter_complex(&self, approx: bool, config: &OutputConfig) -> String {
+        let rate = if approx {
+            approx_rate(&self.step)
+        } else {
+            ffmpeg_rational(&(Rational64::from_integer(1) / self.step))
+        };
+        // xfade needs both inputs at the same rate and timebase
+        let prep = |i: usize, label: &str| {
+            format!(
+                "[{i}:v] setpts=PTS-STARTPTS, fps={rate}, {}, settb=AVTB [{label}]",
+                config.scale()
+            )
+        };
+        let mut graph = format!(
+            "{};{};[from][to] xfade=transition={}:duration={}:offset=0",
+            prep(0, "from"),
+            prep(1, "to"),
+            self.kind,
+            ffmpeg_time(&self.duration)
+        );
+        if self.start != Rational64::from_integer(0) || self.end != self.duration {
+            graph += &format!(
+                ", trim=start={}:end={}, setpts=PTS-STARTPTS",
+                ffmpeg_time(&self.start),
+                ffmpeg_time(&self.end)
+            );
+        }
+        graph
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct PipLayout {
+    /// Top left corner of the inset in pixels for every output frame
+    positions: Vec<(i64, i64)>,
+    width: usize,
+    height: usize,
+    opacity: Rational64,
+    border_width: usize,
+    border_color: String,
+    step: Rational64,
+}
+
+impl PipLayout {
+    /// `-filter_complex` graph overlaying input 1 on input 0, moved with `sendcmd` whenever the
+    /// position changes
+    fn filter_complex(&self, approx: bool, config: &OutputConfig) -> String {
+        let fps = if approx {
+            format!("fps={}, ", approx_rate(&self.step))
+        } else {
+            String::new()
+        };
+        let mut inset = format!(
+            "[1:v] setpts=PTS-STARTPTS, {fps}scale={}x{}",
+            self.width, self.height
+        );
+        if self.opacity < Rational64::from_integer(1) {
+            inset += &format!(
+                ", format=rgba, colorchannelmixer=aa={}",
+                ffmpeg_time(&self.opacity)
+            );
+        }
+        if self.border_width > 0 {
+            inset += &format!(
+                ", pad={}:{}:{b}:{b}:color={}",
+                self.width + 2 * self.border_width,
+                self.height + 2 * self.border_width,
+                self.border_color,
+                b = self.border_width
+            );
+        }
+
+        let mut main = format!("[0:v] setpts=PTS-STARTPTS, {fps}{}", config.scale());
+        let mut commands = vec![];
+        for (i, pos) in self.positions.iter().enumerate().skip(1) {
+            if *pos != self.positions[i - 1] {
+                let t = ffmpeg_time(&(self.step * i as i64));
+                commands.push(format!(
+                    "{t} overlay@pip x {}, overlay@pip y {}",
+                    pos.0 - self.border_width as i64,
+                    pos.1 - self.border_width as i64
+                ));
+            }
+        }
+        if !commands.is_empty() {
+            main += &format!(", sendcmd=c='{}'", commands.join(";"));
+        }
+
+        let (x, y) = self.positions[0];
+        format!(
+            "{inset} [inset];{main} [main];[main][inset] overlay@pip=shortest=1:x={}:y={}",
+            x - self.border_width as i64,
+            y - self.border_width as i64
+        )
+    }
+}
+
+impl GridLayout {
+    fn width(&self) -> usize {
+        self.cols * self.cell_width + (self.cols + 1) * self.padding
+    }
+
+    fn height(&self) -> usize {
+        self.rows * self.cell_height + (self.rows + 1) * self.padding
+    }
+
+    /// `-filter_complex` graph placing input `i` in cell `i`, row-major
+    fn filter_complex(&self, inputs: usize, approx: bool) -> String {
+        let rate = if approx {
+            approx_rate(&self.step)
+        } else {
+            ffmpeg_rational(&(Rational64::from_integer(1) / self.step))
+        };
+        let mut chains = vec![format!(
+            "color=c={}:s={}x{}:r={} [base]",
+            self.background,
+            self.width(),
+            self.height(),
+            rate
+        )];
+        for i in 0..inputs {
+            let fps = if approx {
+                format!("fps={rate}, ")
+            } else {
+                String::new()
+            };
+            chains.push(format!(
+                "[{i}:v] setpts=PTS-STARTPTS, {fps}scale={}x{} [cell{i}]",
+                self.cell_width, self.cell_height
+            ));
+        }
+        for i in 0..inputs {
+            let x = self.padding + (i % self.cols) * (self.cell_width + self.padding);
+            let y = self.padding + (i / self.cols) * (self.cell_height + self.padding);
+            let below = if i == 0 {
+                "[base]".to_string()
+            } else {
+                format!("[tmp{}]", i - 1)
+            };
+            let label = if i + 1 == inputs {
+                String::new()
+            } else {
+                format!(" [tmp{i}]")
+            };
+            chains.push(format!(
+                "{below}[cell{i}] overlay=shortest=1:x={x}:y={y}{label}"
+            ));
+        }
+        chains.join(";")
+    }
+}
+
+/// `t` in seconds, rounded down to the microsecond ffmpeg parses times into. Rounding down means
+/// seeking to a frame's time never skips that frame, even at NTSC rates like 30000/1001 where
+/// frame times aren't whole microseconds.
+fn ffmpeg_time(t: &Rational64) -> String {
+    let micros = (*t.numer() as i128 * 1_000_000).div_euclid(*t.denom() as i128);
+    let sign = if micros < 0 { "-" } else { "" };
+    format!(
+        "{sign}{}.{:06}",
+        micros.abs() / 1_000_000,
+        micros.abs() % 1_000_000
+    )
+}
+
+/// `-t` value covering `duration` worth of frames `step` apart when read from a frame time. The
+/// extra half step keeps the last frame in whatever the rounding, so it should be paired with a
+/// `-frames:v` limit in case ffmpeg also keeps the frame that starts at `duration`.
+fn ffmpeg_duration(duration: &Rational64, step: &Rational64) -> String {
+    ffmpeg_time(&(*duration + step.abs() / 2))
+}
+
+/// ffmpeg command for `Op::FFmpegRetime`, which reads `range` from `input` at any speed or
+/// direction and writes it at `out_step`
+fn retime_command(
+    input: &str,
+    range: &Range,
+    source_step: &Rational64,
+    out_step: &Rational64,
+    out: &str,
+    config: &OutputConfig,
+    seeks: &SeekOffsets,
+) -> std::process::Command {
+    let first = range.start;
+    let last = range.end - range.step;
+    let reversed = range.step < Rational64::new(0, 1);
+    let (lo, hi) = if reversed {
+        (last, first)
+    } else {
+        (first, last)
+    };
+
+    let mut cmd = std::process::Command::new("ffmpeg");
+    cmd.arg("-hide_banner");
+    cmd.arg("-loglevel").arg("error");
+
+    // Decode every source frame in [lo, hi] and nothing after it, so reversing only buffers
+    // those frames
+    cmd.arg("-ss").arg(seek_time(seeks, input, &lo));
+    cmd.arg("-i").arg(input);
+    let source_frames = ((hi - lo) / source_step).floor().to_integer() + 1;
+
+    // Lay the decoded frames out in playback order at the output speed, then let the fps
+    // filter keep (or duplicate) the frame nearest to each output time. Slowed down reads can
+    // run out of source frames just before the end, so hold the last one.
+    let speed = range.step.abs() / out_step;
+    let mut filter = format!("trim=end_frame={source_frames},");
+    if reversed {
+        filter.push_str("reverse,");
+    }
+    filter.push_str(&format!(
+        "setpts=N*{}/TB,fps={},tpad=stop=-1:stop_mode=clone,{}",
+        ffmpeg_rational(&(source_step / speed)),
+        ffmpeg_rational(&out_step.recip()),
+        config.scale(),
+    ));
+    cmd.arg("-vf").arg(filter);
+    cmd.arg("-frames:v")
+        .arg(range.frame_times().count().to_string());
+    config.encoder_args(&mut cmd);
+
+    cmd.arg(out);
+    cmd.arg("-y");
+    cmd
+}
+
+/// Frame rate the approximate preview renders at, half the full rate
+fn approx_rate(step: &Rational64) -> String {
+    ffmpeg_rational(&(step.abs() * 2).recip())
+}
+
+fn ffmpeg_rational(t: &Rational64) -> String {
+    format!("{}/{}", t.numer(), t.denom())
+}
+
+/// `-ss` value for reading `input` from source time `t`
+fn seek_time(seeks: &SeekOffsets, input: &str, t: &Rational64) -> String {
+    let offset = seeks.get(input).copied().unwrap_or_default();
+    ffmpeg_time(&(*t + offset))
+}
+
+impl Op {
+    fn out(&self) -> &str {
+        match self {
+            Op::FFmpegClip { out, .. }
+            | Op::FFmpegRetime { out, .. }
+            | Op::FFmpegFreeze { out, .. }
+            | Op::FFmpegConcat { out, .. }
+            | Op::FFmpegFilter { out, .. }
+            | Op::Cached { out, .. } => out,
+        }
+    }
+
+    fn out_mut(&mut self) -> &mut String {
+        match self {
+            Op::FFmpegClip { out, .. }
+            | Op::FFmpegRetime { out, .. }
+            | Op::FFmpegFreeze { out, .. }
+            | Op::FFmpegConcat { out, .. }
+            | Op::FFmpegFilter { out, .. }
+            | Op::Cached { out, .. } => out,
+        }
+    }
+
+    /// Every file this op reads
+    fn inputs_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Op::FFmpegClip { input, .. }
+            | Op::FFmpegRetime { input, .. }
+            | Op::FFmpegFreeze { input, .. } => vec![input],
+            Op::FFmpegConcat { inputs, .. } => inputs.iter_mut().collect(),
+            Op::FFmpegFilter { inputs, .. } => inputs.iter_mut().map(|(input, _)| input).collect(),
+            Op::Cached { file, .. } => vec![file],
+        }
+    }
+
+    fn run(&self, seeks: &SeekOffsets) {
+        match self {
+            Op::Cached { file, out } => {
+                info!("Copying cached {file} to {out}");
+                fs::copy(file, out).expect("Unable to copy cached output");
+            }
+            Op::FFmpegClip {
+                input,
+                range,
+                out,
+                method,
+                config,
+            } => {
+                let mut cmd = std::process::Command::new("ffmpeg");
+                cmd.arg("-hide_banner");
+                cmd.arg("-loglevel").arg("error");
+
+                cmd.arg("-ss").arg(seek_time(seeks, input, &range.start));
+                cmd.arg("-i").arg(input);
+                cmd.arg("-t")
+                    .arg(ffmpeg_duration(&(range.end - range.start), &range.step));
+                cmd.arg("-frames:v")
+                    .arg(range.frame_times().count().to_string());
+
+                match method {
+                    FFmpegClipMethod::StreamCopy => {
+                        cmd.arg("-c:v").arg("copy");
+                        cmd.arg("-c:a").arg("copy");
+                    }
+                    FFmpegClipMethod::Transcode => {
+                        config.encoder_args(&mut cmd);
+                        cmd.arg("-vf").arg(config.scale());
+                    }
+                    FFmpegClipMethod::ConstantRate => {
+                        config.encoder_args(&mut cmd);
+                        cmd.arg("-vf").arg(format!(
+                            "fps={}:start_time=0:round=near,{}",
+                            ffmpeg_rational(&range.step.recip()),
+                            config.scale()
+                        ));
+                    }
+                }
+
+                cmd.arg(out);
+                cmd.arg("-y");
+
+                info!("{cmd:?}",);
+
+                // run cmd
+                let status = cmd.status().expect("failed to execute process");
+                assert!(status.success());
+            }
+            Op::FFmpegRetime {
+                input,
+                range,
+                source_step,
+                out_step,
+                out,
+                config,
+            } => {
+                let mut cmd =
+                    retime_command(input, range, source_step, out_step, out, config, seeks);
+                info!("{cmd:?}",);
+                let status = cmd.status().expect("failed to execute process");
+                assert!(status.success());
+            }
+            Op::FFmpegFreeze {
+                input,
+                t,
+                frames,
+                out_step,
+                out,
+                config,
+            } => {
+                let mut cmd = std::process::Command::new("ffmpeg");
+                cmd.arg("-hide_banner");
+                cmd.arg("-loglevel").arg("error");
+
+                cmd.arg("-ss").arg(seek_time(seeks, input, t));
+                cmd.arg("-i").arg(input);
+                cmd.arg("-vf").arg(format!(
+                    "trim=end_frame=1,loop=loop={}:size=1,setpts=N*{}/TB,{}",
+                    frames - 1,
+                    ffmpeg_rational(out_step),
+                    config.scale(),
+                ));
+                cmd.arg("-frames:v").arg(frames.to_string());
+                config.encoder_args(&mut cmd);
+
+                cmd.arg(out);
+                cmd.arg("-y");
+
+                info!("{cmd:?}",);
+                let status = cmd.status().expect("failed to execute process");
+                assert!(status.success());
+            }
+            Op::FFmpegConcat { inputs: input, out } => {
+                let job_file_path = format!("/scratch/tmp_{}.txt", Uuid::new_v4());
+                let job_file_content = input
+                    .iter()
+                    .map(|f| format!("file '{f}'",))
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                fs::write(&job_file_path, job_file_content).expect("Unable to write to file");
+
+                let mut cmd = std::process::Command::new("ffmpeg");
+                cmd.arg("-hide_banner");
+                cmd.arg("-loglevel").arg("error");
+
+                cmd.arg("-f").arg("concat");
+                cmd.arg("-safe").arg("0");
+                cmd.arg("-i").arg(job_file_path);
+                cmd.arg("-c").arg("copy");
+
+                cmd.arg(out);
+                cmd.arg("-y");
+
+                info!("{cmd:?}",);
+                let status = cmd.status().expect("failed to execute process");
+                assert!(status.success());
+            }
+            Op::FFmpegFilter {
+                inputs,
+                filter,
+                approx,
+                out,
+                config,
+            } => {
+                let mut cmd = std::process::Command::new("ffmpeg");
+                cmd.arg("-hide_banner");
+                cmd.arg("-loglevel").arg("error");
+
+                for (input, input_range) in inputs {
+                    if let Some(input_range) = input_range {
+                        cmd.arg("-ss")
+                            .arg(seek_time(seeks, input, &input_range.start));
+                    }
+                    cmd.arg("-i").arg(input);
+                }
+
+                if let Some(input_range) = &inputs[0].1 {
+                    cmd.arg("-t").arg(ffmpeg_duration(
+                        &(input_range.end - input_range.start),
+                        &input_range.step,
+                    ));
+                    // approximate renders drop frames, so only full renders know the count
+                    if !*approx {
+                        cmd.arg("-frames:v")
+                            .arg(input_range.frame_times().count().to_string());
+                    }
+                }
+
+                match filter {
+                    // complex filters should handle their own resolution
+                    FilterGraph::Grid(grid) => {
+                        cmd.arg("-filter_complex")
+                            .arg(grid.filter_complex(inputs.len(), *approx));
+                    }
+                    FilterGraph::PictureInPicture(pip) => {
+                        cmd.arg("-filter_complex")
+                            .arg(pip.filter_complex(*approx, config));
+                    }
+                    FilterGraph::Transition(transition) => {
+                        cmd.arg("-filter_complex")
+                            .arg(transition.filter_complex(*approx, config));
+                    }
+                    // crops are in source pixels, so they scale to the target size themselves
+                    FilterGraph::Crop(crop) => {
+                        cmd.arg("-vf").arg(crop.filter(*approx, config));
+                    }
+                    FilterGraph::Adjust(adjustments) => {
+                        let chain: Vec<String> = adjustments.iter().map(|a| a.filter()).collect();
+                        // chains don't know their rate, so halve whatever the input runs at
+                        let fps = if *approx { "fps=source_fps/2," } else { "" };
+                        cmd.arg("-vf")
+                            .arg(format!("{fps}{},{}", config.scale(), chain.join(",")));
+                    }
+                    FilterGraph::Chain(filter) if *approx => {
+                        cmd.arg("-vf")
+                            .arg(format!("fps=source_fps/2,{},{filter}", config.scale()));
+                    }
+                    FilterGraph::Chain(filter) => {
+                        cmd.arg("-vf").arg(format!("{},{filter}", config.scale()));
+                    }
+                }
+
+                config.encoder_args(&mut cmd);
 
                 cmd.arg(out);
                 cmd.arg("-y");
@@ -540,29 +1628,45 @@ struct DOp {
 }
 
 impl DOp {
-    fn run(&self, parallel: bool) {
+    fn run(&self, parallel: bool, seeks: &SeekOffsets, cache: Option<(&OpCache, &CacheKeys)>) {
         if parallel {
-            self.deps.par_iter().for_each(|dep| dep.run(parallel));
+            self.deps
+                .par_iter()
+                .for_each(|dep| dep.run(parallel, seeks, cache));
         } else {
             for dep in &self.deps {
-                dep.run(parallel);
+                dep.run(parallel, seeks, cache);
             }
         }
 
-        self.op.run();
+        self.op.run(seeks);
+        if let Some((cache, keys)) = cache {
+            self.cache_output(cache, keys);
+        }
     }
 
     fn optimize_shard_filters(self) -> DOp {
-        const SHARD_FRAMES: i64 = 300;
-
         match self.op {
             Op::FFmpegFilter {
                 inputs,
                 filter,
-                complex,
                 out,
                 approx,
-            } if inputs[0].1.is_some() && inputs[0].1.clone().unwrap().len() > SHARD_FRAMES => {
+                config,
+            } if inputs[0].1.is_some()
+                && inputs[0].1.clone().unwrap().len() > SHARD_FRAMES
+                && filter.is_shardable()
+                // every shard would make its own copy of a variable frame rate input
+                && !self.deps.iter().any(|dep| {
+                    matches!(
+                        dep.op,
+                        Op::FFmpegClip {
+                            method: FFmpegClipMethod::ConstantRate,
+                            ..
+                        }
+                    )
+                }) =>
+            {
                 let mut out_deps = vec![];
                 let mut out_inputs = vec![];
 
@@ -579,6 +1683,12 @@ impl DOp {
                 let shard_duration: Rational64 =
                     Rational64::new(SHARD_FRAMES, 1) * active_ranges[0].step;
 
+                // filters that vary over time need to know where each shard starts
+                let first_input = active_ranges[0].clone();
+                let frames_before = |shard: &Range| {
+                    ((shard.start - first_input.start) / first_input.step).to_integer() as usize
+                };
+
                 loop {
                     let shard_pts: Vec<Rational64> = active_ranges
                         .iter()
@@ -600,11 +1710,11 @@ impl DOp {
                     }
 
                     let shard = Op::FFmpegFilter {
+                        filter: filter.skip_frames(frames_before(&splits[0].0)),
                         inputs: new_inputs,
-                        filter: filter.clone(),
-                        complex,
                         out: shard_name.clone(),
                         approx,
+                        config: config.clone(),
                     };
                     out_deps.push(DOp {
                         op: shard,
@@ -628,11 +1738,11 @@ impl DOp {
                             new_inputs.push((inputs[i].0.clone(), Some(splits[i].1.clone())));
                         }
                         let shard = Op::FFmpegFilter {
+                            filter: filter.skip_frames(frames_before(&splits[0].1)),
                             inputs: new_inputs,
-                            filter: filter.clone(),
-                            complex,
                             out: shard_name.clone(),
                             approx,
+                            config: config.clone(),
                         };
                         out_deps.push(DOp {
                             op: shard,
@@ -681,14 +1791,14 @@ impl DOp {
                 range,
                 out,
                 method,
-                codec,
+                config,
             } => DOp {
                 op: Op::FFmpegClip {
                     input,
                     range,
                     out,
                     method,
-                    codec,
+                    config,
                 },
                 deps: self
                     .deps
@@ -696,12 +1806,20 @@ impl DOp {
                     .map(|x| x.optimize_seek_pullup())
                     .collect(),
             },
+            op @ (Op::FFmpegRetime { .. } | Op::FFmpegFreeze { .. } | Op::Cached { .. }) => DOp {
+                op,
+                deps: self
+                    .deps
+                    .into_iter()
+                    .map(|x| x.optimize_seek_pullup())
+                    .collect(),
+            },
             Op::FFmpegFilter {
                 mut inputs,
                 filter,
-                complex,
                 out,
                 approx,
+                config,
             } => {
                 let mut out_deps = vec![];
                 for (i, dep) in self.deps.into_iter().enumerate() {
@@ -711,7 +1829,7 @@ impl DOp {
                             range,
                             out,
                             method,
-                            codec,
+                            config,
                         } => {
                             if method == FFmpegClipMethod::Transcode && dep.deps.is_empty() {
                                 inputs[i].0 = input;
@@ -723,7 +1841,7 @@ impl DOp {
                                         range,
                                         out,
                                         method,
-                                        codec,
+                                        config,
                                     },
                                     deps: dep.deps,
                                 });
@@ -739,9 +1857,9 @@ impl DOp {
                     op: Op::FFmpegFilter {
                         inputs,
                         filter,
-                        complex,
                         out,
                         approx,
+                        config,
                     },
                     deps: out_deps,
                 }
@@ -749,44 +1867,259 @@ impl DOp {
         }
     }
 
-    fn optimize_smart_cut(self, datastore: &Datastore) -> DOp {
-        match self.op {
+    /// Make ops reading variable frame rate sources see frames on the source's nominal grid.
+    /// Clips convert while they cut, freezes hold the nearest real frame, and retimes and
+    /// filters read a constant rate copy of the part of the source they need.
+    fn conform_vfr_sources(self, datastore: &Datastore, seeks: &mut SeekOffsets) -> DOp {
+        let mut deps: Vec<DOp> = self
+            .deps
+            .into_iter()
+            .map(|dep| dep.conform_vfr_sources(datastore, seeks))
+            .collect();
+        let frame_times = |input: &str| {
+            datastore
+                .find_video_by_path(input)
+                .and_then(|(_, video)| video.frame_times.as_ref())
+        };
+        let mut conform = |input: &mut String, range: Range, config: &OutputConfig| {
+            let Some((_, video)) = datastore.find_video_by_path(input) else {
+                return;
+            };
+            if video.frame_times.is_none() {
+                return;
+            }
+            // the op reading the copy scales it, and some, like crops, expect source pixels
+            let (width, height) = video.stream.dims.unwrap_or((config.width, config.height));
+            let config = OutputConfig {
+                width,
+                height,
+                ..config.clone()
+            };
+            let out = format!("/scratch/tmp_{}.mp4", Uuid::new_v4());
+            // the copy starts at `range.start`
+            seeks.insert(out.clone(), -range.start);
+            deps.push(DOp {
+                op: Op::FFmpegClip {
+                    input: std::mem::replace(input, out.clone()),
+                    range,
+                    out,
+                    method: FFmpegClipMethod::ConstantRate,
+                    config,
+                },
+                deps: vec![],
+            });
+        };
+
+        let op = match self.op {
             Op::FFmpegClip {
                 input,
                 range,
                 out,
-                method,
-                codec,
+                method: FFmpegClipMethod::Transcode,
+                config,
+            } if frame_times(&input).is_some() => Op::FFmpegClip {
+                input,
+                range,
+                out,
+                method: FFmpegClipMethod::ConstantRate,
+                config,
+            },
+            Op::FFmpegFreeze {
+                input,
+                t,
+                frames,
+                out_step,
+                out,
+                config,
+            } => Op::FFmpegFreeze {
+                t: frame_times(&input).map_or(t, |times| times.nearest(t)),
+                input,
+                frames,
+                out_step,
+                out,
+                config,
+            },
+            Op::FFmpegRetime {
+                mut input,
+                range,
+                source_step,
+                out_step,
+                out,
+                config,
             } => {
-                let iframes: Vec<Rational64> = datastore
-                    .videos
-                    .get(&datastore.path_to_vid_key(&input))
-                    .unwrap()
-                    .gops
-                    .iter()
-                    .map(|g| g.start)
-                    .filter(|t| *t >= range.start && *t <= range.end)
-                    .collect();
-
-                // println!("iframes: {:?}", iframes);
-
-                if iframes.len() >= 2
-                    && method == FFmpegClipMethod::Transcode
-                    && self.deps.is_empty()
-                {
-                    let head_name = format!("/scratch/tmp_{}.mp4", Uuid::new_v4());
-                    let head = Op::FFmpegClip {
-                        input: input.clone(),
-                        range: Range {
-                            start: range.start,
-                            end: iframes[0],
-                            step: range.step,
-                        },
-                        out: head_name.clone(),
-                        method: FFmpegClipMethod::Transcode,
-                        codec,
-                    };
-
+                let first = range.start;
+                let last = range.end - range.step;
+                let read = Range {
+                    start: first.min(last),
+                    end: first.max(last) + source_step,
+                    step: source_step,
+                };
+                conform(&mut input, read, &config);
+                Op::FFmpegRetime {
+                    input,
+                    range,
+                    source_step,
+                    out_step,
+                    out,
+                    config,
+                }
+            }
+            Op::FFmpegFilter {
+                mut inputs,
+                filter,
+                approx,
+                out,
+                config,
+            } => {
+                for (input, range) in &mut inputs {
+                    if let Some(range) = range {
+                        conform(input, range.clone(), &config);
+                    }
+                }
+                Op::FFmpegFilter {
+                    inputs,
+                    filter,
+                    approx,
+                    out,
+                    config,
+                }
+            }
+            op => op,
+        };
+        DOp { op, deps }
+    }
+
+    /// Read plain clips from whichever copy of their source in the datastore leaves the fewest
+    /// frames to encode once smart cut, e.g. a transcode with shorter GOPs
+    fn optimize_derived_sources(self, datastore: &Datastore) -> DOp {
+        match self.op {
+            Op::FFmpegClip {
+                input,
+                range,
+                out,
+                method: FFmpegClipMethod::Transcode,
+                config,
+            } if self.deps.is_empty() => {
+                let encoded_frames = |video: &Video, range: &Range| {
+                    let keyframes: Vec<Rational64> = video
+                        .gops
+                        .iter()
+                        .filter(|g| g.closed)
+                        .map(|g| g.start)
+                        .filter(|t| *t >= range.start && *t <= range.end)
+                        .collect();
+                    let copyable = keyframes.len() >= 2
+                        && video.stream.can_copy_into(&config)
+                        && video.frame_times.is_none();
+                    let encoded = if copyable {
+                        keyframes[0] - range.start + range.end - keyframes[keyframes.len() - 1]
+                    } else {
+                        range.end - range.start
+                    };
+                    (encoded / range.step).to_integer()
+                };
+
+                let mut best: Option<(i64, &Video, Range)> = None;
+                for (video, shift) in datastore.same_picture_videos(&input) {
+                    let shifted = Range {
+                        start: range.start + shift,
+                        end: range.end + shift,
+                        step: range.step,
+                    };
+                    let fits = video.range.step == range.step
+                        && (shift / range.step).is_integer()
+                        && shifted.start >= video.range.start
+                        && shifted.end <= video.range.end
+                        && video.frame_times.is_none();
+                    if !fits {
+                        continue;
+                    }
+                    let cost = encoded_frames(video, &shifted);
+                    // stay on the source the spec asked for unless a copy is strictly cheaper
+                    let better = match &best {
+                        None => true,
+                        Some((best_cost, _, _)) => {
+                            cost < *best_cost || (cost == *best_cost && video.path == input)
+                        }
+                    };
+                    if better {
+                        best = Some((cost, video, shifted));
+                    }
+                }
+
+                let (input, range) = match best {
+                    Some((_, video, shifted)) if video.path != input => {
+                        debug!("Reading {} instead of {}", video.path, input);
+                        (video.path.clone(), shifted)
+                    }
+                    _ => (input, range),
+                };
+                DOp {
+                    op: Op::FFmpegClip {
+                        input,
+                        range,
+                        out,
+                        method: FFmpegClipMethod::Transcode,
+                        config,
+                    },
+                    deps: vec![],
+                }
+            }
+            op => DOp {
+                op,
+                deps: self
+                    .deps
+                    .into_iter()
+                    .map(|dep| dep.optimize_derived_sources(datastore))
+                    .collect(),
+            },
+        }
+    }
+
+    fn optimize_smart_cut(self, datastore: &Datastore) -> DOp {
+        match self.op {
+            Op::FFmpegClip {
+                input,
+                range,
+                out,
+                method,
+                config,
+            } => {
+                let video = datastore
+                    .videos
+                    .get(&datastore.path_to_vid_key(&input))
+                    .unwrap();
+                // only closed GOPs can start or end a stream copy
+                let iframes: Vec<Rational64> = video
+                    .gops
+                    .iter()
+                    .filter(|g| g.closed)
+                    .map(|g| g.start)
+                    .filter(|t| *t >= range.start && *t <= range.end)
+                    .collect();
+
+                // println!("iframes: {:?}", iframes);
+
+                // Only sources already in the output's codec, size and pixel format can be
+                // smart cut, anything else is transcoded whole
+                if iframes.len() >= 2
+                    && method == FFmpegClipMethod::Transcode
+                    && self.deps.is_empty()
+                    && video.stream.can_copy_into(&config)
+                {
+                    let head_name = format!("/scratch/tmp_{}.mp4", Uuid::new_v4());
+                    let head = Op::FFmpegClip {
+                        input: input.clone(),
+                        range: Range {
+                            start: range.start,
+                            end: iframes[0],
+                            step: range.step,
+                        },
+                        out: head_name.clone(),
+                        method: FFmpegClipMethod::Transcode,
+                        config: config.clone(),
+                    };
+
                     let body_name = format!("/scratch/tmp_{}.mp4", Uuid::new_v4());
                     let body = Op::FFmpegClip {
                         input: input.clone(),
@@ -797,7 +2130,7 @@ impl DOp {
                         },
                         out: body_name.clone(),
                         method: FFmpegClipMethod::StreamCopy,
-                        codec,
+                        config: config.clone(),
                     };
 
                     let tail_name = format!("/scratch/tmp_{}.mp4", Uuid::new_v4());
@@ -810,7 +2143,7 @@ impl DOp {
                         },
                         out: tail_name.clone(),
                         method: FFmpegClipMethod::Transcode,
-                        codec,
+                        config,
                     };
 
                     let mut concat_inputs = vec![];
@@ -856,12 +2189,17 @@ impl DOp {
                             range,
                             out,
                             method,
-                            codec,
+                            config,
                         },
                         deps: self.deps,
                     }
                 }
             }
+            // Retimed frames never line up with source GOPs, so these can't be stream copied
+            op @ (Op::FFmpegRetime { .. } | Op::FFmpegFreeze { .. } | Op::Cached { .. }) => DOp {
+                op,
+                deps: self.deps,
+            },
             Op::FFmpegConcat { inputs: input, out } => DOp {
                 op: Op::FFmpegConcat { inputs: input, out },
                 deps: self
@@ -873,22 +2211,79 @@ impl DOp {
             Op::FFmpegFilter {
                 inputs,
                 filter,
-                complex,
                 out,
                 approx,
+                config,
             } => DOp {
                 op: Op::FFmpegFilter {
                     inputs,
                     filter,
-                    complex,
                     out,
                     approx,
+                    config,
                 },
                 deps: self.deps,
             },
         }
     }
 
+    /// Merge adjustments applied directly to the output of other adjustments into one filter, so
+    /// the intermediate clip is never encoded
+    fn optimize_fuse_adjustments(self) -> DOp {
+        let deps: Vec<DOp> = self
+            .deps
+            .into_iter()
+            .map(|dep| dep.optimize_fuse_adjustments())
+            .collect();
+
+        match self.op {
+            Op::FFmpegFilter {
+                inputs,
+                filter: FilterGraph::Adjust(mut outer),
+                approx,
+                config,
+                out,
+            } if inputs.len() == 1 && inputs[0].1.is_none() && deps.len() == 1 => {
+                match deps.into_iter().next().unwrap() {
+                    DOp {
+                        op:
+                            Op::FFmpegFilter {
+                                inputs: inner_inputs,
+                                filter: FilterGraph::Adjust(mut inner),
+                                approx: inner_approx,
+                                out: inner_out,
+                                config: _,
+                            },
+                        deps: inner_deps,
+                    } if inner_out == inputs[0].0 && inner_approx == approx => {
+                        inner.append(&mut outer);
+                        DOp {
+                            op: Op::FFmpegFilter {
+                                inputs: inner_inputs,
+                                filter: FilterGraph::Adjust(inner),
+                                approx,
+                                config,
+                                out,
+                            },
+                            deps: inner_deps,
+                        }
+                    }
+                    dep => DOp {
+                        op: Op::FFmpegFilter {
+                            inputs,
+                            filter: FilterGraph::Adjust(outer),
+                            approx,
+                            config,
+                            out,
+                        },
+                        deps: vec![dep],
+                    },
+                }
+            }
+            op => DOp { op, deps },
+        }
+    }
+
     fn optimize_concat_squash(self) -> DOp {
         match self.op {
             Op::FFmpegConcat { inputs, out } => {
@@ -942,31 +2337,237 @@ impl DOp {
     }
 }
 
+/// Seconds to add to source times read from each input path to get the time `-ss` seeks to.
+/// Inputs that aren't listed, like intermediate files, start at 0.
+type SeekOffsets = BTreeMap<String, Rational64>;
+
+/// Cache fingerprints of the ops in a plan, by output path
+type CacheKeys = BTreeMap<String, String>;
+
 #[derive(Debug, Clone)]
 pub struct Plan {
     op: DOp,
+    seek_offsets: SeekOffsets,
+    cache_keys: CacheKeys,
 }
 
 impl Plan {
     pub fn run(&self, parallel: bool) {
-        self.op.run(parallel);
+        self.op.run(parallel, &self.seek_offsets, None);
     }
 
     pub fn optimize_heuristic(&self, datastore: &Datastore) -> Self {
         let mut out = self.clone();
+        out.op = out.op.optimize_fuse_adjustments();
         out.op = out.op.optimize_seek_pullup();
         out.op = out.op.optimize_shard_filters();
+        out.op = out.op.optimize_derived_sources(datastore);
         out.op = out.op.optimize_smart_cut(datastore);
         out.op = out.op.optimize_concat_squash();
         out
     }
 }
 
+/// Boxes on the frame with this number, counting from the start of a clip
+type FrameBoxes = (i64, Vec<BBox>);
+
+/// Build a drawbox/drawtext chain for boxes given by frame index within the filtered clip
+fn bbox_filter(
+    frames: &[FrameBoxes],
+    color: &str,
+    labels: bool,
+    (width, height): (usize, usize),
+) -> String {
+    let mut filters = vec![];
+    for (n, boxes) in frames {
+        for b in boxes {
+            let x = (b.x * width as f64) as i64;
+            let y = (b.y * height as f64) as i64;
+            let w = (b.w * width as f64) as i64;
+            let h = (b.h * height as f64) as i64;
+            filters.push(format!(
+                "drawbox=x={x}:y={y}:w={w}:h={h}:color={color}:enable='eq(n\\,{n})'"
+            ));
+            if let (true, Some(label)) = (labels, &b.label) {
+                let label: String = label
+                    .chars()
+                    .filter(|c| c.is_alphanumeric() || " _-".contains(*c))
+                    .collect();
+                filters.push(format!(
+                    "drawtext=text='{label}':x={x}:y={}:fontcolor=white:fontsize=24:enable='eq(n\\,{n})'",
+                    y - 10
+                ));
+            }
+        }
+    }
+    filters.join(",")
+}
+
+/// `-vf` graph hiding each box on its frame, leaving everything outside the boxes untouched
+fn redact_filter(
+    frames: &[FrameBoxes],
+    style: &str,
+    strength: usize,
+    (width, height): (usize, usize),
+) -> String {
+    let mut regions = vec![];
+    for (n, boxes) in frames {
+        for b in boxes {
+            let x = (b.x * width as f64) as i64;
+            let y = (b.y * height as f64) as i64;
+            let w = (b.w * width as f64).ceil() as i64;
+            let h = (b.h * height as f64).ceil() as i64;
+            regions.push(format!(
+                "drawbox=x={x}:y={y}:w={w}:h={h}:color={{}}:t=fill:enable='eq(n\\,{n})'"
+            ));
+        }
+    }
+
+    // the chain still has to be valid filter graph text
+    if regions.is_empty() {
+        return "null".to_string();
+    }
+
+    let effect = match style {
+        "fill" => {
+            return regions
+                .iter()
+                .map(|r| r.replace("{}", "black"))
+                .collect::<Vec<_>>()
+                .join(",")
+        }
+        "pixelate" => format!(
+            "scale=iw/{s}:ih/{s},scale={width}:{height}:flags=neighbor",
+            s = strength.max(1)
+        ),
+        _ => format!("boxblur={}", strength.max(1)),
+    };
+
+    // apply the effect to the whole frame, then only keep it where a mask has the boxes drawn in
+    let mask: Vec<String> = regions.iter().map(|r| r.replace("{}", "white")).collect();
+    format!(
+        "split=3 [orig][fx][mask];\
+         [fx] {effect}, format=yuva420p [fxd];\
+         [mask] format=gray, drawbox=x=0:y=0:w=iw:h=ih:color=black:t=fill, {} [alpha];\
+         [fxd][alpha] alphamerge [hidden];\
+         [orig][hidden] overlay",
+        mask.join(", ")
+    )
+}
+
+/// Typed adjustment for one of the adjustment F2Fs over `range`, given its resolved args
+fn adjustment(func: &F2FType, range: &Range, args: &[ArrayValue]) -> Adjustment {
+    let num = |i: usize, default: f64| match args.get(i) {
+        Some(ArrayValue::Num(n)) => n.to_f64().unwrap(),
+        None | Some(ArrayValue::Null) => default,
+        Some(v) => panic!("{:?} argument {} must be a number, got {:?}", func, i, v),
+    };
+    let string = |i: usize| match args.get(i) {
+        Some(ArrayValue::Str(s)) => s.as_str(),
+        v => panic!("{:?} argument {} must be a string, got {:?}", func, i, v),
+    };
+    let rational = |i: usize| match args.get(i) {
+        Some(ArrayValue::Num(n)) => *n,
+        v => panic!("{:?} argument {} must be a number, got {:?}", func, i, v),
+    };
+
+    match func {
+        F2FType::Adjust => Adjustment::Eq {
+            brightness: num(0, 0.0),
+            contrast: num(1, 1.0),
+            saturation: num(2, 1.0),
+        },
+        F2FType::Grayscale => Adjustment::Grayscale,
+        F2FType::Sharpen => Adjustment::Sharpen(num(0, 1.0)),
+        F2FType::Blur => Adjustment::Blur(num(0, 0.0)),
+        F2FType::Rotate => Adjustment::Rotate(num(0, 0.0)),
+        F2FType::Flip => Adjustment::Flip {
+            horizontal: string(0) == "horizontal",
+        },
+        F2FType::Fade => Adjustment::Fade {
+            fade_in: string(0) == "in",
+            start: rational(1) - range.start,
+            duration: rational(2),
+        },
+        _ => panic!("{:?} is not an adjustment", func),
+    }
+}
+
+/// Layout for a `Grid` (or `Quadrents`) F2F from its constant args
+fn grid_layout(
+    func: &F2FType,
+    args: &[DataExpr],
+    step: Rational64,
+    config: &OutputConfig,
+) -> GridLayout {
+    let num = |i: usize, default: usize| match args.get(i) {
+        Some(DataExpr::ConstNum(n)) => n.to_integer() as usize,
+        None => default,
+        Some(arg) => panic!(
+            "{:?} argument {} must be a constant number, got {}",
+            func, i, arg
+        ),
+    };
+    let (rows, cols) = match func {
+        F2FType::Quadrents => (2, 2),
+        _ => (num(0, 1), num(1, 1)),
+    };
+    GridLayout {
+        rows,
+        cols,
+        padding: num(2, 0),
+        background: match args.get(3) {
+            Some(DataExpr::ConstStr(color)) => color.clone(),
+            _ => "black".to_string(),
+        },
+        cell_width: num(4, config.width),
+        cell_height: num(5, config.height),
+        step,
+    }
+}
+
+/// Frame size of `source` when it is read straight from a datastore video with known dimensions
+fn source_dims(datastore: &Datastore, source: &FrameExpr) -> Option<(usize, usize)> {
+    match source {
+        FrameExpr::SourceFunction { source, .. } => {
+            datastore.find_video_by_path(source)?.1.stream.dims
+        }
+        _ => None,
+    }
+}
+
+/// Domain times inside `range` where a read of `source` lands on the start of a closed source GOP.
+/// Only plain reads at normal speed line up with the source's GOPs.
+fn source_keyframes(datastore: &Datastore, source: &FrameExpr, range: &Range) -> Vec<Rational64> {
+    let (video, t) = match source {
+        FrameExpr::SourceFunction { source, t, .. } => match datastore.find_video_by_path(source) {
+            Some((_, video)) => (video, t),
+            None => return vec![],
+        },
+        _ => return vec![],
+    };
+    if !t.is_affine() || t.eval(range.start + range.step) - t.eval(range.start) != range.step {
+        return vec![];
+    }
+    // variable frame rate sources are never stream copied
+    if video.frame_times.is_some() {
+        return vec![];
+    }
+
+    let offset = t.eval(range.start) - range.start;
+    video
+        .gops
+        .iter()
+        .filter(|g| g.closed)
+        .map(|g| g.start - offset)
+        .filter(|t| *t > range.start && *t < range.end)
+        .collect()
+}
+
 pub fn plan_query(query: &Spec, datastore: &Datastore) -> Plan {
-    // println!("Dependency checks:");
-    // for (dep_name, dep_type, range) in query.range_deps() {
-    //     println!("{:?} {} requires {}", dep_type, dep_name, range);
-    // }
+    for (dep_name, dep_type, range) in query.range_deps() {
+        debug!("{:?} {} requires {}", dep_type, dep_name, range);
+    }
 
     // println!();
     // println!("Flattened query:");
@@ -978,7 +2579,14 @@ pub fn plan_query(query: &Spec, datastore: &Datastore) -> Plan {
 
     let mut root_clips = query.flatten_matches();
 
-    fn plan_clip(datastore: &Datastore, range: &Range, expr: FrameExpr, output: &str) -> DOp {
+    fn plan_clip(
+        datastore: &Datastore,
+        arrays: &Arrays,
+        config: &OutputConfig,
+        range: &Range,
+        expr: FrameExpr,
+        output: &str,
+    ) -> DOp {
         match expr {
             FrameExpr::SourceFunction {
                 func,
@@ -987,17 +2595,84 @@ pub fn plan_query(query: &Spec, datastore: &Datastore) -> Plan {
                 args: _,
             } => match func {
                 SourceType::ReadFrame => {
-                    let range = t.range(range);
+                    let pieces = t.linear_pieces(range);
+                    if pieces.len() > 1 {
+                        // loops, freezes, and ramps become one clip per affine piece
+                        let mut piece_outputs = vec![];
+                        let mut piece_deps = vec![];
+                        for (piece_range, piece_t) in pieces {
+                            let piece_output = format!("/scratch/tmp_{}.mp4", Uuid::new_v4());
+                            piece_deps.push(plan_clip(
+                                datastore,
+                                arrays,
+                                config,
+                                &piece_range,
+                                FrameExpr::SourceFunction {
+                                    func: SourceType::ReadFrame,
+                                    source: source.clone(),
+                                    t: piece_t,
+                                    args: vec![],
+                                },
+                                &piece_output,
+                            ));
+                            piece_outputs.push(piece_output);
+                        }
+                        return DOp {
+                            op: Op::FFmpegConcat {
+                                inputs: piece_outputs,
+                                out: output.to_string(),
+                            },
+                            deps: piece_deps,
+                        };
+                    }
 
-                    DOp {
-                        op: Op::FFmpegClip {
-                            input: source.clone(),
-                            range,
-                            out: output.to_string(),
-                            method: FFmpegClipMethod::Transcode,
-                            codec: Codec::H264,
-                        },
-                        deps: vec![],
+                    let out_step = range.step;
+                    if t.eval(range.start) == t.eval(range.start + range.step) {
+                        return DOp {
+                            op: Op::FFmpegFreeze {
+                                input: source.clone(),
+                                t: t.eval(range.start),
+                                frames: range.frame_times().count() as i64,
+                                out_step,
+                                out: output.to_string(),
+                                config: config.clone(),
+                            },
+                            deps: vec![],
+                        };
+                    }
+
+                    let range = t.range(range);
+                    if range.step == out_step {
+                        DOp {
+                            op: Op::FFmpegClip {
+                                input: source.clone(),
+                                range,
+                                out: output.to_string(),
+                                method: FFmpegClipMethod::Transcode,
+                                config: config.clone(),
+                            },
+                            deps: vec![],
+                        }
+                    } else {
+                        // fast-forward, slow motion, or reversed playback
+                        let source_step = datastore
+                            .videos
+                            .get(&datastore.path_to_vid_key(&source))
+                            .unwrap()
+                            .range
+                            .step;
+
+                        DOp {
+                            op: Op::FFmpegRetime {
+                                input: source.clone(),
+                                range,
+                                source_step,
+                                out_step,
+                                out: output.to_string(),
+                                config: config.clone(),
+                            },
+                            deps: vec![],
+                        }
                     }
                 }
             },
@@ -1007,221 +2682,1587 @@ pub fn plan_query(query: &Spec, datastore: &Datastore) -> Plan {
                 mut sources,
                 args,
             } => match func {
-                F2FType::Quadrents => {
-                    let quad_outs = [
-                        format!("/scratch/tmp_{}.mp4", Uuid::new_v4()),
-                        format!("/scratch/tmp_{}.mp4", Uuid::new_v4()),
-                        format!("/scratch/tmp_{}.mp4", Uuid::new_v4()),
-                        format!("/scratch/tmp_{}.mp4", Uuid::new_v4()),
-                    ];
-
-                    let deps = [
-                        plan_clip(datastore, range, sources.remove(0), &quad_outs[0]),
-                        plan_clip(datastore, range, sources.remove(0), &quad_outs[1]),
-                        plan_clip(datastore, range, sources.remove(0), &quad_outs[2]),
-                        plan_clip(datastore, range, sources.remove(0), &quad_outs[3]),
-                    ];
+                F2FType::BoundingBoxes => {
+                    let color = match args.get(1) {
+                        Some(DataExpr::ConstStr(color)) => color.clone(),
+                        _ => "red@0.5".to_string(),
+                    };
+                    let labels = !matches!(args.get(2), Some(DataExpr::ConstBool(false)));
+                    plan_box_regions(
+                        datastore,
+                        arrays,
+                        config,
+                        range,
+                        sources.remove(0),
+                        &args[0],
+                        output,
+                        &|boxes| bbox_filter(boxes, &color, labels, (config.width, config.height)),
+                    )
+                }
+                F2FType::Redact => {
+                    let style = match args.get(1) {
+                        Some(DataExpr::ConstStr(style)) => style.clone(),
+                        _ => "blur".to_string(),
+                    };
+                    let strength = match args.get(2) {
+                        Some(DataExpr::ConstNum(n)) => n.to_integer() as usize,
+                        _ => 20,
+                    };
+                    plan_box_regions(
+                        datastore,
+                        arrays,
+                        config,
+                        range,
+                        sources.remove(0),
+                        &args[0],
+                        output,
+                        &|boxes| {
+                            redact_filter(boxes, &style, strength, (config.width, config.height))
+                        },
+                    )
+                }
+                F2FType::PictureInPicture => {
+                    let positions = arrays::resolve_args(range, &args[..2], arrays)
+                        .into_iter()
+                        .flat_map(|(run, values)| {
+                            let coord = |v: &ArrayValue| match v {
+                                ArrayValue::Num(n) => Some(n.to_f64().unwrap()),
+                                ArrayValue::Null => None,
+                                v => panic!(
+                                    "PictureInPicture position must be a number, got {:?}",
+                                    v
+                                ),
+                            };
+                            let pos = (coord(&values[0]), coord(&values[1]));
+                            std::iter::repeat_n(pos, run.frame_times().count())
+                        })
+                        // frames without a position keep the inset where it last was
+                        .scan((0, 0), |last, (x, y)| {
+                            if let Some(x) = x {
+                                last.0 = (x * config.width as f64) as i64;
+                            }
+                            if let Some(y) = y {
+                                last.1 = (y * config.height as f64) as i64;
+                            }
+                            Some(*last)
+                        })
+                        .collect();
+                    let constant = |i: usize, default: Rational64| match args.get(i) {
+                        Some(DataExpr::ConstNum(n)) => *n,
+                        None => default,
+                        Some(arg) => {
+                            panic!(
+                                "PictureInPicture argument {} must be a constant, got {}",
+                                i, arg
+                            )
+                        }
+                    };
+                    let layout = PipLayout {
+                        positions,
+                        width: (constant(2, Rational64::new(1, 4)) * config.width as i64)
+                            .to_integer() as usize,
+                        height: (constant(3, Rational64::new(1, 4)) * config.height as i64)
+                            .to_integer() as usize,
+                        opacity: constant(4, Rational64::from_integer(1)),
+                        border_width: constant(5, Rational64::from_integer(0)).to_integer()
+                            as usize,
+                        border_color: match args.get(6) {
+                            Some(DataExpr::ConstStr(color)) => color.clone(),
+                            _ => "white".to_string(),
+                        },
+                        step: range.step,
+                    };
+
+                    let mut inputs = vec![];
+                    let mut deps = vec![];
+                    for source in sources {
+                        let source_out = format!("/scratch/tmp_{}.mp4", Uuid::new_v4());
+                        deps.push(plan_clip(
+                            datastore,
+                            arrays,
+                            config,
+                            range,
+                            source,
+                            &source_out,
+                        ));
+                        inputs.push((source_out, None));
+                    }
 
                     DOp {
                         op: Op::FFmpegFilter {
-                            inputs: vec![
-                                (quad_outs[0].to_string(), None),
-                                (quad_outs[1].to_string(), None),
-                                (quad_outs[2].to_string(), None),
-                                (quad_outs[3].to_string(), None),
-                            ],
-                            filter: format!("nullsrc=size={DUB_WIDTH}x{DUB_HEIGHT} [base];[0:v] setpts=PTS-STARTPTS, scale={TARGET_WIDTH}x{TARGET_HEIGHT} [upperleft];[1:v] setpts=PTS-STARTPTS, scale={TARGET_WIDTH}x{TARGET_HEIGHT} [upperright];[2:v] setpts=PTS-STARTPTS, scale={TARGET_WIDTH}x{TARGET_HEIGHT} [lowerleft];[3:v] setpts=PTS-STARTPTS, scale={TARGET_WIDTH}x{TARGET_HEIGHT} [lowerright];[base][upperleft] overlay=shortest=1 [tmp1];[tmp1][upperright] overlay=shortest=1:x={TARGET_WIDTH} [tmp2];[tmp2][lowerleft] overlay=shortest=1:y={TARGET_HEIGHT} [tmp3];[tmp3][lowerright] overlay=shortest=1:x={TARGET_WIDTH}:y={TARGET_HEIGHT}", DUB_WIDTH=TARGET_WIDTH * 2, DUB_HEIGHT=TARGET_HEIGHT * 2),
-                            complex: true,
+                            inputs,
+                            filter: FilterGraph::PictureInPicture(layout),
                             out: output.to_string(),
                             approx: false,
+                            config: config.clone(),
                         },
-                        deps: deps.into(),
+                        deps,
                     }
                 }
-                F2FType::Filter => {
+                F2FType::Crop => {
+                    let source = sources.remove(0);
+                    // anything other than a plain read has already been scaled to the target size
+                    let dims = match &source {
+                        FrameExpr::SourceFunction { .. } => source_dims(datastore, &source),
+                        _ => Some((config.width, config.height)),
+                    }
+                    .unwrap_or_else(|| panic!("Crop needs the dimensions of {}", source));
+                    let smoothing = match args.get(1) {
+                        Some(DataExpr::ConstNum(n)) => n.to_integer() as usize,
+                        None => 0,
+                        Some(arg) => panic!("Crop smoothing must be a constant, got {}", arg),
+                    };
+                    let rects: Vec<Option<BBox>> = range
+                        .frame_times()
+                        .map(|t| match args[0].eval(t, arrays) {
+                            ArrayValue::Boxes(mut boxes) if !boxes.is_empty() => {
+                                Some(boxes.remove(0))
+                            }
+                            ArrayValue::Boxes(_) | ArrayValue::Null => None,
+                            v => panic!("Crop needs an array of boxes, got {:?}", v),
+                        })
+                        .collect();
+
+                    // rects are in the pixels the filter reads, so a plain read is clipped at
+                    // the source's size rather than the output's
+                    let source_config = match &source {
+                        FrameExpr::SourceFunction { .. } => OutputConfig {
+                            width: dims.0,
+                            height: dims.1,
+                            ..config.clone()
+                        },
+                        _ => config.clone(),
+                    };
+
                     let source_path = format!("/scratch/tmp_{}.mp4", Uuid::new_v4());
                     DOp {
                         op: Op::FFmpegFilter {
                             inputs: vec![(source_path.clone(), None)],
-                            complex: false,
+                            filter: FilterGraph::Crop(CropLayout {
+                                rects: crop_rects(
+                                    &rects,
+                                    smoothing,
+                                    dims,
+                                    config.width as f64 / config.height as f64,
+                                ),
+                                step: range.step,
+                            }),
+                            out: output.to_string(),
+                            approx: false,
+                            config: config.clone(),
+                        },
+                        deps: vec![plan_clip(
+                            datastore,
+                            arrays,
+                            &source_config,
+                            range,
+                            source,
+                            &source_path,
+                        )],
+                    }
+                }
+                F2FType::Transition => {
+                    let constant = |i: usize| match &args[i] {
+                        DataExpr::ConstNum(n) => *n,
+                        arg => panic!("Transition argument {} must be a constant, got {}", i, arg),
+                    };
+                    let kind = match &args[0] {
+                        DataExpr::ConstStr(kind) => kind.clone(),
+                        arg => panic!("Transition kind must be a string, got {}", arg),
+                    };
+                    let (start, duration) = (constant(1), constant(2));
+                    // both sides are read over the whole transition so xfade sees every frame
+                    let window = Range {
+                        start,
+                        end: start + duration,
+                        step: range.step,
+                    };
+
+                    let mut inputs = vec![];
+                    let mut deps = vec![];
+                    for source in sources {
+                        let source_out = format!("/scratch/tmp_{}.mp4", Uuid::new_v4());
+                        deps.push(plan_clip(
+                            datastore,
+                            arrays,
+                            config,
+                            &window,
+                            source,
+                            &source_out,
+                        ));
+                        inputs.push((source_out, None));
+                    }
+
+                    DOp {
+                        op: Op::FFmpegFilter {
+                            inputs,
+                            filter: FilterGraph::Transition(TransitionLayout {
+                                kind,
+                                duration,
+                                start: range.start - start,
+                                end: range.end - start,
+                                step: range.step,
+                            }),
+                            out: output.to_string(),
+                            approx: false,
+                            config: config.clone(),
+                        },
+                        deps,
+                    }
+                }
+                F2FType::Quadrents | F2FType::Grid => {
+                    let layout = grid_layout(&func, &args, range.step, config);
+                    let mut inputs = vec![];
+                    let mut deps = vec![];
+                    for source in sources {
+                        let cell_out = format!("/scratch/tmp_{}.mp4", Uuid::new_v4());
+                        deps.push(plan_clip(
+                            datastore, arrays, config, range, source, &cell_out,
+                        ));
+                        inputs.push((cell_out, None));
+                    }
+
+                    DOp {
+                        op: Op::FFmpegFilter {
+                            inputs,
+                            filter: FilterGraph::Grid(layout),
                             out: output.to_string(),
-                            filter: args[0].unwrap_const_str(),
                             approx: false,
+                            config: config.clone(),
                         },
-                        deps: vec![plan_clip(datastore, range, sources.remove(0), &source_path)],
+                        deps,
                     }
                 }
+                F2FType::Filter => plan_filter_runs(
+                    datastore,
+                    arrays,
+                    config,
+                    range,
+                    sources.remove(0),
+                    &args,
+                    output,
+                    &|_, values| match &values[0] {
+                        ArrayValue::Str(filter) => FilterGraph::Chain(filter.clone()),
+                        // frames without a filter pass through untouched
+                        ArrayValue::Null => FilterGraph::Chain("null".to_string()),
+                        v => panic!("Filter argument must be a string, got {:?}", v),
+                    },
+                ),
+                F2FType::Adjust
+                | F2FType::Grayscale
+                | F2FType::Sharpen
+                | F2FType::Blur
+                | F2FType::Fade
+                | F2FType::Rotate
+                | F2FType::Flip => plan_filter_runs(
+                    datastore,
+                    arrays,
+                    config,
+                    range,
+                    sources.remove(0),
+                    &args,
+                    output,
+                    &|run_range, values| {
+                        FilterGraph::Adjust(vec![adjustment(&func, run_range, values)])
+                    },
+                ),
             },
         }
     }
 
-    if root_clips.len() == 1 {
-        let (clip_range, clip_expr) = root_clips.remove(0);
-        Plan {
-            op: plan_clip(datastore, &clip_range, clip_expr, &query.output),
+    /// Filter `source` over `range`, splitting it into runs wherever an arg changes value and
+    /// concatenating the runs back together
+    #[allow(clippy::too_many_arguments)]
+    fn plan_filter_runs(
+        datastore: &Datastore,
+        arrays: &Arrays,
+        config: &OutputConfig,
+        range: &Range,
+        source: FrameExpr,
+        args: &[DataExpr],
+        output: &str,
+        graph: &dyn Fn(&Range, &[ArrayValue]) -> FilterGraph,
+    ) -> DOp {
+        let mut runs = arrays::resolve_args(range, args, arrays);
+
+        let plan_run = |run_range: &Range, values: &[ArrayValue], run_output: &str| {
+            let source_path = format!("/scratch/tmp_{}.mp4", Uuid::new_v4());
+            DOp {
+                op: Op::FFmpegFilter {
+                    inputs: vec![(source_path.clone(), None)],
+                    out: run_output.to_string(),
+                    filter: graph(run_range, values),
+                    approx: false,
+                    config: config.clone(),
+                },
+                deps: vec![plan_clip(
+                    datastore,
+                    arrays,
+                    config,
+                    run_range,
+                    source.clone(),
+                    &source_path,
+                )],
+            }
+        };
+
+        if runs.len() == 1 {
+            let (run_range, values) = runs.remove(0);
+            return plan_run(&run_range, &values, output);
+        }
+
+        // the filter changes over time, so filter each run separately
+        let mut run_outputs = vec![];
+        let mut run_deps = vec![];
+        for (run_range, values) in runs {
+            let run_output = format!("/scratch/tmp_{}.mp4", Uuid::new_v4());
+            run_deps.push(plan_run(&run_range, &values, &run_output));
+            run_outputs.push(run_output);
+        }
+        DOp {
+            op: Op::FFmpegConcat {
+                inputs: run_outputs,
+                out: output.to_string(),
+            },
+            deps: run_deps,
         }
+    }
+
+    /// Filter the frames that have boxes GOP by GOP, so GOPs without any stay untouched and can
+    /// be stream copied. `filter` builds a `-vf` chain from boxes keyed by frame number.
+    #[allow(clippy::too_many_arguments)]
+    fn plan_box_regions(
+        datastore: &Datastore,
+        arrays: &Arrays,
+        config: &OutputConfig,
+        range: &Range,
+        source: FrameExpr,
+        boxes: &DataExpr,
+        output: &str,
+        filter: &dyn Fn(&[FrameBoxes]) -> String,
+    ) -> DOp {
+        let frames: Vec<(Rational64, Vec<BBox>)> = range
+            .frame_times()
+            .map(|t| match boxes.eval(t, arrays) {
+                ArrayValue::Boxes(boxes) => (t, boxes),
+                ArrayValue::Null => (t, vec![]),
+                v => panic!("expected an array of boxes, got {:?}", v),
+            })
+            .collect();
+
+        // (first frame, end frame, frames with boxes) for each run of frames
+        let mut groups: Vec<(usize, usize, usize)> = vec![];
+        let keyframes = source_keyframes(datastore, &source, range);
+        let mut gop_starts: Vec<usize> = keyframes
+            .iter()
+            .map(|k| ((k - range.start) / range.step).to_integer() as usize)
+            .collect();
+        gop_starts.push(frames.len());
+
+        let mut gop_start = 0;
+        for gop_end in gop_starts {
+            let boxed = frames[gop_start..gop_end]
+                .iter()
+                .filter(|f| !f.1.is_empty())
+                .count();
+            match groups.last_mut() {
+                // merge GOPs without detections freely, and GOPs with them until the filter is
+                // long enough
+                Some(last)
+                    if (last.2 == 0 && boxed == 0)
+                        || (last.2 > 0
+                            && boxed > 0
+                            && last.2 + boxed <= MAX_BOX_FRAMES
+                            && ((gop_end - last.0) as i64) < SHARD_FRAMES) =>
+                {
+                    last.1 = gop_end;
+                    last.2 += boxed;
+                }
+                _ => groups.push((gop_start, gop_end, boxed)),
+            }
+            gop_start = gop_end;
+        }
+
+        // Frame numbers in the filter restart with every clip, so a filtered group must never be
+        // sharded. Long GOPs are split instead, and the pieces without boxes are plain clips.
+        let groups: Vec<(usize, usize, usize)> = groups
+            .into_iter()
+            .flat_map(|(start, end, boxed)| {
+                if boxed == 0 {
+                    vec![(start, end, boxed)]
+                } else {
+                    (start..end)
+                        .step_by(SHARD_FRAMES as usize - 1)
+                        .map(|s| {
+                            let e = std::cmp::min(end, s + SHARD_FRAMES as usize - 1);
+                            let boxed = frames[s..e].iter().filter(|f| !f.1.is_empty()).count();
+                            (s, e, boxed)
+                        })
+                        .collect()
+                }
+            })
+            .collect();
+
+        let mut group_plans = vec![];
+        for (start, end, boxed) in &groups {
+            let group_range = Range {
+                start: frames[*start].0,
+                end: frames[*start].0 + range.step * (end - start) as i64,
+                step: range.step,
+            };
+            let group_output = if groups.len() == 1 {
+                output.to_string()
+            } else {
+                format!("/scratch/tmp_{}.mp4", Uuid::new_v4())
+            };
+
+            let plan = if *boxed == 0 {
+                plan_clip(
+                    datastore,
+                    arrays,
+                    config,
+                    &group_range,
+                    source.clone(),
+                    &group_output,
+                )
+            } else {
+                let boxes: Vec<FrameBoxes> = frames[*start..*end]
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, f)| !f.1.is_empty())
+                    .map(|(n, f)| (n as i64, f.1.clone()))
+                    .collect();
+                let source_path = format!("/scratch/tmp_{}.mp4", Uuid::new_v4());
+                DOp {
+                    op: Op::FFmpegFilter {
+                        inputs: vec![(source_path.clone(), None)],
+                        out: group_output.clone(),
+                        filter: FilterGraph::Chain(filter(&boxes)),
+                        approx: false,
+                        config: config.clone(),
+                    },
+                    deps: vec![plan_clip(
+                        datastore,
+                        arrays,
+                        config,
+                        &group_range,
+                        source.clone(),
+                        &source_path,
+                    )],
+                }
+            };
+            group_plans.push((group_output, plan));
+        }
+
+        if group_plans.len() == 1 {
+            return group_plans.remove(0).1;
+        }
+        DOp {
+            op: Op::FFmpegConcat {
+                inputs: group_plans.iter().map(|(out, _)| out.clone()).collect(),
+                out: output.to_string(),
+            },
+            deps: group_plans.into_iter().map(|(_, plan)| plan).collect(),
+        }
+    }
+
+    let arrays = &query.arrays;
+    let config = &query.output_config;
+    let op = if root_clips.len() == 1 {
+        let (clip_range, clip_expr) = root_clips.remove(0);
+        plan_clip(
+            datastore,
+            arrays,
+            config,
+            &clip_range,
+            clip_expr,
+            &query.output,
+        )
     } else {
         let mut ops = vec![];
         let mut root_clip_outputs = vec![];
         for (clip_range, clip_expr) in root_clips {
             let clip_output = format!("/scratch/tmp_{}.mp4", Uuid::new_v4());
-            let clip_plan = plan_clip(datastore, &clip_range, clip_expr, &clip_output);
+            let clip_plan = plan_clip(
+                datastore,
+                arrays,
+                config,
+                &clip_range,
+                clip_expr,
+                &clip_output,
+            );
             ops.push(clip_plan);
             root_clip_outputs.push(clip_output);
         }
-        Plan {
-            op: DOp {
-                op: Op::FFmpegConcat {
-                    inputs: root_clip_outputs,
-                    out: query.output.clone(),
-                },
-                deps: ops,
+        DOp {
+            op: Op::FFmpegConcat {
+                inputs: root_clip_outputs,
+                out: query.output.clone(),
             },
+            deps: ops,
         }
+    };
+    let mut seek_offsets = datastore.seek_offsets();
+    let op = op.conform_vfr_sources(datastore, &mut seek_offsets);
+    Plan {
+        op,
+        seek_offsets,
+        cache_keys: CacheKeys::new(),
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct SourceGopBound {
     start: Rational64,
+    /// Time of the GOP's last frame. Unlike a `Range` end, this frame is part of the GOP.
     end: Rational64,
+    /// Whether decoding can start at this GOP's keyframe without frames from the one before.
+    /// Datastores profiled before this was recorded can't tell, so their GOPs are all treated as
+    /// open and never smart cut until the video is profiled again.
+    #[serde(default)]
+    closed: bool,
 }
 
 impl std::fmt::Debug for SourceGopBound {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "SourceGopBound {{ start: {:.4}, end: {:.4} }}",
+            "SourceGopBound {{ start: {:.4}, end: {:.4}, closed: {} }}",
             self.start.to_f64().unwrap(),
             self.end.to_f64().unwrap(),
+            self.closed,
         )
     }
 }
 
-fn load_meta(meta_path: &str) -> (Range, Vec<SourceGopBound>, Codec) {
-    let x = std::fs::read_to_string(meta_path).unwrap();
-    let v: serde_json::Value = serde_json::from_str(&x).unwrap();
-    let y: String = v["streams"][0]["time_base"].as_str().unwrap().to_string();
-    let tbn = {
-        let mut it = y.split('/');
-        let tmp_numer = it.next().unwrap();
-        debug_assert!(tmp_numer == "1");
-        it.next().unwrap().parse::<i64>().unwrap()
-    };
+pub fn add(left: usize, right: usize) -> usize {
+    left + right
+}
+
+pub fn cleanup() {
+    let status = std::process::Command::new("bash")
+        .arg("-c")
+        .arg("rm -rf /scratch/*")
+        .status()
+        .expect("rm command failed to start");
+    assert!(status.success());
+
+    let status = std::process::Command::new("sync")
+        .status()
+        .expect("sync command failed to start");
+    assert!(status.success());
+}
 
-    let mut gop_bounds: Vec<SourceGopBound> = vec![];
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    fn get_pts(frame: &serde_json::Value) -> i64 {
-        let pts1 = frame["pkt_pts"].as_i64();
-        let pts2 = frame["pts"].as_i64();
+    #[test]
+    fn it_works() {
+        let result = add(2, 2);
+        assert_eq!(result, 4);
+    }
 
-        // do some sanity checks
-        if let Some(pts1) = pts1 {
-            if let Some(pts2) = pts2 {
-                assert_eq!(pts1, pts2);
+    #[test]
+    fn splits_ranges_without_losing_frames() {
+        let range = Range {
+            start: Rational64::from_integer(0),
+            end: Rational64::from_integer(10),
+            step: Rational64::new(1, 24),
+        };
+        assert_eq!(range.len(), 240);
+        let (left, right) = range.split_at(Rational64::from_integer(4));
+        assert_eq!(left.len(), 96);
+        assert_eq!(right.start, left.end);
+        assert_eq!(left.len() + right.len(), range.len());
+    }
+
+    fn test_datastore() -> Datastore {
+        let mut videos = BTreeMap::new();
+        videos.insert(
+            "clip".to_string(),
+            Video {
+                path: "videos/clip.mp4".to_string(),
+                ffprobe_path: "videos/clip.ffprobe.json".to_string(),
+                range: Range {
+                    start: Rational64::new(0, 1),
+                    end: Rational64::new(600, 1),
+                    step: Rational64::new(1, 24),
+                },
+                gops: (0..50)
+                    .map(|i| SourceGopBound {
+                        start: Rational64::new(i * 12, 1),
+                        end: Rational64::new(i * 12 + 12, 1) - Rational64::new(1, 24),
+                        closed: true,
+                    })
+                    .collect(),
+                stream: StreamParams {
+                    codec: Some(Codec::H264),
+                    dims: Some((3840, 2160)),
+                    ..Default::default()
+                },
+                audio: vec![],
+                timing: SourceTiming::default(),
+                frame_times: None,
+                derived_from: None,
+                file: None,
+            },
+        );
+        Datastore {
+            version: DATASTORE_VERSION,
+            videos,
+            tree_idxs: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn remaps_without_keyframes_to_the_same_time() {
+        let remap = TExpr::Remap(
+            Box::new(TExpr::Add(Box::new(TExpr::T), Rational64::new(1, 2))),
+            vec![],
+        );
+        assert_eq!(
+            remap.eval(Rational64::from_integer(3)),
+            Rational64::new(7, 2)
+        );
+    }
+
+    #[test]
+    fn splits_nonlinear_time_into_pieces() {
+        let domain: Range = "Range(0, 3, 1/2)".parse().unwrap();
+
+        // loop a one second clip starting at 10s
+        let looped: TExpr = "((t % 1) + 10)".parse().unwrap();
+        let pieces = looped.linear_pieces(&domain);
+        assert_eq!(pieces.len(), 3);
+        assert_eq!(pieces[1].0, "Range(1, 2, 1/2)".parse().unwrap());
+        assert_eq!(pieces[1].1, "(t + 9)".parse().unwrap());
+
+        // hold the first frame for a second, then ramp to double speed
+        let ramped: TExpr = "remap(t, [0 -> 5, 1 -> 5, 2 -> 7])".parse().unwrap();
+        let pieces = ramped.linear_pieces(&domain);
+        assert_eq!(
+            pieces.iter().map(|p| p.1.clone()).collect::<Vec<_>>(),
+            vec![
+                TExpr::Const(Rational64::new(5, 1)),
+                "((t * 2) + 3)".parse().unwrap(),
+                TExpr::Const(Rational64::new(7, 1)),
+            ]
+        );
+        assert_eq!(pieces[0].0, "Range(0, 3/2, 1/2)".parse().unwrap());
+        assert_eq!(pieces[1].0, "Range(3/2, 5/2, 1/2)".parse().unwrap());
+    }
+
+    #[test]
+    fn flattens_matches_through_f2fs() {
+        let datastore = test_datastore();
+        let spec: Spec = r#"source clip = "videos/clip.mp4";
+            Iter=Range(0, 20, 1/24);
+            Render=Filter(match{
+                t in Range(0, 10, 1/24) => vid<clip>[(t + 60)],
+                t in Range(10, 20, 1/24) => vid<clip>[(t + 120)],
+            }, "gblur=sigma=30");
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+
+        let flat = spec.flatten_matches();
+        assert_eq!(
+            flat.iter()
+                .map(|(r, e)| format!("{} {}", r, e))
+                .collect::<Vec<_>>(),
+            vec![
+                "Range(0, 10, 1/24) Filter(vid<videos/clip.mp4>[(t + 60)], \"gblur=sigma=30\")",
+                "Range(10, 20, 1/24) Filter(vid<videos/clip.mp4>[(t + 120)], \"gblur=sigma=30\")",
+            ]
+        );
+
+        let plan = plan_query(&spec, &datastore);
+        assert!(matches!(plan.op.op, Op::FFmpegConcat { .. }));
+        assert_eq!(plan.op.deps.len(), 2);
+    }
+
+    #[test]
+    fn plans_reversed_reads_as_retimes() {
+        let datastore = test_datastore();
+        let spec: Spec = r#"Iter=Range(0, 60, 1/24);
+            Render=vid<videos/clip.mp4>[((t * -1) + 120)];
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+
+        let plan = plan_query(&spec, &datastore).optimize_heuristic(&datastore);
+        match &plan.op.op {
+            Op::FFmpegRetime {
+                range,
+                source_step,
+                out_step,
+                ..
+            } => {
+                assert_eq!(range.start, Rational64::new(120, 1));
+                assert_eq!(range.end, Rational64::new(60, 1));
+                assert_eq!(range.step, Rational64::new(-1, 24));
+                assert_eq!(*source_step, Rational64::new(1, 24));
+                assert_eq!(*out_step, Rational64::new(1, 24));
             }
+            op => panic!("expected a retime, got {:?}", op),
         }
+    }
 
-        if let Some(pts1) = pts1 {
-            pts1
-        } else {
-            pts2.unwrap()
+    #[test]
+    fn trims_retime_reads_before_reversing_or_stretching() {
+        let datastore = test_datastore();
+        let retime_args = |render: &str| {
+            let spec: Spec = format!(
+                r#"Iter=Range(0, 10, 1/24);
+                Render={render};
+                Output="out.mp4""#
+            )
+            .parse()
+            .unwrap();
+            let plan = plan_query(&spec, &datastore).optimize_heuristic(&datastore);
+            let cmd = match &plan.op.op {
+                Op::FFmpegRetime {
+                    input,
+                    range,
+                    source_step,
+                    out_step,
+                    out,
+                    config,
+                } => retime_command(
+                    input,
+                    range,
+                    source_step,
+                    out_step,
+                    out,
+                    config,
+                    &SeekOffsets::new(),
+                ),
+                op => panic!("expected a retime, got {:?}", op),
+            };
+            cmd.get_args()
+                .map(|a| a.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+        let arg_after = |args: &[String], flag: &str| {
+            let i = args.iter().position(|a| a == flag).unwrap();
+            args[i + 1].clone()
+        };
+
+        // 240 frames backwards from 120s, nothing past them decoded
+        let args = retime_args("vid<videos/clip.mp4>[((t * -1) + 120)]");
+        assert!(!args.contains(&"-t".to_string()));
+        assert_eq!(arg_after(&args, "-ss"), "110.041666");
+        assert!(arg_after(&args, "-vf").starts_with("trim=end_frame=240,reverse,setpts="));
+        assert_eq!(arg_after(&args, "-frames:v"), "240");
+
+        // half speed reads 120 source frames and stretches them over all 240 output frames
+        let args = retime_args("vid<videos/clip.mp4>[((t * 1/2) + 20)]");
+        assert!(!args.contains(&"-t".to_string()));
+        let filter = arg_after(&args, "-vf");
+        assert!(filter.starts_with("trim=end_frame=120,setpts=N*1/12/TB,fps=24/1,tpad="));
+        assert_eq!(arg_after(&args, "-frames:v"), "240");
+    }
+
+    #[test]
+    fn threads_output_config_through_every_op() {
+        let datastore = test_datastore();
+        let spec: Spec = r#"Iter=Range(0, 40, 1/24);
+            Render=match{
+                t in Range(0, 20, 1/24) => vid<videos/clip.mp4>[(t + 5)],
+                t in Range(20, 40, 1/24) => Grayscale(vid<videos/clip.mp4>[(t + 100)]),
+            };
+            Output="out.webm"{width=1920, height=1080, codec="vp9", crf=31, container="webm"}"#
+            .parse()
+            .unwrap();
+        assert!(spec.validate(&datastore).is_empty());
+        assert_eq!(
+            spec.to_string().parse::<Spec>().unwrap().output_config,
+            spec.output_config
+        );
+
+        fn configs(dop: &DOp, out: &mut Vec<OutputConfig>) {
+            match &dop.op {
+                Op::FFmpegClip { config, .. }
+                | Op::FFmpegRetime { config, .. }
+                | Op::FFmpegFreeze { config, .. }
+                | Op::FFmpegFilter { config, .. } => out.push(config.clone()),
+                Op::FFmpegConcat { .. } | Op::Cached { .. } => {}
+            }
+            for dep in &dop.deps {
+                configs(dep, out);
+            }
+        }
+        let plan = plan_query(&spec, &datastore).optimize_heuristic(&datastore);
+        let mut found = vec![];
+        configs(&plan.op, &mut found);
+        assert!(found.len() > 2);
+        assert!(found.iter().all(|c| *c == spec.output_config));
+    }
+
+    #[test]
+    fn only_filters_gops_with_boxes() {
+        let datastore = test_datastore();
+        let spec: Spec =
+            r#"array dets = inline(0, 12, [null, [box(0.1, 0.1, 0.5, 0.5, "zebra")], null]);
+            Iter=Range(0, 36, 1/24);
+            Render=BoundingBoxes(vid<videos/clip.mp4>[t], dets[t], "blue");
+            Output="out.mp4""#
+                .parse()
+                .unwrap();
+        assert!(spec.validate(&datastore).is_empty());
+
+        let plan = plan_query(&spec, &datastore);
+        assert!(matches!(plan.op.op, Op::FFmpegConcat { .. }));
+        let kinds: Vec<&str> = plan
+            .op
+            .deps
+            .iter()
+            .map(|d| match &d.op {
+                Op::FFmpegClip { .. } => "clip",
+                Op::FFmpegFilter {
+                    filter: FilterGraph::Chain(filter),
+                    ..
+                } => {
+                    assert!(filter.contains("color=blue"));
+                    assert!(filter.contains("text='zebra'"));
+                    "filter"
+                }
+                op => panic!("unexpected {:?}", op),
+            })
+            .collect();
+        // boxed GOPs are split to stay under the shard size
+        assert_eq!(kinds.first(), Some(&"clip"));
+        assert_eq!(kinds.last(), Some(&"clip"));
+        assert!(kinds[1..kinds.len() - 1].iter().all(|k| *k == "filter"));
+    }
+
+    #[test]
+    fn plans_box_free_pieces_of_long_gops_as_clips() {
+        let mut datastore = test_datastore();
+        datastore.videos.get_mut("clip").unwrap().gops = (0..24)
+            .map(|i| SourceGopBound {
+                start: Rational64::new(i * 25, 1),
+                end: Rational64::new(i * 25 + 25, 1) - Rational64::new(1, 24),
+                closed: true,
+            })
+            .collect();
+        let spec: Spec = r#"array dets = inline(0, 1, [[box(0.1, 0.1, 0.5, 0.5)]]);
+            Iter=Range(0, 25, 1/24);
+            Render=BoundingBoxes(vid<videos/clip.mp4>[t], dets[t], "blue");
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+        assert!(spec.validate(&datastore).is_empty());
+
+        // one 600 frame GOP, with boxes only in its first second
+        let plan = plan_query(&spec, &datastore);
+        let filtered: Vec<bool> = plan
+            .op
+            .deps
+            .iter()
+            .map(|d| match &d.op {
+                Op::FFmpegFilter {
+                    filter: FilterGraph::Chain(filter),
+                    ..
+                } => {
+                    assert!(!filter.is_empty());
+                    true
+                }
+                Op::FFmpegClip { .. } => false,
+                op => panic!("unexpected {:?}", op),
+            })
+            .collect();
+        assert_eq!(filtered, vec![true, false, false]);
+    }
+
+    #[test]
+    fn lays_out_grids_programmatically() {
+        let datastore = test_datastore();
+        let spec: Spec = r#"Iter=Range(0, 10, 1/24);
+            Render=Grid(vid<videos/clip.mp4>[t], vid<videos/clip.mp4>[(t + 10)],
+                vid<videos/clip.mp4>[(t + 20)], vid<videos/clip.mp4>[(t + 30)],
+                vid<videos/clip.mp4>[(t + 40)], 2, 3, 8, "white", 640, 360);
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+        assert!(spec.validate(&datastore).is_empty());
+
+        let plan = plan_query(&spec, &datastore);
+        let (inputs, grid) = match &plan.op.op {
+            Op::FFmpegFilter {
+                inputs,
+                filter: FilterGraph::Grid(grid),
+                ..
+            } => (inputs, grid),
+            op => panic!("expected a grid filter, got {:?}", op),
+        };
+        assert_eq!(inputs.len(), 5);
+        assert_eq!(
+            (grid.width(), grid.height()),
+            (3 * 640 + 4 * 8, 2 * 360 + 3 * 8)
+        );
+
+        let graph = grid.filter_complex(inputs.len(), false);
+        assert!(graph.starts_with("color=c=white:s=1952x744:r=24/1 [base];"));
+        // the fifth source lands in the middle of the second row, and the last overlay is the
+        // graph's output
+        assert!(graph.ends_with("[tmp3][cell4] overlay=shortest=1:x=656:y=376"));
+        assert!(grid
+            .filter_complex(inputs.len(), true)
+            .contains("fps=12/1, scale=640x360"));
+
+        let too_small: Spec = r#"Iter=Range(0, 10, 1/24);
+            Render=Grid(vid<videos/clip.mp4>[t], vid<videos/clip.mp4>[t], vid<videos/clip.mp4>[t], 1, 2);
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+        assert!(too_small.validate(&datastore).iter().any(|d| matches!(
+            d.kind,
+            DiagnosticKind::GridTooSmall {
+                cells: 2,
+                sources: 3
+            }
+        )));
+    }
+
+    #[test]
+    fn shards_moving_picture_in_picture() {
+        let datastore = test_datastore();
+        let spec: Spec = r#"array track = inline(0, 10, [1/10, 1/2, 9/10]);
+            Iter=Range(0, 30, 1/24);
+            Render=PictureInPicture(vid<videos/clip.mp4>[t], vid<videos/clip.mp4>[(t + 100)],
+                track[t], 1/10, 1/4, 1/4, 1/2, 4, "yellow");
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+        assert!(spec.validate(&datastore).is_empty());
+
+        let plan = plan_query(&spec, &datastore).optimize_heuristic(&datastore);
+        assert!(matches!(plan.op.op, Op::FFmpegConcat { .. }));
+        let graphs: Vec<String> = plan
+            .op
+            .deps
+            .iter()
+            .map(|shard| match &shard.op {
+                Op::FFmpegFilter {
+                    inputs,
+                    filter: FilterGraph::PictureInPicture(pip),
+                    ..
+                } => {
+                    // both sources are read directly by the filter
+                    assert!(inputs.iter().all(|input| input.1.is_some()));
+                    pip.filter_complex(false, &OutputConfig::default())
+                }
+                op => panic!("expected a picture in picture shard, got {:?}", op),
+            })
+            .collect();
+
+        assert!(graphs.len() > 1);
+        assert!(graphs[0].contains("sendcmd=c='10.000000 overlay@pip x 636"));
+        assert!(graphs[0].ends_with("overlay@pip=shortest=1:x=124:y=68"));
+        // the second shard starts after the first move, so it starts from the moved position
+        assert!(graphs[1].ends_with("overlay@pip=shortest=1:x=636:y=68"));
+        assert!(graphs[1].contains("colorchannelmixer=aa=0.500000"));
+    }
+
+    #[test]
+    fn only_reencodes_transition_frames() {
+        let datastore = test_datastore();
+        let spec: Spec = r#"Iter=Range(0, 20, 1/24);
+            Render=match{
+                t in Range(0, 10, 1/24) => vid<videos/clip.mp4>[(t + 120)],
+                transition("fadeblack", 1/2),
+                t in Range(10, 20, 1/24) => vid<videos/clip.mp4>[(t + 300)],
+            };
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+        assert!(spec.validate(&datastore).is_empty());
+        // the transition is spelled out as its own case
+        let reparsed: Spec = spec.to_string().parse().unwrap();
+        assert_eq!(reparsed.flatten_matches().len(), 3);
+
+        let plan = plan_query(&spec, &datastore).optimize_heuristic(&datastore);
+        let filters: Vec<&FilterGraph> = plan
+            .op
+            .deps
+            .iter()
+            .filter_map(|dep| match &dep.op {
+                Op::FFmpegFilter { filter, .. } => Some(filter),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(filters.len(), 1);
+        match filters[0] {
+            FilterGraph::Transition(transition) => {
+                assert_eq!(transition.duration, Rational64::new(1, 2));
+                assert!(transition
+                    .filter_complex(false, &OutputConfig::default())
+                    .ends_with("xfade=transition=fadeblack:duration=0.500000:offset=0"));
+            }
+            filter => panic!("expected a transition, got {:?}", filter),
         }
+
+        let err = r#"Iter=Range(0, 20, 1/24);
+            Render=match{
+                t in Range(0, 9, 1/24) => vid<videos/clip.mp4>[t],
+                transition("fade", 1),
+                t in Range(10, 20, 1/24) => vid<videos/clip.mp4>[t],
+            };
+            Output="out.mp4""#
+            .parse::<Spec>()
+            .unwrap_err();
+        assert!(err.message.contains("not adjacent"));
     }
 
-    // we assume the frames are in order later
-    let mut frames = v["frames"].as_array().unwrap().clone();
-    frames.sort_by_key(get_pts);
+    #[test]
+    fn smooths_and_shards_crops() {
+        let small = |x: f64| {
+            Some(BBox {
+                x,
+                y: 0.5,
+                w: 0.1,
+                h: 0.05,
+                label: None,
+            })
+        };
+        // widened to 16:9 around the box center and rounded to even pixels
+        let rects = crop_rects(&[small(0.25)], 0, (1920, 1080), 16.0 / 9.0);
+        assert_eq!(rects, vec![(480, 512, 192, 108)]);
+        // a one frame jump is spread over the smoothing window, and missing frames hold
+        let rects = crop_rects(
+            &[small(0.0), small(0.0), small(0.3), None, None],
+            1,
+            (1920, 1080),
+            16.0 / 9.0,
+        );
+        assert_eq!(rects[0].0, 0);
+        assert!(rects[1].0 > 0 && rects[1].0 < rects[2].0);
+        assert_eq!(rects[3], rects[4]);
+
+        let datastore = test_datastore();
+        let spec: Spec = r#"array track = inline(0, 1/24, [[box(0, 0, 0.5, 0.5)]]);
+            Iter=Range(0, 30, 1/24);
+            Render=Crop(vid<videos/clip.mp4>[t], track[(t % 15)], 24);
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+        assert!(spec.validate(&datastore).is_empty());
+
+        let plan = plan_query(&spec, &datastore);
+        let full = match &plan.op.op {
+            Op::FFmpegFilter {
+                filter: FilterGraph::Crop(crop),
+                ..
+            } => crop.clone(),
+            op => panic!("expected a crop, got {:?}", op),
+        };
+        assert_eq!(full.rects.len(), 720);
+        // unoptimized, the crop reads a clip at the source's size
+        match &plan.op.deps[..] {
+            [DOp {
+                op: Op::FFmpegClip { config, .. },
+                ..
+            }] => assert_eq!((config.width, config.height), (3840, 2160)),
+            deps => panic!("expected a single clip, got {:?}", deps),
+        }
+        assert!(full
+            .rects
+            .iter()
+            .all(|&(x, y, w, h)| x + w <= 3840 && y + h <= 2160));
+        assert_eq!(full.rects[0], (0, 0, 1920, 1080));
+
+        // long crops shard, each shard picking up the rectangles where it starts
+        let plan = plan.optimize_heuristic(&datastore);
+        let shards: Vec<&CropLayout> = plan
+            .op
+            .deps
+            .iter()
+            .map(|dep| match &dep.op {
+                Op::FFmpegFilter {
+                    filter: FilterGraph::Crop(crop),
+                    ..
+                } => crop,
+                op => panic!("expected a crop shard, got {:?}", op),
+            })
+            .collect();
+        assert!(shards.len() > 1);
+        assert_eq!(shards[1].rects[0], full.rects[SHARD_FRAMES as usize]);
+    }
 
-    let mut step = Option::None;
-    let mut gop_start: Option<Rational64> = Option::None;
-    let mut last_frame: Option<Rational64> = Option::None;
+    #[test]
+    fn redacts_only_inside_boxes() {
+        let frames = vec![(
+            3,
+            vec![BBox {
+                x: 0.5,
+                y: 0.5,
+                w: 0.25,
+                h: 0.25,
+                label: None,
+            }],
+        )];
+        assert_eq!(
+            redact_filter(&frames, "fill", 20, (1280, 720)),
+            "drawbox=x=640:y=360:w=320:h=180:color=black:t=fill:enable='eq(n\\,3)'"
+        );
+        let blur = redact_filter(&frames, "pixelate", 16, (1280, 720));
+        assert!(blur.contains("[fx] scale=iw/16:ih/16"));
+        assert!(blur.contains("color=white:t=fill:enable='eq(n\\,3)' [alpha]"));
+
+        // GOPs without any boxes are planned as plain clips
+        let datastore = test_datastore();
+        let spec: Spec = r#"array faces = inline(12, 1, [[box(0.1, 0.1, 0.1, 0.1)]]);
+            Iter=Range(0, 36, 1/24);
+            Render=Redact(vid<videos/clip.mp4>[t], faces[t], "blur", 10);
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+        assert!(spec.validate(&datastore).is_empty());
+        let plan = plan_query(&spec, &datastore);
+        let filtered: Vec<bool> = plan
+            .op
+            .deps
+            .iter()
+            .map(|d| matches!(d.op, Op::FFmpegFilter { .. }))
+            .collect();
+        assert_eq!(filtered, vec![false, true, false]);
+
+        // and so are the pieces of long GOPs without any
+        assert_eq!(redact_filter(&[], "pixelate", 16, (1280, 720)), "null");
+        let mut datastore = datastore;
+        datastore.videos.get_mut("clip").unwrap().gops = (0..24)
+            .map(|i| SourceGopBound {
+                start: Rational64::new(i * 25, 1),
+                end: Rational64::new(i * 25 + 25, 1) - Rational64::new(1, 24),
+                closed: true,
+            })
+            .collect();
+        let spec: Spec = r#"array faces = inline(20, 1, [[box(0.1, 0.1, 0.1, 0.1)]]);
+            Iter=Range(0, 25, 1/24);
+            Render=Redact(vid<videos/clip.mp4>[t], faces[t], "blur", 10);
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+        let plan = plan_query(&spec, &datastore);
+        let filtered: Vec<bool> = plan
+            .op
+            .deps
+            .iter()
+            .map(|d| matches!(d.op, Op::FFmpegFilter { .. }))
+            .collect();
+        assert_eq!(filtered, vec![false, true, false]);
+    }
 
-    for frame in &frames {
-        let pts = get_pts(frame);
-        let pts = Rational64::new(pts, tbn);
+    #[test]
+    fn fuses_and_shards_frame_local_adjustments() {
+        let datastore = test_datastore();
+        let spec: Spec = r#"Iter=Range(0, 30, 1/24);
+            Render=Blur(Flip(Grayscale(vid<videos/clip.mp4>[t]), "horizontal"), 5);
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+        assert!(spec.validate(&datastore).is_empty());
+
+        // three filters over one clip become one filter, sharded since every step is frame-local
+        let plan = plan_query(&spec, &datastore).optimize_heuristic(&datastore);
+        assert!(matches!(plan.op.op, Op::FFmpegConcat { .. }));
+        assert!(plan.op.deps.len() > 1);
+        for shard in &plan.op.deps {
+            match &shard.op {
+                Op::FFmpegFilter {
+                    inputs,
+                    filter: FilterGraph::Adjust(adjustments),
+                    ..
+                } => {
+                    assert_eq!(inputs.len(), 1);
+                    assert!(inputs[0].1.is_some());
+                    assert_eq!(
+                        adjustments.iter().map(|a| a.filter()).collect::<Vec<_>>(),
+                        vec!["hue=s=0", "hflip", "gblur=sigma=5"]
+                    );
+                }
+                op => panic!("expected a fused shard, got {:?}", op),
+            }
+        }
 
-        if pts > 0.into() && step.is_none() {
-            // the second frame's time is the step
-            step = Option::Some(pts);
+        // fades depend on where the clip starts, so they are fused but never sharded
+        let spec: Spec = r#"Iter=Range(0, 30, 1/24);
+            Render=Fade(Adjust(vid<videos/clip.mp4>[t], 0.1, 1.2), "out", 28, 2);
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+        let plan = plan_query(&spec, &datastore).optimize_heuristic(&datastore);
+        match &plan.op.op {
+            Op::FFmpegFilter {
+                filter: FilterGraph::Adjust(adjustments),
+                ..
+            } => assert_eq!(
+                adjustments[1].filter(),
+                "fade=t=out:st=28.000000:d=2.000000"
+            ),
+            op => panic!("expected one fused filter, got {:?}", op),
         }
+    }
 
-        if frame["pict_type"].as_str().unwrap() == "I" {
-            debug_assert!(frame["key_frame"].as_i64().unwrap() == 1);
+    const RATES: [(i64, i64); 4] = [(24000, 1001), (25, 1), (30000, 1001), (60, 1)];
 
-            if let Some(prior_gop_start) = gop_start {
-                gop_bounds.push(SourceGopBound {
-                    start: prior_gop_start,
-                    end: last_frame.unwrap(),
-                });
+    #[test]
+    fn cuts_are_frame_exact_at_common_rates() {
+        // what ffmpeg parses a time string back into
+        let parse = |time: String| {
+            let (secs, micros) = time.split_once('.').unwrap();
+            Rational64::new(
+                secs.parse::<i64>().unwrap() * 1_000_000 + micros.parse::<i64>().unwrap(),
+                1_000_000,
+            )
+        };
+        for (num, den) in RATES {
+            let step = Rational64::new(den, num);
+            for first in [0, 1, 7, 1001, 53_947] {
+                for frames in [1, 2, 48, 1001] {
+                    let start = step * first;
+                    let ss = parse(ffmpeg_time(&start));
+                    let t = parse(ffmpeg_duration(&(step * frames), &step));
+                    let wanted: Vec<i64> = (first..first + frames).collect();
+                    let context =
+                        format!("{} frames from frame {} at {}/{}", frames, first, num, den);
+                    // -ss drops frames before it. Whether -t drops frames starting at least t
+                    // after it or ending after t, -frames:v leaves exactly the wanted frames.
+                    let starting: Vec<i64> = (first - 2..first + frames + 2)
+                        .filter(|i| step * *i >= ss && step * *i - ss < t)
+                        .take(frames as usize)
+                        .collect();
+                    assert_eq!(starting, wanted, "{}", context);
+                    let ending: Vec<i64> = (first - 2..first + frames + 2)
+                        .filter(|i| step * *i >= ss && step * (*i + 1) - ss <= t)
+                        .collect();
+                    assert_eq!(ending, wanted, "{}", context);
+                }
             }
+        }
+    }
 
-            gop_start = Option::Some(pts);
-        } else {
-            debug_assert!(
-                frame["pict_type"].as_str().unwrap() == "P"
-                    || frame["pict_type"].as_str().unwrap() == "B"
+    #[test]
+    fn smart_cuts_only_sources_in_the_output_codec() {
+        fn methods(dop: &DOp, out: &mut Vec<(FFmpegClipMethod, Codec)>) {
+            if let Op::FFmpegClip { method, config, .. } = &dop.op {
+                out.push((method.clone(), config.codec));
+            }
+            for dep in &dop.deps {
+                methods(dep, out);
+            }
+        }
+        let spec: Spec = r#"Iter=Range(0, 40, 1/24);
+            Render=vid<videos/clip.mp4>[(t + 5)];
+            Output="out.mkv"{width=3840, height=2160, codec="hevc", container="mkv"}"#
+            .parse()
+            .unwrap();
+
+        let mut datastore = test_datastore();
+        for (codec, copied) in [
+            (Some(Codec::H264), false),
+            (Some(Codec::HEVC), true),
+            (None, false),
+        ] {
+            datastore.videos.get_mut("clip").unwrap().stream.codec = codec;
+            let plan = plan_query(&spec, &datastore).optimize_heuristic(&datastore);
+            let mut found = vec![];
+            methods(&plan.op, &mut found);
+            assert_eq!(
+                found
+                    .iter()
+                    .any(|(method, _)| *method == FFmpegClipMethod::StreamCopy),
+                copied,
+                "{:?}",
+                codec
             );
+            // the head and tail are encoded to match the copied body
+            assert!(found.iter().all(|(_, c)| *c == Codec::HEVC));
         }
-
-        last_frame = Option::Some(pts);
     }
-    gop_bounds.push(SourceGopBound {
-        start: gop_start.unwrap(),
-        end: last_frame.unwrap(),
-    });
 
-    let codec = match v["streams"][0]["codec_name"]
-        .as_str()
-        .unwrap()
-        .to_string()
-        .as_str()
-    {
-        "h264" => Codec::H264,
-        "vp9" => Codec::VP9,
-        "av1" => {
-            println!("Skipping AV1");
-            std::process::exit(0)
+    #[test]
+    fn smart_cuts_only_at_closed_gops() {
+        fn copied(dop: &DOp) -> Vec<Range> {
+            let mut out = vec![];
+            if let Op::FFmpegClip {
+                method: FFmpegClipMethod::StreamCopy,
+                range,
+                ..
+            } = &dop.op
+            {
+                out.push(range.clone());
+            }
+            out.extend(dop.deps.iter().flat_map(copied));
+            out
         }
-        _ => panic!("Unsupported video codec!"),
-    };
+        let spec: Spec = r#"Iter=Range(0, 40, 1/24);
+            Render=vid<videos/clip.mp4>[(t + 5)];
+            Output="out.mkv"{width=3840, height=2160, codec="h264", container="mkv"}"#
+            .parse()
+            .unwrap();
+
+        let mut datastore = test_datastore();
+        let plan = plan_query(&spec, &datastore).optimize_heuristic(&datastore);
+        let body = copied(&plan.op);
+        assert_eq!(body.len(), 1);
+        assert_eq!(body[0].start, Rational64::from_integer(12));
+
+        // open-GOP keyframes, like x264 --open-gop writes, are skipped over
+        let gops = &mut datastore.videos.get_mut("clip").unwrap().gops;
+        gops[1].closed = false;
+        let plan = plan_query(&spec, &datastore).optimize_heuristic(&datastore);
+        let body = copied(&plan.op);
+        assert_eq!(body.len(), 1);
+        assert_eq!(body[0].start, Rational64::from_integer(24));
+        assert_eq!(body[0].end, Rational64::from_integer(36));
+
+        let gops = &mut datastore.videos.get_mut("clip").unwrap().gops;
+        gops[2].closed = false;
+        let plan = plan_query(&spec, &datastore).optimize_heuristic(&datastore);
+        assert!(copied(&plan.op).is_empty());
+    }
 
-    (
-        Range {
-            start: Rational64::new(0, 1),
-            end: last_frame.unwrap(),
-            step: step.unwrap(),
-        },
-        gop_bounds,
-        codec,
-    )
-}
+    #[test]
+    fn seeks_relative_to_the_first_frame() {
+        let spec: Spec = r#"Iter=Range(0, 40, 1/24);
+            Render=vid<videos/clip.mp4>[(t + 5)];
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+
+        let mut datastore = test_datastore();
+        let plan = plan_query(&spec, &datastore);
+        assert!(plan.seek_offsets.is_empty());
+
+        datastore.videos.get_mut("clip").unwrap().timing.start = Rational64::new(1001, 24000);
+        let plan = plan_query(&spec, &datastore).optimize_heuristic(&datastore);
+        let five = Rational64::from_integer(5);
+        assert_eq!(
+            seek_time(&plan.seek_offsets, "videos/clip.mp4", &five),
+            "5.041708"
+        );
+        // intermediate files start where they were cut
+        assert_eq!(
+            seek_time(&plan.seek_offsets, "/scratch/tmp.mp4", &five),
+            "5.000000"
+        );
+    }
 
-pub fn add(left: usize, right: usize) -> usize {
-    left + right
-}
+    #[test]
+    fn reads_vfr_sources_at_a_constant_rate() {
+        let mut datastore = test_datastore();
+        // 15 fps frames that don't line up with the 24 fps grid the source is read on
+        datastore.videos.get_mut("clip").unwrap().frame_times = Some(FrameTimes {
+            start: Rational64::new(1, 100),
+            runs: vec![(Rational64::new(1, 15), 9000)],
+        });
+        let plan = |render: &str| {
+            let spec: Spec = format!(
+                r#"Iter=Range(0, 40, 1/24);
+                Render={render};
+                Output="out.mp4""#
+            )
+            .parse()
+            .unwrap();
+            plan_query(&spec, &datastore).optimize_heuristic(&datastore)
+        };
 
-pub fn cleanup() {
-    let status = std::process::Command::new("bash")
-        .arg("-c")
-        .arg("rm -rf /scratch/*")
-        .status()
-        .expect("rm command failed to start");
-    assert!(status.success());
+        // plain reads convert as they cut, and are never smart cut
+        let clip = plan("vid<videos/clip.mp4>[(t + 5)]");
+        assert!(matches!(
+            clip.op.op,
+            Op::FFmpegClip {
+                method: FFmpegClipMethod::ConstantRate,
+                ..
+            }
+        ));
+        assert!(clip.op.deps.is_empty());
 
-    let status = std::process::Command::new("sync")
-        .status()
-        .expect("sync command failed to start");
-    assert!(status.success());
-}
+        // retimes read a constant rate copy of just the frames they need
+        let retime = plan("vid<videos/clip.mp4>[((t * -1) + 120)]");
+        let Op::FFmpegRetime { input, .. } = &retime.op.op else {
+            panic!("expected a retime, got {:?}", retime.op.op);
+        };
+        match &retime.op.deps[..] {
+            [DOp {
+                op:
+                    Op::FFmpegClip {
+                        input: source,
+                        range,
+                        out,
+                        method: FFmpegClipMethod::ConstantRate,
+                        config,
+                    },
+                ..
+            }] => {
+                assert_eq!(source, "videos/clip.mp4");
+                assert_eq!((config.width, config.height), (3840, 2160));
+                assert_eq!(out, input);
+                assert_eq!(range.start, Rational64::new(80 * 24 + 1, 24));
+                assert_eq!(range.end, Rational64::new(120 * 24 + 1, 24));
+                // so the first frame read is at the start of the copy
+                assert_eq!(
+                    seek_time(&retime.seek_offsets, input, &range.start),
+                    "0.000000"
+                );
+            }
+            deps => panic!("expected a conversion, got {:?}", deps),
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        // freezes hold the real frame nearest to the time asked for
+        let freeze = plan("vid<videos/clip.mp4>[((t * 0) + 5)]");
+        let Op::FFmpegFreeze { t, .. } = &freeze.op.op else {
+            panic!("expected a freeze, got {:?}", freeze.op.op);
+        };
+        assert_eq!(*t, Rational64::new(501, 100));
+
+        // crops read a copy in source pixels
+        let spec: Spec = r#"array track = inline(0, 40, [[box(0, 0, 0.5, 0.5)]]);
+            Iter=Range(0, 40, 1/24);
+            Render=Crop(vid<videos/clip.mp4>[t], track[t]);
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+        let crop = plan_query(&spec, &datastore).optimize_heuristic(&datastore);
+        match &crop.op.deps[..] {
+            [DOp {
+                op:
+                    Op::FFmpegClip {
+                        method: FFmpegClipMethod::ConstantRate,
+                        config,
+                        ..
+                    },
+                ..
+            }] => assert_eq!((config.width, config.height), (3840, 2160)),
+            deps => panic!("expected a conversion, got {:?}", deps),
+        }
+    }
 
     #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
+    fn reads_clips_from_cheaper_derived_copies() {
+        fn clips(dop: &DOp, out: &mut Vec<(String, Range)>) {
+            if let Op::FFmpegClip { input, range, .. } = &dop.op {
+                out.push((input.clone(), range.clone()));
+            }
+            for dep in &dop.deps {
+                clips(dep, out);
+            }
+        }
+        let spec: Spec = r#"Iter=Range(0, 40, 1/24);
+            Render=vid<videos/clip.mp4>[(t + 5)];
+            Output="out.mkv"{width=3840, height=2160, codec="h264", container="mkv"}"#
+            .parse()
+            .unwrap();
+
+        let mut datastore = test_datastore();
+        // copies with a keyframe every second, one cut from 2s in and one with boxes drawn on
+        for (name, offset, overlay) in [("short_gops", 2, None), ("boxes", 0, Some("dets"))] {
+            let mut video = test_datastore().videos.remove("clip").unwrap();
+            video.path = format!("videos/{name}.mp4");
+            video.gops = (0..590)
+                .map(|i| SourceGopBound {
+                    start: Rational64::from_integer(i),
+                    end: Rational64::new(i * 24 + 23, 24),
+                    closed: true,
+                })
+                .collect();
+            video.range.end = Rational64::from_integer(590);
+            video.derived_from = Some(Derivation {
+                parent: "clip".to_string(),
+                offset: Rational64::from_integer(offset),
+                overlay: overlay.map(str::to_string),
+            });
+            datastore.videos.insert(name.to_string(), video);
+            datastore
+                .tree_idxs
+                .entry("clip".to_string())
+                .or_default()
+                .insert(name.to_string());
+        }
+
+        let plan = plan_query(&spec, &datastore).optimize_heuristic(&datastore);
+        let mut found = vec![];
+        clips(&plan.op, &mut found);
+        assert!(found
+            .iter()
+            .all(|(input, _)| input == "videos/short_gops.mp4"));
+        // the copy's time 3 is the source's time 5
+        let start = found.iter().map(|(_, r)| r.start).min().unwrap();
+        assert_eq!(start, Rational64::from_integer(3));
+
+        // a copy with something drawn on it never stands in for the source
+        datastore.videos.remove("short_gops");
+        datastore
+            .tree_idxs
+            .get_mut("clip")
+            .unwrap()
+            .remove("short_gops");
+        let plan = plan_query(&spec, &datastore).optimize_heuristic(&datastore);
+        let mut found = vec![];
+        clips(&plan.op, &mut found);
+        assert!(found.iter().all(|(input, _)| input == "videos/clip.mp4"));
+    }
+
+    #[test]
+    fn refuses_to_add_taken_names() {
+        let mut datastore = test_datastore();
+        let source = |name: &str| VideoSource {
+            name: name.to_string(),
+            path: format!("videos/{name}.mp4"),
+            ffprobe_path: format!("videos/{name}.ffprobe.json"),
+        };
+        assert!(matches!(
+            datastore.add_new_video(&source("clip")),
+            Err(DatastoreError::NameTaken(name)) if name == "clip"
+        ));
+        assert!(matches!(
+            datastore.add_derived_video(
+                &source("copy"),
+                Derivation {
+                    parent: "missing".to_string(),
+                    offset: Rational64::from_integer(0),
+                    overlay: None,
+                },
+            ),
+            Err(DatastoreError::NoSuchVideo(name)) if name == "missing"
+        ));
+        assert_eq!(datastore.videos.len(), 1);
+    }
+
+    #[test]
+    fn loads_unversioned_datastores() {
+        // as written before the format was versioned
+        let datastore = Datastore::from_json(serde_json::json!({
+            "videos": {
+                "old": {
+                    "path": "old.mp4",
+                    "ffprobe_path": "old.ffprobe.json",
+                    "range": {"start": [0, 1], "end": [239, 24], "step": [1, 24]},
+                    "gops": [
+                        {"start": [0, 1], "end": [95, 24]},
+                        {"start": [4, 1], "end": [239, 24]},
+                    ],
+                },
+            },
+            "tree_idxs": {"old": [[[0, 1], [4, 1]], [[10, 1]]]},
+        }));
+        assert_eq!(datastore.version, DATASTORE_VERSION);
+        let old = &datastore.videos["old"];
+        assert_eq!(old.stream.codec, Some(Codec::H264));
+        assert_eq!(old.stream.dims, None);
+        assert_eq!(old.range.end, Rational64::from_integer(10));
+        assert_eq!(old.gops.len(), 2);
+        assert!(old.gops.iter().all(|g| !g.closed));
+        assert!(datastore.tree_idxs.is_empty());
+        assert_eq!(datastore.root_of("old").0, "old");
+
+        // and round trips in the current format
+        let saved = serde_json::to_value(&datastore).unwrap();
+        let reloaded = Datastore::from_json(saved);
+        assert_eq!(reloaded.videos["old"].stream, old.stream);
+        assert_eq!(reloaded.videos["old"].range, old.range);
     }
 }
@@ -0,0 +1,966 @@
+//! Reading source video metadata out of ffprobe JSON.
+
+use crate::{read_index, Codec, OutputConfig, Range, SourceGopBound};
+use log::*;
+use num_rational::Rational64;
+use num_traits::Signed;
+use serde::{Deserialize, Serialize};
+
+/// Parameters of a source's video stream. Anything ffprobe didn't report is left unset.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct StreamParams {
+    /// `None` if it's a codec we can't encode, so the video is never stream copied
+    pub codec: Option<Codec>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<i64>,
+    /// Width and height of the coded frames, before rotation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dims: Option<(usize, usize)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pix_fmt: Option<String>,
+    /// Sample (pixel) aspect ratio
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sar: Option<Rational64>,
+    /// Display aspect ratio
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dar: Option<Rational64>,
+    /// Bits per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bit_rate: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_base: Option<Rational64>,
+    /// Clockwise degrees the frames are rotated by when displayed
+    pub rotation: i64,
+}
+
+impl StreamParams {
+    /// Whether packets from this stream can be stream copied into output encoded with `config`
+    /// and concatenated with frames encoded by it. Unknown parameters are assumed to match.
+    /// Encoded frames are scaled to the output size, so the frame size has to match exactly:
+    /// with the default 1280x720 output, 1080p and 4K sources are always fully transcoded.
+    pub fn can_copy_into(&self, config: &OutputConfig) -> bool {
+        self.codec == Some(config.codec)
+            && self
+                .dims
+                .is_none_or(|dims| dims == (config.width, config.height))
+            && self.pix_fmt.as_ref().is_none_or(|f| *f == config.pix_fmt)
+            && self.sar.is_none_or(|sar| sar == Rational64::from_integer(1))
+            // copied packets keep their rotation, encoded ones have it applied
+            && self.rotation == 0
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AudioStream {
+    pub codec: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_layout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bit_rate: Option<u64>,
+}
+
+/// Where a source's frames sit in the file. Source times count from the first presented frame,
+/// which isn't always where ffmpeg's `-ss` counts from.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SourceTiming {
+    /// Seconds from the start of the file, as `-ss` counts them, to the first presented frame.
+    /// Nonzero when the video starts after other streams or the file has a start time.
+    pub start: Rational64,
+    /// The video track's MP4 edit list, which ffmpeg applies before `-ss` sees any timestamps
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub edits: Vec<Edit>,
+}
+
+/// One entry of an MP4 edit list, in seconds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Edit {
+    /// How much of the presentation this edit covers
+    pub duration: Rational64,
+    /// Where in the track's media the edit starts, `None` for an empty edit delaying the track
+    pub media_time: Option<Rational64>,
+}
+
+/// Presentation times of a variable frame rate source's frames, stored as runs of equal gaps
+/// since even VFR footage is mostly evenly spaced between rate changes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FrameTimes {
+    /// Time of the first frame
+    pub start: Rational64,
+    /// `(gap, count)` runs of `count` frames, each `gap` after the one before
+    pub runs: Vec<(Rational64, u32)>,
+}
+
+impl FrameTimes {
+    /// Compress sorted frame times
+    pub fn new(times: &[Rational64]) -> Self {
+        let mut runs: Vec<(Rational64, u32)> = vec![];
+        for pair in times.windows(2) {
+            let gap = pair[1] - pair[0];
+            match runs.last_mut() {
+                Some((last_gap, count)) if *last_gap == gap => *count += 1,
+                _ => runs.push((gap, 1)),
+            }
+        }
+        FrameTimes {
+            start: times[0],
+            runs,
+        }
+    }
+
+    pub fn times(&self) -> impl Iterator<Item = Rational64> + '_ {
+        let gaps = self
+            .runs
+            .iter()
+            .flat_map(|(gap, count)| std::iter::repeat_n(*gap, *count as usize));
+        std::iter::once(self.start).chain(gaps.scan(self.start, |t, gap| {
+            *t += gap;
+            Some(*t)
+        }))
+    }
+
+    /// Time of the real frame closest to `t`, the earlier one on a tie
+    pub fn nearest(&self, t: Rational64) -> Rational64 {
+        self.times()
+            .min_by_key(|frame| ((*frame - t).abs(), *frame))
+            .unwrap()
+    }
+}
+
+/// Everything the datastore records about a source
+pub(crate) struct SourceMeta {
+    pub range: Range,
+    pub gops: Vec<SourceGopBound>,
+    pub stream: StreamParams,
+    pub audio: Vec<AudioStream>,
+    pub timing: SourceTiming,
+    pub frame_times: Option<FrameTimes>,
+}
+
+pub(crate) fn load_meta(meta_path: &str) -> SourceMeta {
+    let x = std::fs::read_to_string(meta_path).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&x).unwrap();
+    parse_meta(&v)
+}
+
+fn ffprobe(args: &[&str], video_path: &str) -> serde_json::Value {
+    let mut cmd = std::process::Command::new("ffprobe");
+    cmd.arg("-v").arg("error");
+    cmd.arg("-print_format").arg("json");
+    cmd.args(args);
+    cmd.arg(video_path);
+
+    info!("{cmd:?}",);
+    let output = cmd.output().expect("failed to execute ffprobe");
+    assert!(
+        output.status.success(),
+        "ffprobe failed on {}: {}",
+        video_path,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+/// Probe `video_path` and write what `load_meta` needs to `probe_path`. Keyframes come from the
+/// container's own index when `read_index` understands it, and from ffprobe's packet flags
+/// otherwise, so nothing is decoded.
+pub fn profile_video(video_path: &str, probe_path: &std::path::Path) {
+    let streams = ffprobe(&["-show_streams"], video_path);
+    let video = streams["streams"]
+        .as_array()
+        .and_then(|s| s.iter().find(|s| s["codec_type"] == "video"))
+        .unwrap_or_else(|| panic!("{} has no video stream", video_path));
+    let time_base = ratio(&video["time_base"], '/').unwrap();
+
+    let (packets, edits) = match read_index(std::path::Path::new(video_path)) {
+        Ok(index) => (index.packets_json(time_base), index.edits),
+        Err(err) => {
+            info!("{}, reading packets with ffprobe instead", err);
+            let packets = ffprobe(
+                &[
+                    "-select_streams",
+                    "v:0",
+                    "-show_entries",
+                    "packet=pts,flags",
+                ],
+                video_path,
+            )["packets"]
+                .clone();
+            (packets, vec![])
+        }
+    };
+    // ffprobe doesn't show edit lists, so they're kept alongside its output
+    let probe = serde_json::json!({
+        "streams": streams["streams"],
+        "packets": packets,
+        "edits": edits,
+        "file": probed_file(video_path),
+    });
+    std::fs::write(probe_path, serde_json::to_string(&probe).unwrap()).unwrap();
+}
+
+/// The path, size and modification time of a video, recorded in its probe
+fn probed_file(video_path: &str) -> Option<serde_json::Value> {
+    let metadata = std::fs::metadata(video_path).ok()?;
+    let modified = metadata
+        .modified()
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?;
+    Some(serde_json::json!({
+        "path": video_path,
+        "bytes": metadata.len(),
+        "modified": [modified.as_secs(), modified.subsec_nanos()],
+    }))
+}
+
+/// Whether `profile_video` wrote the probe at `probe_path` from `video_path` as it is now
+pub fn probe_is_current(probe_path: &std::path::Path, video_path: &str) -> bool {
+    let Ok(text) = std::fs::read_to_string(probe_path) else {
+        return false;
+    };
+    let Ok(probe) = serde_json::from_str::<serde_json::Value>(&text) else {
+        return false;
+    };
+    probed_file(video_path).is_some_and(|file| probe["file"] == file)
+}
+
+/// Frame duration from an ffprobe rate like `"30000/1001"`, if the stream has one
+fn rate_step(rate: &str) -> Option<Rational64> {
+    let (numer, denom) = rate.split_once('/')?;
+    let (numer, denom) = (numer.parse::<i64>().ok()?, denom.parse::<i64>().ok()?);
+    if numer <= 0 || denom <= 0 {
+        return None;
+    }
+    Some(Rational64::new(denom, numer))
+}
+
+/// A ratio like `"16:9"` or `"1/12288"`, `None` when either side is zero (ffprobe's unknown)
+fn ratio(value: &serde_json::Value, sep: char) -> Option<Rational64> {
+    let (numer, denom) = value.as_str()?.split_once(sep)?;
+    let (numer, denom) = (numer.parse::<i64>().ok()?, denom.parse::<i64>().ok()?);
+    if numer == 0 || denom == 0 {
+        return None;
+    }
+    Some(Rational64::new(numer, denom))
+}
+
+/// ffprobe writes most numbers as strings
+fn number<T: std::str::FromStr>(value: &serde_json::Value) -> Option<T> {
+    match value {
+        serde_json::Value::String(s) => s.parse().ok(),
+        serde_json::Value::Number(n) => n.to_string().parse().ok(),
+        _ => None,
+    }
+}
+
+fn rotation(stream: &serde_json::Value) -> i64 {
+    // the display matrix rotates counterclockwise, the older rotate tag clockwise
+    let degrees = stream["side_data_list"]
+        .as_array()
+        .and_then(|list| list.iter().find_map(|d| d["rotation"].as_i64()))
+        .map(|r| -r)
+        .or_else(|| number(&stream["tags"]["rotate"]))
+        .unwrap_or(0);
+    degrees.rem_euclid(360)
+}
+
+fn stream_params(stream: &serde_json::Value) -> StreamParams {
+    let codec_name = stream["codec_name"].as_str().unwrap();
+    let codec = Codec::from_name(codec_name);
+    if codec.is_none() {
+        warn!(
+            "Unsupported video codec {}, clips from it will always be transcoded",
+            codec_name
+        );
+    }
+
+    StreamParams {
+        codec,
+        profile: stream["profile"].as_str().map(str::to_string),
+        // ffprobe reports -99 when there's no level
+        level: stream["level"].as_i64().filter(|l| *l >= 0),
+        dims: match (stream["width"].as_u64(), stream["height"].as_u64()) {
+            (Some(w), Some(h)) => Some((w as usize, h as usize)),
+            _ => None,
+        },
+        pix_fmt: stream["pix_fmt"].as_str().map(str::to_string),
+        sar: ratio(&stream["sample_aspect_ratio"], ':'),
+        dar: ratio(&stream["display_aspect_ratio"], ':'),
+        bit_rate: number(&stream["bit_rate"]),
+        time_base: ratio(&stream["time_base"], '/'),
+        rotation: rotation(stream),
+    }
+}
+
+fn audio_stream(stream: &serde_json::Value) -> AudioStream {
+    AudioStream {
+        codec: stream["codec_name"]
+            .as_str()
+            .unwrap_or("unknown")
+            .to_string(),
+        sample_rate: number(&stream["sample_rate"]),
+        channels: number(&stream["channels"]),
+        channel_layout: stream["channel_layout"].as_str().map(str::to_string),
+        bit_rate: number(&stream["bit_rate"]),
+    }
+}
+
+pub(crate) fn parse_meta(v: &serde_json::Value) -> SourceMeta {
+    let streams = v["streams"].as_array().unwrap();
+    let codec_type = |s: &serde_json::Value| s["codec_type"].as_str().map(str::to_string);
+    // probes made with `-select_streams v` may not say what type their one stream is
+    let video = streams
+        .iter()
+        .find(|s| codec_type(s).as_deref() == Some("video"))
+        .unwrap_or(&streams[0]);
+    let audio = streams
+        .iter()
+        .filter(|s| codec_type(s).as_deref() == Some("audio"))
+        .map(audio_stream)
+        .collect();
+
+    let time_base = ratio(&video["time_base"], '/').unwrap();
+
+    fn get_pts(frame: &serde_json::Value) -> i64 {
+        let pts1 = frame["pkt_pts"].as_i64();
+        let pts2 = frame["pts"].as_i64();
+
+        // do some sanity checks
+        if let Some(pts1) = pts1 {
+            if let Some(pts2) = pts2 {
+                assert_eq!(pts1, pts2);
+            }
+        }
+
+        if let Some(pts1) = pts1 {
+            pts1
+        } else {
+            pts2.unwrap()
+        }
+    }
+
+    // Frame-level probes come from decoding every frame, packet-level ones (see `profile_video`)
+    // only read the container, and flag keyframes instead of giving picture types.
+    let entries = match v["frames"].as_array() {
+        Some(frames) => frames,
+        None => v["packets"].as_array().unwrap(),
+    };
+    let mut entries: Vec<&serde_json::Value> = entries
+        .iter()
+        .filter(|f| f["media_type"].as_str().is_none_or(|t| t == "video"))
+        .filter(|f| f["codec_type"].as_str().is_none_or(|t| t == "video"))
+        // packets ffprobe can't time can't be cut at either
+        .filter(|f| f["pts"].is_i64() || f["pkt_pts"].is_i64())
+        .collect();
+    // frames are listed in presentation order, but finding open GOPs needs decode order
+    if entries.iter().all(|f| f["pkt_dts"].is_i64()) {
+        entries.sort_by_key(|f| f["pkt_dts"].as_i64());
+    }
+    let frames: Vec<(Rational64, bool)> = entries
+        .into_iter()
+        .map(|frame| {
+            // non-IDR I-frames (e.g. at x264 scenecuts) can't be decoded from on their own
+            let keyframe = match (frame["key_frame"].as_i64(), frame["pict_type"].as_str()) {
+                (Some(key_frame), _) => key_frame == 1,
+                (None, Some(pict_type)) => {
+                    debug_assert!(matches!(pict_type, "I" | "P" | "B"));
+                    pict_type == "I"
+                }
+                (None, None) => frame["flags"].as_str().unwrap().starts_with('K'),
+            };
+            (time_base * get_pts(frame), keyframe)
+        })
+        .collect();
+
+    // The nominal rate is exact even when the timebase can't represent frame times exactly
+    // (e.g. 30000/1001 in a 1/1000 timebase), otherwise the second frame's time is the step
+    let step = video["r_frame_rate"]
+        .as_str()
+        .and_then(rate_step)
+        .unwrap_or_else(|| {
+            let mut times: Vec<Rational64> = frames.iter().map(|f| f.0).collect();
+            times.sort();
+            times.into_iter().find(|pts| *pts > 0.into()).unwrap()
+        });
+    let edits: Vec<Edit> = serde_json::from_value(v["edits"].clone()).unwrap_or_default();
+    // `-ss` counts from the earliest stream, which can be audio starting before the video
+    let stream_start =
+        |s: &serde_json::Value| Some(ratio(&s["time_base"], '/')? * s["start_pts"].as_i64()?);
+    // an edit list says exactly where the video starts, otherwise ffprobe has worked it out
+    let origin = if edits.iter().any(|e| e.media_time.is_some()) {
+        first_presented(&frames, &edits)
+    } else {
+        stream_start(video).unwrap_or_else(|| first_presented(&frames, &edits))
+    };
+    let file_start = streams
+        .iter()
+        .filter_map(stream_start)
+        .fold(origin, Rational64::min);
+    let end = presented_end(&edits);
+    let frame_times = vfr_frame_times(&frames, step, origin, end);
+    if frame_times.is_some() {
+        info!(
+            "Variable frame rate video, it'll be read at {} fps",
+            step.recip()
+        );
+    }
+    let (range, gops) = frame_gops(frames, step, origin, end);
+
+    SourceMeta {
+        range,
+        gops,
+        stream: stream_params(video),
+        audio,
+        timing: SourceTiming {
+            start: origin - file_start,
+            edits,
+        },
+        frame_times,
+    }
+}
+
+/// The presented frames' times relative to `origin`, if they don't all sit on the `step` grid.
+/// Timebases that can't hold the step exactly round frame times by far less than a quarter step,
+/// while rate changes, dropped frames and duplicates move whole frames off it.
+pub(crate) fn vfr_frame_times(
+    frames: &[(Rational64, bool)],
+    step: Rational64,
+    origin: Rational64,
+    end: Option<Rational64>,
+) -> Option<FrameTimes> {
+    let zero = Rational64::from_integer(0);
+    let mut times: Vec<Rational64> = frames
+        .iter()
+        .map(|f| f.0 - origin)
+        .filter(|t| *t >= zero && end.is_none_or(|end| *t < end - origin))
+        .collect();
+    times.sort();
+    let on_grid = times
+        .iter()
+        .enumerate()
+        .all(|(i, t)| (*t - step * i as i64).abs() <= step / 4);
+    if on_grid || times.is_empty() {
+        None
+    } else {
+        Some(FrameTimes::new(&times))
+    }
+}
+
+/// Time of the first frame that's shown. An edit list says so directly, otherwise it's the
+/// first frame at or after 0, since the frames an edit list cuts from the start of a track are
+/// given negative times but are still needed to decode the first GOP.
+pub(crate) fn first_presented(frames: &[(Rational64, bool)], edits: &[Edit]) -> Rational64 {
+    if let Some(first_edit) = edits.iter().position(|e| e.media_time.is_some()) {
+        return edits[..first_edit].iter().map(|e| e.duration).sum();
+    }
+    let zero = Rational64::from_integer(0);
+    let times = || frames.iter().map(|f| f.0);
+    times()
+        .filter(|t| *t >= zero)
+        .min()
+        .or_else(|| times().min())
+        .unwrap()
+}
+
+/// Where an edit list stops showing the track, as a time on the same clock as the frames
+pub(crate) fn presented_end(edits: &[Edit]) -> Option<Rational64> {
+    if edits.is_empty() {
+        return None;
+    }
+    Some(edits.iter().map(|e| e.duration).sum())
+}
+
+/// Split presentation times (in seconds) into GOPs at each keyframe. Frames must be given in
+/// decode order so GOPs whose keyframe is followed by leading frames can be marked open.
+/// Times are made relative to `origin`, the first presented frame, and frames from `end` on
+/// aren't shown so are left out.
+pub(crate) fn frame_gops(
+    frames: Vec<(Rational64, bool)>,
+    step: Rational64,
+    origin: Rational64,
+    end: Option<Rational64>,
+) -> (Range, Vec<SourceGopBound>) {
+    // snap every timestamp onto the frame grid so cut points are exact multiples of the step
+    let snap = |pts: Rational64| ((pts - origin) / step).round() * step;
+    let end = end.map(snap);
+
+    // A keyframe is only a safe place to start decoding if nothing decoded after it is shown
+    // before it. Open-GOP I-frames (and CRAs with RASL pictures) are followed by B-frames that
+    // reference the previous GOP.
+    let mut frames: Vec<(Rational64, bool, bool)> = frames
+        .into_iter()
+        .map(|(pts, keyframe)| (snap(pts), keyframe, true))
+        .filter(|(pts, _, _)| end.is_none_or(|end| *pts < end))
+        .collect();
+    let mut first_later: Option<Rational64> = None;
+    for frame in frames.iter_mut().rev() {
+        frame.2 = first_later.is_none_or(|t| t > frame.0);
+        first_later = Some(first_later.map_or(frame.0, |t| t.min(frame.0)));
+    }
+
+    // we assume the frames are in order later
+    frames.sort();
+
+    let mut gop_bounds: Vec<SourceGopBound> = vec![];
+    let mut gop_start: Option<(Rational64, bool)> = Option::None;
+    let mut last_frame: Option<Rational64> = Option::None;
+
+    for (pts, keyframe, closed) in frames {
+        if keyframe {
+            if let Some((prior_gop_start, prior_closed)) = gop_start {
+                gop_bounds.push(SourceGopBound {
+                    start: prior_gop_start,
+                    end: last_frame.unwrap(),
+                    closed: prior_closed,
+                });
+            }
+
+            gop_start = Option::Some((pts, closed));
+        }
+
+        last_frame = Option::Some(pts);
+    }
+    let (start, closed) = gop_start.unwrap();
+    gop_bounds.push(SourceGopBound {
+        start,
+        end: last_frame.unwrap(),
+        closed,
+    });
+
+    (
+        Range {
+            start: Rational64::new(0, 1),
+            end: last_frame.unwrap() + step,
+            step,
+        },
+        gop_bounds,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATES: [(i64, i64); 4] = [(24000, 1001), (25, 1), (30000, 1001), (60, 1)];
+
+    #[test]
+    fn copies_only_into_matching_outputs() {
+        let stream = StreamParams {
+            codec: Some(Codec::H264),
+            dims: Some((1920, 1080)),
+            pix_fmt: Some("yuv420p".to_string()),
+            ..Default::default()
+        };
+        let output = |width, height, codec, pix_fmt: &str| OutputConfig {
+            width,
+            height,
+            codec,
+            pix_fmt: pix_fmt.to_string(),
+            ..Default::default()
+        };
+
+        assert!(stream.can_copy_into(&output(1920, 1080, Codec::H264, "yuv420p")));
+        // the default output is 720p, so this source is transcoded
+        assert!(!stream.can_copy_into(&OutputConfig::default()));
+        assert!(!stream.can_copy_into(&output(1920, 1080, Codec::HEVC, "yuv420p")));
+        assert!(!stream.can_copy_into(&output(1920, 1080, Codec::H264, "yuv444p")));
+
+        let rotated = StreamParams {
+            rotation: 90,
+            ..stream.clone()
+        };
+        assert!(!rotated.can_copy_into(&output(1920, 1080, Codec::H264, "yuv420p")));
+        let no_dims = StreamParams {
+            dims: None,
+            ..stream.clone()
+        };
+        assert!(no_dims.can_copy_into(&OutputConfig::default()));
+        let unknown_codec = StreamParams {
+            codec: None,
+            ..stream
+        };
+        assert!(!unknown_codec.can_copy_into(&output(1920, 1080, Codec::H264, "yuv420p")));
+    }
+
+    #[test]
+    fn only_reuses_probes_of_the_same_file() {
+        let dir = std::env::temp_dir().join(format!("v2v-probe-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let video = dir.join("a.mp4").to_string_lossy().to_string();
+        let other = dir.join("b.mp4").to_string_lossy().to_string();
+        let probe = dir.join("a.ffprobe.json");
+        std::fs::write(&video, "frames").unwrap();
+        std::fs::write(&other, "frames").unwrap();
+
+        // probes written before files were recorded are never reused
+        std::fs::write(&probe, r#"{"streams": []}"#).unwrap();
+        assert!(!probe_is_current(&probe, &video));
+
+        let json = serde_json::json!({"streams": [], "file": probed_file(&video)});
+        std::fs::write(&probe, json.to_string()).unwrap();
+        assert!(probe_is_current(&probe, &video));
+        assert!(!probe_is_current(&probe, &other));
+        std::fs::write(&video, "other frames").unwrap();
+        assert!(!probe_is_current(&probe, &video));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn snaps_timestamps_to_the_frame_grid() {
+        for (num, den) in RATES {
+            let step = Rational64::new(den, num);
+            // a millisecond timebase can't hold NTSC frame times exactly
+            let frames: Vec<serde_json::Value> = (0..90)
+                .map(|i| {
+                    let pts = (step * i * 1000).round().to_integer();
+                    serde_json::json!({
+                        "pts": pts,
+                        "key_frame": if i % 30 == 0 { 1 } else { 0 },
+                        "pict_type": if i % 30 == 0 { "I" } else { "P" },
+                    })
+                })
+                .collect();
+            let meta = serde_json::json!({
+                "streams": [{
+                    "codec_name": "h264",
+                    "time_base": "1/1000",
+                    "r_frame_rate": format!("{}/{}", num, den),
+                    "width": 1920,
+                    "height": 1080,
+                }],
+                "frames": frames,
+            });
+
+            let meta = parse_meta(&meta);
+            assert_eq!(meta.range.step, step);
+            assert_eq!(meta.range.end, step * 90);
+            let starts: Vec<Rational64> = meta.gops.iter().map(|g| g.start).collect();
+            assert_eq!(
+                starts,
+                vec![Rational64::from_integer(0), step * 30, step * 60]
+            );
+            assert_eq!(meta.gops[0].end, step * 29);
+            assert_eq!(meta.frame_times, None);
+        }
+    }
+
+    #[test]
+    fn reads_keyframes_from_packet_flags() {
+        // packets come in decode order, with B-frames after the frame they reference
+        let packets: Vec<serde_json::Value> = [(0, "K__"), (3, "___"), (1, "___"), (2, "___")]
+            .into_iter()
+            .chain([(4, "K__"), (6, "___"), (5, "___"), (7, "___")])
+            .map(|(pts, flags)| serde_json::json!({"pts": pts * 512, "flags": flags}))
+            .chain([serde_json::json!({"flags": "___"})])
+            .collect();
+        let meta = serde_json::json!({
+            "streams": [{
+                "codec_type": "video",
+                "codec_name": "h264",
+                "time_base": "1/12288",
+                "r_frame_rate": "24/1",
+            }],
+            "packets": packets,
+        });
+
+        let meta = parse_meta(&meta);
+        let step = Rational64::new(1, 24);
+        assert_eq!(meta.range.end, step * 8);
+        let gops: Vec<(Rational64, Rational64)> =
+            meta.gops.iter().map(|g| (g.start, g.end)).collect();
+        assert_eq!(
+            gops,
+            vec![
+                (Rational64::from_integer(0), step * 3),
+                (step * 4, step * 7)
+            ]
+        );
+    }
+
+    #[test]
+    fn marks_open_gops_from_leading_b_frames() {
+        // x264 --open-gop: the non-IDR I-frame at 9 is decoded before the B-frames shown
+        // ahead of it, which reference the GOP before
+        let packets: Vec<serde_json::Value> = [(0, "K__"), (3, "___"), (1, "___"), (2, "___")]
+            .into_iter()
+            .chain([(6, "___"), (4, "___"), (5, "___")])
+            .chain([(9, "K__"), (7, "___"), (8, "___"), (12, "___"), (10, "___")])
+            .chain([(11, "___"), (13, "K__"), (14, "___")])
+            .map(|(pts, flags)| serde_json::json!({"pts": pts * 512, "flags": flags}))
+            .collect();
+        let meta = serde_json::json!({
+            "streams": [{
+                "codec_type": "video",
+                "codec_name": "h264",
+                "time_base": "1/12288",
+                "r_frame_rate": "24/1",
+            }],
+            "packets": packets,
+        });
+
+        let meta = parse_meta(&meta);
+        let step = Rational64::new(1, 24);
+        let gops: Vec<(Rational64, bool)> = meta.gops.iter().map(|g| (g.start, g.closed)).collect();
+        assert_eq!(
+            gops,
+            vec![
+                (Rational64::from_integer(0), true),
+                (step * 9, false),
+                (step * 13, true)
+            ]
+        );
+        assert_eq!(meta.gops[0].end, step * 8);
+    }
+
+    #[test]
+    fn only_splits_gops_at_keyframes() {
+        // frames are listed in presentation order; the I-frame at 4 is a scenecut, not an IDR
+        let frames: Vec<serde_json::Value> = [
+            (0, 0, 1, "I"),
+            (1, 2, 0, "B"),
+            (2, 3, 0, "B"),
+            (3, 1, 0, "P"),
+            (4, 4, 0, "I"),
+            (5, 5, 0, "P"),
+            (6, 7, 0, "B"),
+            (7, 6, 1, "I"),
+            (8, 8, 0, "P"),
+        ]
+        .into_iter()
+        .map(|(pts, dts, key_frame, pict_type)| {
+            serde_json::json!({
+                "pts": pts,
+                "pkt_dts": dts,
+                "key_frame": key_frame,
+                "pict_type": pict_type,
+            })
+        })
+        .collect();
+        let meta = serde_json::json!({
+            "streams": [{"codec_name": "h264", "time_base": "1/24", "r_frame_rate": "24/1"}],
+            "frames": frames,
+        });
+
+        let meta = parse_meta(&meta);
+        let step = Rational64::new(1, 24);
+        let gops: Vec<(Rational64, bool)> = meta.gops.iter().map(|g| (g.start, g.closed)).collect();
+        assert_eq!(
+            gops,
+            vec![(Rational64::from_integer(0), true), (step * 7, false)]
+        );
+    }
+
+    #[test]
+    fn counts_source_time_from_the_first_frame() {
+        // an MPEG-TS style start time, with audio starting a little before the video
+        let step = Rational64::new(1001, 30000);
+        let packets: Vec<serde_json::Value> = (0..30)
+            .map(|i| {
+                serde_json::json!({
+                    "pts": 126_000 + i * 3003,
+                    "flags": if i % 15 == 0 { "K_" } else { "__" },
+                })
+            })
+            .collect();
+        let probe = |edits: Vec<Edit>| {
+            serde_json::json!({
+                "streams": [
+                    {
+                        "codec_type": "video",
+                        "codec_name": "h264",
+                        "time_base": "1/90000",
+                        "r_frame_rate": "30000/1001",
+                        "start_pts": 126_000,
+                    },
+                    {
+                        "codec_type": "audio",
+                        "codec_name": "aac",
+                        "time_base": "1/48000",
+                        "start_pts": 64_800,
+                    },
+                ],
+                "packets": packets,
+                "edits": edits,
+            })
+        };
+
+        let meta = parse_meta(&probe(vec![]));
+        assert_eq!(meta.timing.start, Rational64::new(1, 20));
+        assert_eq!(meta.range.start, Rational64::from_integer(0));
+        assert_eq!(meta.range.end, step * 30);
+        let starts: Vec<Rational64> = meta.gops.iter().map(|g| g.start).collect();
+        assert_eq!(starts, vec![Rational64::from_integer(0), step * 15]);
+
+        // an edit list showing frames 2 to 21 of the track, a second in
+        let edits = vec![
+            Edit {
+                duration: Rational64::from_integer(1),
+                media_time: None,
+            },
+            Edit {
+                duration: step * 20,
+                media_time: Some(step * 2),
+            },
+        ];
+        let shifted: Vec<serde_json::Value> = (0..30)
+            .map(|i| {
+                serde_json::json!({
+                    "pts": 90_000 + (i - 2) * 3003,
+                    "flags": if i % 15 == 0 { "K_" } else { "__" },
+                })
+            })
+            .collect();
+        let mut shifted_probe = probe(edits.clone());
+        shifted_probe["packets"] = shifted.into();
+        shifted_probe["streams"][0]["start_pts"] = 90_000.into();
+        shifted_probe["streams"][1]["start_pts"] = 0.into();
+        let meta = parse_meta(&shifted_probe);
+        assert_eq!(meta.timing.edits, edits);
+        // the empty edit starts the video a second after the audio
+        assert_eq!(meta.timing.start, Rational64::from_integer(1));
+        assert_eq!(meta.range.end, step * 20);
+        // the first GOP starts before the edit, so it has to be decoded from there
+        let starts: Vec<Rational64> = meta.gops.iter().map(|g| g.start).collect();
+        assert_eq!(starts, vec![step * -2, step * 13]);
+    }
+
+    #[test]
+    fn detects_variable_frame_rates() {
+        // a screen recording dropping from 30 to 15 fps once nothing moves
+        let times: Vec<i64> = (0..10)
+            .map(|i| i * 3000)
+            .chain((0..10).map(|i| 30_000 + i * 6000))
+            .collect();
+        let packets: Vec<serde_json::Value> = times
+            .iter()
+            .enumerate()
+            .map(|(i, pts)| serde_json::json!({"pts": pts, "flags": if i == 0 { "K_" } else { "__" }}))
+            .collect();
+        let meta = serde_json::json!({
+            "streams": [{
+                "codec_type": "video",
+                "codec_name": "h264",
+                "time_base": "1/90000",
+                "r_frame_rate": "30/1",
+            }],
+            "packets": packets,
+        });
+
+        let meta = parse_meta(&meta);
+        let frame_times = meta.frame_times.unwrap();
+        assert_eq!(
+            frame_times,
+            FrameTimes {
+                start: Rational64::from_integer(0),
+                runs: vec![(Rational64::new(1, 30), 10), (Rational64::new(1, 15), 9)],
+            }
+        );
+        let expanded: Vec<i64> = frame_times
+            .times()
+            .map(|t| (t * 90_000).to_integer())
+            .collect();
+        assert_eq!(expanded, times);
+        // between the 15 fps frames, the earlier one wins ties
+        assert_eq!(
+            frame_times.nearest(Rational64::new(11, 30)),
+            Rational64::new(1, 3)
+        );
+        assert_eq!(
+            frame_times.nearest(Rational64::new(12, 30)),
+            Rational64::new(2, 5)
+        );
+        assert_eq!(
+            frame_times.nearest(Rational64::from_integer(5)),
+            Rational64::new(14, 15)
+        );
+    }
+
+    #[test]
+    fn detects_codecs_without_exiting() {
+        let meta = |codec: &str| {
+            serde_json::json!({
+                "streams": [{"codec_name": codec, "time_base": "1/24", "r_frame_rate": "24/1"}],
+                "frames": [
+                    {"pts": 0, "key_frame": 1, "pict_type": "I"},
+                    {"pts": 1, "key_frame": 0, "pict_type": "P"},
+                ],
+            })
+        };
+        assert_eq!(parse_meta(&meta("av1")).stream.codec, Some(Codec::AV1));
+        assert_eq!(parse_meta(&meta("hevc")).stream.codec, Some(Codec::HEVC));
+        assert_eq!(parse_meta(&meta("mpeg4")).stream.codec, Some(Codec::MPEG4));
+        assert_eq!(parse_meta(&meta("prores")).stream.codec, None);
+    }
+
+    #[test]
+    fn records_stream_and_audio_params() {
+        let meta = serde_json::json!({
+            "streams": [
+                {
+                    "codec_type": "audio",
+                    "codec_name": "aac",
+                    "sample_rate": "48000",
+                    "channels": 2,
+                    "channel_layout": "stereo",
+                    "bit_rate": "128000",
+                },
+                {
+                    "codec_type": "video",
+                    "codec_name": "hevc",
+                    "profile": "Main 10",
+                    "level": 150,
+                    "width": 3840,
+                    "height": 2160,
+                    "pix_fmt": "yuv420p10le",
+                    "sample_aspect_ratio": "1:1",
+                    "display_aspect_ratio": "16:9",
+                    "bit_rate": "45000000",
+                    "time_base": "1/30000",
+                    "r_frame_rate": "30000/1001",
+                    "side_data_list": [{"side_data_type": "Display Matrix", "rotation": -90}],
+                },
+            ],
+            "frames": [
+                {"media_type": "video", "pts": 0, "key_frame": 1, "pict_type": "I"},
+                {"media_type": "audio", "pts": 0},
+                {"media_type": "video", "pts": 1001, "key_frame": 0, "pict_type": "P"},
+            ],
+        });
+
+        let meta = parse_meta(&meta);
+        assert_eq!(
+            meta.stream,
+            StreamParams {
+                codec: Some(Codec::HEVC),
+                profile: Some("Main 10".to_string()),
+                level: Some(150),
+                dims: Some((3840, 2160)),
+                pix_fmt: Some("yuv420p10le".to_string()),
+                sar: Some(Rational64::from_integer(1)),
+                dar: Some(Rational64::new(16, 9)),
+                bit_rate: Some(45_000_000),
+                time_base: Some(Rational64::new(1, 30000)),
+                rotation: 90,
+            }
+        );
+        assert_eq!(
+            meta.audio,
+            vec![AudioStream {
+                codec: "aac".to_string(),
+                sample_rate: Some(48000),
+                channels: Some(2),
+                channel_layout: Some("stereo".to_string()),
+                bit_rate: Some(128_000),
+            }]
+        );
+        assert_eq!(meta.range.end, Rational64::new(2002, 30000));
+    }
+}
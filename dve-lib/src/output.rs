@@ -0,0 +1,187 @@
+use crate::Codec;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Container {
+    Mp4,
+    Mkv,
+    Webm,
+    Mov,
+}
+
+impl Container {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "mkv",
+            Container::Webm => "webm",
+            Container::Mov => "mov",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Container> {
+        match name {
+            "mp4" => Some(Container::Mp4),
+            "mkv" => Some(Container::Mkv),
+            "webm" => Some(Container::Webm),
+            "mov" => Some(Container::Mov),
+            _ => None,
+        }
+    }
+
+    fn holds(&self, codec: Codec) -> bool {
+        match self {
+            Container::Mkv => true,
+            Container::Mp4 => true,
+            Container::Webm => matches!(codec, Codec::VP9 | Codec::AV1),
+            Container::Mov => matches!(codec, Codec::H264 | Codec::HEVC | Codec::MPEG4),
+        }
+    }
+}
+
+/// How the rendered video is encoded. Every op that encodes frames uses the same settings, so
+/// their outputs can be concatenated without re-encoding.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct OutputConfig {
+    pub width: usize,
+    pub height: usize,
+    pub pix_fmt: String,
+    pub codec: Codec,
+    /// Constant quality, mutually exclusive with `bitrate`. MPEG-4 uses it as its `-q:v` scale.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crf: Option<u32>,
+    /// Target bitrate in ffmpeg syntax, e.g. `"4M"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<String>,
+    /// Encoder preset, the fastest one for each encoder when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    pub container: Container,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            width: 1280,
+            height: 720,
+            pix_fmt: "yuv420p".to_string(),
+            codec: Codec::H264,
+            crf: None,
+            bitrate: None,
+            preset: None,
+            container: Container::Mp4,
+        }
+    }
+}
+
+const PIX_FMTS: &[&str] = &["yuv420p", "yuv422p", "yuv444p", "yuv420p10le", "nv12"];
+
+impl OutputConfig {
+    pub fn is_default(&self) -> bool {
+        *self == OutputConfig::default()
+    }
+
+    /// Problems with this config, given the path the video is written to
+    pub fn problems(&self, output: &str) -> Vec<String> {
+        let mut out = vec![];
+        if self.width == 0 || self.height == 0 || self.width % 2 == 1 || self.height % 2 == 1 {
+            out.push(format!(
+                "{}x{} is not a positive, even resolution",
+                self.width, self.height
+            ));
+        }
+        if !PIX_FMTS.contains(&self.pix_fmt.as_str()) {
+            out.push(format!(
+                "pixel format {} is not one of {}",
+                self.pix_fmt,
+                PIX_FMTS.join(", ")
+            ));
+        }
+        if self.pix_fmt == "yuv420p10le" && matches!(self.codec, Codec::H264 | Codec::MPEG4) {
+            out.push(format!(
+                "10-bit output needs HEVC, VP9 or AV1 rather than {:?}",
+                self.codec
+            ));
+        }
+        if self.crf.is_some() && self.bitrate.is_some() {
+            out.push("crf and bitrate can't both be set".to_string());
+        }
+        let max_crf = match self.codec {
+            Codec::H264 | Codec::HEVC => 51,
+            Codec::VP9 | Codec::AV1 => 63,
+            Codec::MPEG4 => 31,
+        };
+        if self.crf.is_some_and(|crf| crf > max_crf) {
+            out.push(format!("{:?} crf must be at most {}", self.codec, max_crf));
+        }
+        if !self.container.holds(self.codec) {
+            out.push(format!(
+                "{} can't hold {:?}",
+                self.container.name(),
+                self.codec
+            ));
+        }
+        let extension = std::path::Path::new(output)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("");
+        if extension != self.container.name() {
+            out.push(format!(
+                "output {} doesn't end in .{}",
+                output,
+                self.container.name()
+            ));
+        }
+        out
+    }
+
+    pub(crate) fn encoder_args(&self, cmd: &mut std::process::Command) {
+        let preset = self.preset.as_deref();
+        match self.codec {
+            Codec::H264 => {
+                cmd.arg("-c:v").arg("libx264");
+                cmd.arg("-preset").arg(preset.unwrap_or("ultrafast"));
+            }
+            Codec::HEVC => {
+                cmd.arg("-c:v").arg("libx265");
+                cmd.arg("-preset").arg(preset.unwrap_or("ultrafast"));
+            }
+            Codec::VP9 => {
+                cmd.arg("-c:v").arg("libvpx-vp9");
+                cmd.arg("-deadline").arg(preset.unwrap_or("realtime"));
+                if preset.is_none() {
+                    cmd.arg("-speed").arg("8");
+                }
+            }
+            Codec::AV1 => {
+                // SVT-AV1 presets are numbers, higher is faster
+                cmd.arg("-c:v").arg("libsvtav1");
+                cmd.arg("-preset").arg(preset.unwrap_or("12"));
+            }
+            // the native encoder has no presets
+            Codec::MPEG4 => {
+                cmd.arg("-c:v").arg("mpeg4");
+            }
+        }
+        if let Some(crf) = self.crf {
+            match self.codec {
+                Codec::MPEG4 => cmd.arg("-q:v").arg(crf.to_string()),
+                _ => cmd.arg("-crf").arg(crf.to_string()),
+            };
+            if self.codec == Codec::VP9 {
+                // constant quality mode in libvpx
+                cmd.arg("-b:v").arg("0");
+            }
+        }
+        if let Some(bitrate) = &self.bitrate {
+            cmd.arg("-b:v").arg(bitrate);
+        }
+        cmd.arg("-pix_fmt").arg(&self.pix_fmt);
+    }
+
+    /// `scale` filter to the output resolution
+    pub(crate) fn scale(&self) -> String {
+        format!("scale={}:{}", self.width, self.height)
+    }
+}
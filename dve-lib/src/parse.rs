@@ -0,0 +1,783 @@
+//! Parser for the textual spec syntax printed by the `Display` impls in `fmt.rs`.
+//!
+//! A `.v2v` file is an optional list of named sources followed by a spec:
+//!
+//! ```text
+//! # sources can be given short names
+//! source clip = "videos/clip.mp4";
+//!
+//! Iter=Range(0, 10, 1/24);
+//! Render=match{
+//!     t in Range(0, 5, 1/24) => vid<clip>[(t + 60)],
+//!     t in Range(5, 10, 1/24) => Filter(vid<clip>[(t + 120)], "gblur=sigma=30"),
+//! };
+//! Output="/scratch/output.mp4"
+//! ```
+//!
+//! The output can be followed by its encoding settings, e.g.
+//! `Output="out.webm"{codec="vp9", width=1920, height=1080, crf=31, container="webm"}`. Any
+//! setting left out keeps its default.
+//!
+//! A `transition("fade", 1/2),` entry between two adjacent cases blends the first into the
+//! second over the start of the second case, and is parsed into a `Transition` case.
+//!
+//! Specs can also declare time-indexed arrays for `name[t]` lookups, either loaded from a file
+//! (`array dets = csv("dets.csv", 0, 1/24);`) or given inline
+//! (`array captions = inline(0, 5, ["intro", null, "outro"]);`).
+//! Object detector output can be read directly with
+//! `array dets = detections("clip.mp4.json", "yolov5m", 0, 1/24);`.
+
+use crate::{
+    ArrayData, ArrayDecl, ArrayValue, Arrays, BBox, Codec, Container, DataExpr, F2FType, FrameExpr,
+    OutputConfig, Range, SourceType, Spec, TExpr,
+};
+use num_rational::Rational64;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.col, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    sources: BTreeMap<String, String>,
+}
+
+type PResult<T> = Result<T, ParseError>;
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Parser {
+            text,
+            pos: 0,
+            sources: BTreeMap::new(),
+        }
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> PResult<T> {
+        let consumed = &self.text[..self.pos];
+        let line = consumed.matches('\n').count() + 1;
+        let col = consumed.len() - consumed.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+        Err(ParseError {
+            line,
+            col,
+            message: message.into(),
+        })
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_ws(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if trimmed.starts_with('#') {
+                self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_ws();
+        self.rest().chars().next()
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_ws();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> PResult<()> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            self.error(format!("expected `{}`", token))
+        }
+    }
+
+    fn peek_ident(&mut self) -> Option<&'a str> {
+        self.skip_ws();
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        if len == 0 || rest.as_bytes()[0].is_ascii_digit() {
+            None
+        } else {
+            Some(&rest[..len])
+        }
+    }
+
+    fn ident(&mut self) -> PResult<&'a str> {
+        match self.peek_ident() {
+            Some(ident) => {
+                self.pos += ident.len();
+                Ok(ident)
+            }
+            None => self.error("expected an identifier"),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        if self.peek_ident() == Some(keyword) {
+            self.pos += keyword.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn string(&mut self) -> PResult<String> {
+        self.expect("\"")?;
+        let mut out = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(out);
+                }
+                '\\' => match chars.next() {
+                    Some((_, escaped)) => out.push(escaped),
+                    None => break,
+                },
+                c => out.push(c),
+            }
+        }
+        self.pos = self.text.len();
+        self.error("unterminated string")
+    }
+
+    fn integer(&mut self) -> PResult<i64> {
+        self.skip_ws();
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        match rest[..len].parse::<i64>() {
+            Ok(n) => {
+                self.pos += len;
+                Ok(n)
+            }
+            Err(_) => self.error("expected an integer"),
+        }
+    }
+
+    /// Rational literals: `60`, `-3`, `1/24`, or `2.5`
+    fn rational(&mut self) -> PResult<Rational64> {
+        self.skip_ws();
+        let start = self.pos;
+        let negative = self.eat("-");
+        let whole = self.integer()?;
+        let value = if self.rest().starts_with('/') {
+            self.pos += 1;
+            let denom = self.integer()?;
+            if denom == 0 {
+                return self.error("zero denominator");
+            }
+            Rational64::new(whole, denom)
+        } else if self.rest().starts_with('.') {
+            self.pos += 1;
+            let rest = self.rest();
+            let len = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let frac = &rest[..len];
+            self.pos += len;
+            let exact = || {
+                let denom = 10i64.checked_pow(frac.len() as u32)?;
+                let numer = if frac.is_empty() {
+                    0
+                } else {
+                    frac.parse::<i64>().ok()?
+                };
+                Some(Rational64::new(
+                    whole.checked_mul(denom)?.checked_add(numer)?,
+                    denom,
+                ))
+            };
+            match exact() {
+                Some(value) => value,
+                None => {
+                    let literal = &self.text[start..self.pos];
+                    self.pos = start;
+                    return self.error(format!("{literal} has too many digits to be exact"));
+                }
+            }
+        } else {
+            Rational64::new(whole, 1)
+        };
+        Ok(if negative { -value } else { value })
+    }
+
+    fn starts_rational(&mut self) -> bool {
+        matches!(self.peek(), Some(c) if c.is_ascii_digit() || c == '-')
+    }
+
+    fn range(&mut self) -> PResult<Range> {
+        if !self.keyword("Range") {
+            return self.error("expected `Range(start, end, step)`");
+        }
+        self.expect("(")?;
+        let start = self.rational()?;
+        self.expect(",")?;
+        let end = self.rational()?;
+        self.expect(",")?;
+        let step = self.rational()?;
+        self.expect(")")?;
+        Ok(Range { start, end, step })
+    }
+
+    fn texpr(&mut self) -> PResult<TExpr> {
+        let mut lhs = self.texpr_term()?;
+        loop {
+            if self.eat("+") {
+                lhs = TExpr::Add(Box::new(lhs), self.rational()?);
+            } else if self.peek() == Some('-') {
+                self.pos += 1;
+                lhs = TExpr::Sub(Box::new(lhs), self.rational()?);
+            } else {
+                return Ok(lhs);
+            }
+        }
+    }
+
+    fn texpr_term(&mut self) -> PResult<TExpr> {
+        let mut lhs = self.texpr_atom()?;
+        loop {
+            if self.eat("*") {
+                lhs = TExpr::Mul(Box::new(lhs), self.rational()?);
+            } else if self.eat("%") {
+                lhs = TExpr::Mod(Box::new(lhs), self.rational()?);
+            } else {
+                return Ok(lhs);
+            }
+        }
+    }
+
+    fn texpr_atom(&mut self) -> PResult<TExpr> {
+        if self.eat("(") {
+            let inner = self.texpr()?;
+            self.expect(")")?;
+            Ok(inner)
+        } else if self.keyword("t") {
+            Ok(TExpr::T)
+        } else if self.keyword("floor") {
+            self.expect("(")?;
+            let inner = self.texpr()?;
+            self.expect(",")?;
+            let q = self.rational()?;
+            self.expect(")")?;
+            Ok(TExpr::Floor(Box::new(inner), q))
+        } else if self.keyword("remap") {
+            self.expect("(")?;
+            let inner = self.texpr()?;
+            self.expect(",")?;
+            self.expect("[")?;
+            let mut keyframes = vec![];
+            while !self.eat("]") {
+                let from = self.rational()?;
+                self.expect("->")?;
+                let to = self.rational()?;
+                keyframes.push((from, to));
+                if !self.eat(",") {
+                    self.expect("]")?;
+                    break;
+                }
+            }
+            self.expect(")")?;
+            Ok(TExpr::Remap(Box::new(inner), keyframes))
+        } else if self.starts_rational() {
+            Ok(TExpr::Const(self.rational()?))
+        } else {
+            self.error("expected a time expression")
+        }
+    }
+
+    fn source_name(&mut self) -> PResult<String> {
+        let path = if self.peek() == Some('"') {
+            self.string()?
+        } else {
+            self.skip_ws();
+            let rest = self.rest();
+            let len = match rest.find('>') {
+                Some(len) => len,
+                None => return self.error("expected `>` to close the source"),
+            };
+            self.pos += len;
+            rest[..len].trim().to_string()
+        };
+        self.expect(">")?;
+        Ok(self.sources.get(&path).cloned().unwrap_or(path))
+    }
+
+    fn f2f_type(&mut self, name: &str) -> PResult<F2FType> {
+        match name {
+            "Quadrents" => Ok(F2FType::Quadrents),
+            "Filter" => Ok(F2FType::Filter),
+            "BoundingBoxes" => Ok(F2FType::BoundingBoxes),
+            "Grid" => Ok(F2FType::Grid),
+            "PictureInPicture" => Ok(F2FType::PictureInPicture),
+            "Transition" => Ok(F2FType::Transition),
+            "Crop" => Ok(F2FType::Crop),
+            "Redact" => Ok(F2FType::Redact),
+            "Adjust" => Ok(F2FType::Adjust),
+            "Grayscale" => Ok(F2FType::Grayscale),
+            "Sharpen" => Ok(F2FType::Sharpen),
+            "Blur" => Ok(F2FType::Blur),
+            "Fade" => Ok(F2FType::Fade),
+            "Rotate" => Ok(F2FType::Rotate),
+            "Flip" => Ok(F2FType::Flip),
+            _ => self.error(format!("unknown function `{}`", name)),
+        }
+    }
+
+    fn frame_expr(&mut self) -> PResult<FrameExpr> {
+        if self.keyword("match") {
+            self.expect("{")?;
+            let mut cases = vec![];
+            let mut transition = None;
+            while !self.eat("}") {
+                if self.keyword("transition") {
+                    if cases.is_empty() || transition.is_some() {
+                        return self.error("a transition must go between two cases");
+                    }
+                    self.expect("(")?;
+                    let kind = self.string()?;
+                    self.expect(",")?;
+                    let duration = self.rational()?;
+                    self.expect(")")?;
+                    transition = Some((kind, duration));
+                    self.expect(",")?;
+                    continue;
+                }
+                if !self.keyword("t") {
+                    return self.error("expected `t in Range(...) => ...`");
+                }
+                if !self.keyword("in") {
+                    return self.error("expected `in`");
+                }
+                let case_range = self.range()?;
+                self.expect("=>")?;
+                cases.push((case_range, Box::new(self.frame_expr()?)));
+                if let Some((kind, duration)) = transition.take() {
+                    let after = cases.len() - 2;
+                    if let Err(message) =
+                        crate::insert_transition(&mut cases, after, &kind, duration)
+                    {
+                        return self.error(message);
+                    }
+                }
+                if !self.eat(",") {
+                    self.expect("}")?;
+                    break;
+                }
+            }
+            if transition.is_some() {
+                return self.error("a transition must go between two cases");
+            }
+            return Ok(FrameExpr::MatchT(cases));
+        }
+
+        if self.keyword("vid") {
+            self.expect("<")?;
+            let source = self.source_name()?;
+            self.expect("[")?;
+            let t = self.texpr()?;
+            self.expect("]")?;
+            return Ok(FrameExpr::SourceFunction {
+                func: SourceType::ReadFrame,
+                source,
+                t,
+                args: vec![],
+            });
+        }
+
+        let name = self.ident()?;
+        let func = self.f2f_type(name)?;
+        self.expect("(")?;
+        let mut sources = vec![];
+        let mut args = vec![];
+        while !self.eat(")") {
+            if args.is_empty() && self.starts_frame_expr() {
+                sources.push(self.frame_expr()?);
+            } else {
+                args.push(self.data_expr()?);
+            }
+            if !self.eat(",") {
+                self.expect(")")?;
+                break;
+            }
+        }
+        Ok(FrameExpr::F2fFunction {
+            func,
+            sources,
+            args,
+        })
+    }
+
+    fn starts_frame_expr(&mut self) -> bool {
+        match self.peek_ident() {
+            Some("match") | Some("vid") => true,
+            Some(name) => {
+                let after = &self.rest()[name.len()..];
+                self.f2f_type(name).is_ok() && after.trim_start().starts_with('(')
+            }
+            None => false,
+        }
+    }
+
+    fn data_expr(&mut self) -> PResult<DataExpr> {
+        if self.peek() == Some('"') {
+            Ok(DataExpr::ConstStr(self.string()?))
+        } else if self.starts_rational() {
+            Ok(DataExpr::ConstNum(self.rational()?))
+        } else if self.keyword("true") {
+            Ok(DataExpr::ConstBool(true))
+        } else if self.keyword("false") {
+            Ok(DataExpr::ConstBool(false))
+        } else {
+            let name = self.ident()?.to_string();
+            self.expect("[")?;
+            let idx = self.texpr()?;
+            self.expect("]")?;
+            Ok(DataExpr::ArrayIdx(name, Box::new(idx)))
+        }
+    }
+
+    fn source_decl(&mut self) -> PResult<()> {
+        let name = self.ident()?.to_string();
+        self.expect("=")?;
+        let path = self.string()?;
+        self.expect(";")?;
+        if self.sources.insert(name.clone(), path).is_some() {
+            return self.error(format!("source `{}` is declared twice", name));
+        }
+        Ok(())
+    }
+
+    /// Decimal literal for box coordinates, e.g. `0.25` or `1e-3`
+    fn float(&mut self) -> PResult<f64> {
+        self.skip_ws();
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+            .unwrap_or(rest.len());
+        match rest[..len].parse::<f64>() {
+            Ok(n) => {
+                self.pos += len;
+                Ok(n)
+            }
+            Err(_) => self.error("expected a number"),
+        }
+    }
+
+    fn bbox(&mut self) -> PResult<BBox> {
+        if !self.keyword("box") {
+            return self.error("expected `box(x, y, w, h)`");
+        }
+        self.expect("(")?;
+        let x = self.float()?;
+        self.expect(",")?;
+        let y = self.float()?;
+        self.expect(",")?;
+        let w = self.float()?;
+        self.expect(",")?;
+        let h = self.float()?;
+        let label = if self.eat(",") {
+            Some(self.string()?)
+        } else {
+            None
+        };
+        self.expect(")")?;
+        Ok(BBox { x, y, w, h, label })
+    }
+
+    fn array_value(&mut self) -> PResult<ArrayValue> {
+        if self.keyword("null") {
+            Ok(ArrayValue::Null)
+        } else if self.keyword("true") {
+            Ok(ArrayValue::Bool(true))
+        } else if self.keyword("false") {
+            Ok(ArrayValue::Bool(false))
+        } else if self.peek() == Some('"') {
+            Ok(ArrayValue::Str(self.string()?))
+        } else if self.eat("[") {
+            let mut boxes = vec![];
+            while !self.eat("]") {
+                boxes.push(self.bbox()?);
+                if !self.eat(",") {
+                    self.expect("]")?;
+                    break;
+                }
+            }
+            Ok(ArrayValue::Boxes(boxes))
+        } else {
+            Ok(ArrayValue::Num(self.rational()?))
+        }
+    }
+
+    fn array_decl(&mut self) -> PResult<(String, ArrayDecl)> {
+        let name = self.ident()?.to_string();
+        self.expect("=")?;
+        let kind = self.ident()?;
+        self.expect("(")?;
+        let path = match kind {
+            "json" | "csv" | "detections" => {
+                let path = self.string()?;
+                self.expect(",")?;
+                Some(path)
+            }
+            "inline" => None,
+            _ => {
+                return self
+                    .error("expected `json(...)`, `csv(...)`, `detections(...)`, or `inline(...)`")
+            }
+        };
+        let model = if kind == "detections" {
+            let model = self.string()?;
+            self.expect(",")?;
+            Some(model)
+        } else {
+            None
+        };
+        let start = self.rational()?;
+        self.expect(",")?;
+        let step = self.rational()?;
+        let data = match (kind, path) {
+            ("json", Some(path)) => ArrayData::Json(path),
+            ("csv", Some(path)) => ArrayData::Csv(path),
+            ("detections", Some(path)) => ArrayData::Detections {
+                path,
+                model: model.unwrap(),
+            },
+            _ => {
+                self.expect(",")?;
+                self.expect("[")?;
+                let mut values = vec![];
+                while !self.eat("]") {
+                    values.push(self.array_value()?);
+                    if !self.eat(",") {
+                        self.expect("]")?;
+                        break;
+                    }
+                }
+                ArrayData::Inline(values)
+            }
+        };
+        self.expect(")")?;
+        self.expect(";")?;
+        Ok((name, ArrayDecl { start, step, data }))
+    }
+
+    fn spec(&mut self) -> PResult<Spec> {
+        let mut arrays = Arrays::new();
+        loop {
+            if self.keyword("source") {
+                self.source_decl()?;
+            } else if self.keyword("array") {
+                let (name, decl) = self.array_decl()?;
+                if arrays.insert(name.clone(), decl).is_some() {
+                    return self.error(format!("array `{}` is declared twice", name));
+                }
+            } else {
+                break;
+            }
+        }
+
+        if !self.keyword("Iter") {
+            return self.error("expected `Iter=`");
+        }
+        self.expect("=")?;
+        let iter = self.range()?;
+        self.expect(";")?;
+
+        if !self.keyword("Render") {
+            return self.error("expected `Render=`");
+        }
+        self.expect("=")?;
+        let render = self.frame_expr()?;
+        self.expect(";")?;
+
+        if !self.keyword("Output") {
+            return self.error("expected `Output=`");
+        }
+        self.expect("=")?;
+        let output = self.string()?;
+        let output_config = if self.eat("{") {
+            self.output_config()?
+        } else {
+            OutputConfig::default()
+        };
+        self.eat(";");
+
+        self.finish(Spec {
+            iter,
+            render,
+            output,
+            arrays,
+            output_config,
+        })
+    }
+
+    fn output_config(&mut self) -> PResult<OutputConfig> {
+        let mut config = OutputConfig::default();
+        while !self.eat("}") {
+            let key = self.ident()?;
+            self.expect("=")?;
+            match key {
+                "width" => config.width = self.integer()? as usize,
+                "height" => config.height = self.integer()? as usize,
+                "pix_fmt" => config.pix_fmt = self.string()?,
+                "codec" => {
+                    let name = self.string()?;
+                    config.codec = match Codec::from_name(&name) {
+                        Some(codec) => codec,
+                        None => return self.error(format!("unknown codec `{}`", name)),
+                    };
+                }
+                "crf" => config.crf = Some(self.integer()? as u32),
+                "bitrate" => config.bitrate = Some(self.string()?),
+                "preset" => config.preset = Some(self.string()?),
+                "container" => {
+                    let name = self.string()?;
+                    config.container = match Container::from_name(&name) {
+                        Some(container) => container,
+                        None => return self.error(format!("unknown container `{}`", name)),
+                    };
+                }
+                key => return self.error(format!("unknown output setting `{}`", key)),
+            }
+            if !self.eat(",") {
+                self.expect("}")?;
+                break;
+            }
+        }
+        Ok(config)
+    }
+
+    fn finish<T>(&mut self, value: T) -> PResult<T> {
+        self.skip_ws();
+        if self.pos == self.text.len() {
+            Ok(value)
+        } else {
+            self.error("unexpected trailing input")
+        }
+    }
+}
+
+impl std::str::FromStr for Spec {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Parser::new(s).spec()
+    }
+}
+
+impl std::str::FromStr for FrameExpr {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser::new(s);
+        let expr = parser.frame_expr()?;
+        parser.finish(expr)
+    }
+}
+
+impl std::str::FromStr for TExpr {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser::new(s);
+        let expr = parser.texpr()?;
+        parser.finish(expr)
+    }
+}
+
+impl std::str::FromStr for Range {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser::new(s);
+        let range = parser.range()?;
+        parser.finish(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_bundled_specs() {
+        let specs_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/../specs");
+        for entry in std::fs::read_dir(specs_dir).unwrap() {
+            let path = entry.unwrap().path();
+            let text = std::fs::read_to_string(&path).unwrap();
+            let spec: Spec = match path.extension().and_then(|e| e.to_str()) {
+                Some("json") => serde_json::from_str(&text).unwrap(),
+                Some("v2v") => text.parse().unwrap(),
+                _ => continue,
+            };
+            let reparsed: Spec = spec.to_string().parse().unwrap();
+            assert_eq!(
+                serde_json::to_value(&spec).unwrap(),
+                serde_json::to_value(&reparsed).unwrap(),
+                "{:?} did not round trip",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn parses_named_sources_and_literals() {
+        let spec: Spec = r#"
+            source clip = "videos/clip.mp4";
+            Iter=Range(0, 2.5, 1/24);
+            Render=match{
+                t in Range(0, 1, 1/24) => vid<clip>[t * 2 + 60],
+                t in Range(1, 2.5, 1/24) => Filter(vid<other.mp4>[(t - 1)], "drawtext=text='\"hi\"'"),
+            };
+            Output="/scratch/out.mp4"
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(spec.iter.end, Rational64::new(5, 2));
+        assert_eq!(
+            spec.render.to_string(),
+            "match{t in Range(0, 1, 1/24) => vid<videos/clip.mp4>[((t * 2) + 60)], t in Range(1, 5/2, 1/24) => Filter(vid<other.mp4>[(t - 1)], \"drawtext=text='\\\"hi\\\"'\"), }"
+        );
+
+        let err = "Iter=Range(0, 1, 1/24);\nRender=Bogus();".parse::<Spec>();
+        assert_eq!(err.unwrap_err().line, 2);
+
+        let err = "Iter=Range(0, 0.1234567890123456789, 1/24);"
+            .parse::<Spec>()
+            .unwrap_err();
+        assert_eq!((err.line, err.col), (1, 15));
+        assert!(err.message.contains("0.1234567890123456789"));
+    }
+}
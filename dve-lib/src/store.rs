@@ -0,0 +1,589 @@
+//! Keeping datastore entries in step with the files they describe.
+//!
+//! Videos are stamped with their size, modification time and a hash of their contents when
+//! they're added. A file that changed since then has GOPs and timing the datastore no longer
+//! knows about, so it has to be profiled again before anything is smart cut from it.
+
+use crate::cache::Fnv;
+use crate::meta::load_meta;
+use crate::{Datastore, Spec, Video};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io::Read;
+
+/// What a video's file looked like when it was profiled
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileStamp {
+    pub bytes: u64,
+    /// Seconds and nanoseconds since the Unix epoch
+    pub modified: (u64, u32),
+    /// `Fnv` hash of the whole file
+    pub hash: String,
+}
+
+impl FileStamp {
+    pub fn of(path: &str) -> std::io::Result<FileStamp> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(FileStamp {
+            bytes: metadata.len(),
+            modified: modified_time(&metadata),
+            hash: hash_file(path)?,
+        })
+    }
+}
+
+fn modified_time(metadata: &std::fs::Metadata) -> (u64, u32) {
+    let since_epoch = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    (since_epoch.as_secs(), since_epoch.subsec_nanos())
+}
+
+fn hash_file(path: &str) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hash = Fnv::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            return Ok(hash.hex());
+        }
+        hash.update(&buf[..read]);
+    }
+}
+
+/// How a video's file compares to its stamp
+#[derive(Debug, Clone, PartialEq)]
+pub enum VideoStatus {
+    Unchanged,
+    /// Only the modification time changed, like after a copy that didn't keep it
+    Touched,
+    /// What about the file changed
+    Changed(String),
+    Missing,
+    /// Added before files were stamped, so there's nothing to compare against
+    Unstamped,
+}
+
+impl std::fmt::Display for VideoStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VideoStatus::Unchanged => write!(f, "unchanged"),
+            VideoStatus::Touched => write!(f, "touched, contents unchanged"),
+            VideoStatus::Changed(what) => write!(f, "changed: {}", what),
+            VideoStatus::Missing => write!(f, "missing"),
+            VideoStatus::Unstamped => write!(f, "not stamped when added"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DatastoreError {
+    NoSuchVideo(String),
+    NameTaken(String),
+    PathTaken {
+        path: String,
+        name: String,
+    },
+    /// Videos that were made from this one and would be left without a parent
+    HasDerived {
+        name: String,
+        children: Vec<String>,
+    },
+    /// The file at `path` isn't the one that was profiled
+    DifferentFile {
+        path: String,
+        status: VideoStatus,
+    },
+}
+
+impl std::fmt::Display for DatastoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DatastoreError::NoSuchVideo(name) => write!(f, "no video named {}", name),
+            DatastoreError::NameTaken(name) => write!(f, "there's already a video named {}", name),
+            DatastoreError::PathTaken { path, name } => {
+                write!(f, "{} is already in the datastore as {}", path, name)
+            }
+            DatastoreError::HasDerived { name, children } => write!(
+                f,
+                "{} is the parent of {}, remove those first",
+                name,
+                children.join(", ")
+            ),
+            DatastoreError::DifferentFile { path, status } => {
+                write!(f, "{} isn't the file that was profiled ({})", path, status)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DatastoreError {}
+
+/// Lengths of a video's GOPs, in frames
+#[derive(Debug, Clone, PartialEq)]
+pub struct GopStats {
+    pub count: usize,
+    /// GOPs that can't be decoded without the one before, so are never cut at
+    pub open: usize,
+    pub shortest: i64,
+    pub longest: i64,
+    pub mean: f64,
+}
+
+impl Video {
+    pub fn gop_stats(&self) -> Option<GopStats> {
+        let lens: Vec<i64> = self
+            .gops
+            .iter()
+            // GOP ends are the time of their last frame
+            .map(|g| ((g.end - g.start) / self.range.step).round().to_integer() + 1)
+            .collect();
+        Some(GopStats {
+            count: lens.len(),
+            open: self.gops.iter().filter(|g| !g.closed).count(),
+            shortest: *lens.iter().min()?,
+            longest: *lens.iter().max()?,
+            mean: lens.iter().sum::<i64>() as f64 / lens.len() as f64,
+        })
+    }
+}
+
+impl Datastore {
+    /// Compare a video's file with its stamp by size and modification time, without reading it
+    pub fn check_video(&self, name: &str) -> Result<VideoStatus, DatastoreError> {
+        Ok(match self.compare_metadata(name)? {
+            Ok(true) => VideoStatus::Changed("modified time".to_string()),
+            Ok(false) => VideoStatus::Unchanged,
+            Err(status) => status,
+        })
+    }
+
+    /// Compare a video's file with its stamp. The file is only hashed if its size matches but
+    /// its modification time doesn't, unless `contents` is set.
+    pub fn verify_video(&self, name: &str, contents: bool) -> Result<VideoStatus, DatastoreError> {
+        let touched = match self.compare_metadata(name)? {
+            Ok(touched) => touched,
+            Err(status) => return Ok(status),
+        };
+        if !touched && !contents {
+            return Ok(VideoStatus::Unchanged);
+        }
+        let video = &self.videos[name];
+        let stamp = video.file.as_ref().unwrap();
+        Ok(match hash_file(&video.path) {
+            Ok(hash) if hash != stamp.hash => VideoStatus::Changed("contents".to_string()),
+            Ok(_) if touched => VideoStatus::Touched,
+            Ok(_) => VideoStatus::Unchanged,
+            Err(err) => VideoStatus::Changed(format!("can't be read: {}", err)),
+        })
+    }
+
+    /// Whether a video's modification time changed, or how it compares if that's already
+    /// decided by its file being missing, unstamped or a different size
+    fn compare_metadata(&self, name: &str) -> Result<Result<bool, VideoStatus>, DatastoreError> {
+        let video = self.video(name)?;
+        let Ok(metadata) = std::fs::metadata(&video.path) else {
+            return Ok(Err(VideoStatus::Missing));
+        };
+        let Some(stamp) = &video.file else {
+            return Ok(Err(VideoStatus::Unstamped));
+        };
+        if metadata.len() != stamp.bytes {
+            return Ok(Err(VideoStatus::Changed(format!(
+                "size went from {} to {} bytes",
+                stamp.bytes,
+                metadata.len()
+            ))));
+        }
+        Ok(Ok(modified_time(&metadata) != stamp.modified))
+    }
+
+    /// Names of the videos a plan of `spec` may read: its sources, and the copies of them the
+    /// optimizer can read instead
+    pub fn videos_read_by(&self, spec: &Spec) -> BTreeSet<&String> {
+        let mut names = BTreeSet::new();
+        for path in spec.source_paths() {
+            let Some((name, _)) = self.find_video_by_path(&path) else {
+                continue;
+            };
+            let (root, _) = self.root_of(name);
+            let copies = self.tree_idxs.get(&root).into_iter().flatten();
+            names.extend(
+                std::iter::once(&root)
+                    .chain(copies)
+                    .filter_map(|name| self.videos.get_key_value(name).map(|(name, _)| name)),
+            );
+        }
+        names
+    }
+
+    /// Record a video's file as it is now, after `verify_video` found it was only touched
+    pub fn restamp_video(&mut self, name: &str) -> std::io::Result<()> {
+        let video = self
+            .videos
+            .get_mut(name)
+            .expect("restamping a missing video");
+        video.file = Some(FileStamp::of(&video.path)?);
+        Ok(())
+    }
+
+    /// Replace everything known about a video's frames with a new probe of its file, keeping
+    /// its name and what it was derived from
+    pub fn reprofile_video(
+        &mut self,
+        name: &str,
+        ffprobe_path: &str,
+    ) -> Result<(), DatastoreError> {
+        self.video(name)?;
+        let meta = load_meta(ffprobe_path);
+        let video = self.videos.get_mut(name).unwrap();
+        video.ffprobe_path = ffprobe_path.to_string();
+        video.range = meta.range;
+        video.gops = meta.gops;
+        video.stream = meta.stream;
+        video.audio = meta.audio;
+        video.timing = meta.timing;
+        video.frame_times = meta.frame_times;
+        video.file = FileStamp::of(&video.path).ok();
+        Ok(())
+    }
+
+    /// Videos made directly from `name`
+    pub fn derived_videos(&self, name: &str) -> Vec<&String> {
+        self.videos
+            .iter()
+            .filter(|(_, v)| v.derived_from.as_ref().is_some_and(|d| d.parent == name))
+            .map(|(child, _)| child)
+            .collect()
+    }
+
+    pub fn remove_video(&mut self, name: &str) -> Result<Video, DatastoreError> {
+        self.video(name)?;
+        let children = self.derived_videos(name);
+        if !children.is_empty() {
+            return Err(DatastoreError::HasDerived {
+                name: name.to_string(),
+                children: children.into_iter().cloned().collect(),
+            });
+        }
+
+        let (root, _) = self.root_of(name);
+        if root == name {
+            self.tree_idxs.remove(name);
+        } else if let Some(tree) = self.tree_idxs.get_mut(&root) {
+            tree.remove(name);
+        }
+        Ok(self.videos.remove(name).unwrap())
+    }
+
+    pub fn rename_video(&mut self, name: &str, new_name: &str) -> Result<(), DatastoreError> {
+        self.video(name)?;
+        if self.videos.contains_key(new_name) {
+            return Err(DatastoreError::NameTaken(new_name.to_string()));
+        }
+
+        let (root, _) = self.root_of(name);
+        if root == name {
+            if let Some(tree) = self.tree_idxs.remove(name) {
+                self.tree_idxs.insert(new_name.to_string(), tree);
+            }
+        } else if let Some(tree) = self.tree_idxs.get_mut(&root) {
+            tree.remove(name);
+            tree.insert(new_name.to_string());
+        }
+        for video in self.videos.values_mut() {
+            if let Some(derivation) = &mut video.derived_from {
+                if derivation.parent == name {
+                    derivation.parent = new_name.to_string();
+                }
+            }
+        }
+        let video = self.videos.remove(name).unwrap();
+        self.videos.insert(new_name.to_string(), video);
+        Ok(())
+    }
+
+    /// Point a video at its file's new location, checking it's still the file that was profiled
+    pub fn relink_video(&mut self, name: &str, path: &str) -> Result<(), DatastoreError> {
+        self.video(name)?;
+        if let Some((other, _)) = self.find_video_by_path(path) {
+            if other != name {
+                return Err(DatastoreError::PathTaken {
+                    path: path.to_string(),
+                    name: other.clone(),
+                });
+            }
+        }
+
+        let old_path = std::mem::replace(
+            &mut self.videos.get_mut(name).unwrap().path,
+            path.to_string(),
+        );
+        // moving a file usually keeps its modification time, but copying it may not
+        let status = self.verify_video(name, false)?;
+        match status {
+            VideoStatus::Unchanged | VideoStatus::Unstamped => Ok(()),
+            VideoStatus::Touched => {
+                // hashed just now, so it can be read
+                let _ = self.restamp_video(name);
+                Ok(())
+            }
+            VideoStatus::Changed(_) | VideoStatus::Missing => {
+                self.videos.get_mut(name).unwrap().path = old_path;
+                Err(DatastoreError::DifferentFile {
+                    path: path.to_string(),
+                    status,
+                })
+            }
+        }
+    }
+
+    fn video(&self, name: &str) -> Result<&Video, DatastoreError> {
+        self.videos
+            .get(name)
+            .ok_or_else(|| DatastoreError::NoSuchVideo(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Codec, Derivation, Range, SourceGopBound, SourceTiming, StreamParams};
+    use num_rational::Rational64;
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("v2v-store-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn video(path: &Path, parent: Option<&str>) -> Video {
+        let step = Rational64::new(1, 24);
+        Video {
+            path: path.to_string_lossy().to_string(),
+            ffprobe_path: String::new(),
+            range: Range {
+                start: Rational64::from_integer(0),
+                end: Rational64::from_integer(10),
+                step,
+            },
+            gops: vec![
+                SourceGopBound {
+                    start: Rational64::from_integer(0),
+                    end: Rational64::from_integer(4) - step,
+                    closed: true,
+                },
+                SourceGopBound {
+                    start: Rational64::from_integer(4),
+                    end: Rational64::from_integer(10) - step,
+                    closed: false,
+                },
+            ],
+            stream: StreamParams {
+                codec: Some(Codec::H264),
+                ..StreamParams::default()
+            },
+            audio: vec![],
+            timing: SourceTiming::default(),
+            frame_times: None,
+            derived_from: parent.map(|parent| Derivation {
+                parent: parent.to_string(),
+                offset: Rational64::from_integer(0),
+                overlay: None,
+            }),
+            file: FileStamp::of(&path.to_string_lossy()).ok(),
+        }
+    }
+
+    /// "a" with "b" made from it and "c" made from "b"
+    fn datastore(dir: &Path) -> Datastore {
+        let mut videos = BTreeMap::new();
+        for (name, parent) in [("a", None), ("b", Some("a")), ("c", Some("b"))] {
+            let path = dir.join(format!("{name}.mp4"));
+            std::fs::write(&path, name.repeat(100)).unwrap();
+            videos.insert(name.to_string(), video(&path, parent));
+        }
+        Datastore {
+            version: crate::DATASTORE_VERSION,
+            videos,
+            tree_idxs: BTreeMap::from([(
+                "a".to_string(),
+                ["b".to_string(), "c".to_string()].into(),
+            )]),
+        }
+    }
+
+    #[test]
+    fn counts_gop_lengths() {
+        let video = video(Path::new("missing.mp4"), None);
+        assert_eq!(
+            video.gop_stats(),
+            Some(GopStats {
+                count: 2,
+                open: 1,
+                shortest: 96,
+                longest: 144,
+                mean: 120.0,
+            })
+        );
+    }
+
+    #[test]
+    fn detects_changed_files() {
+        let dir = temp_dir();
+        let mut datastore = datastore(&dir);
+        let path = dir.join("a.mp4");
+        let set_modified = |secs: u64| {
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+                .unwrap();
+        };
+        assert_eq!(
+            datastore.verify_video("a", true),
+            Ok(VideoStatus::Unchanged)
+        );
+
+        set_modified(1_000_000);
+        assert_eq!(
+            datastore.check_video("a"),
+            Ok(VideoStatus::Changed("modified time".to_string()))
+        );
+        assert_eq!(datastore.verify_video("a", false), Ok(VideoStatus::Touched));
+        datastore.restamp_video("a").unwrap();
+        assert_eq!(
+            datastore.verify_video("a", false),
+            Ok(VideoStatus::Unchanged)
+        );
+
+        // same size and time, different frames
+        std::fs::write(&path, "b".repeat(100)).unwrap();
+        set_modified(1_000_000);
+        assert_eq!(
+            datastore.verify_video("a", false),
+            Ok(VideoStatus::Unchanged)
+        );
+        assert_eq!(
+            datastore.verify_video("a", true),
+            Ok(VideoStatus::Changed("contents".to_string()))
+        );
+
+        std::fs::write(&path, "a").unwrap();
+        assert_eq!(
+            datastore.verify_video("a", false),
+            Ok(VideoStatus::Changed(
+                "size went from 100 to 1 bytes".to_string()
+            ))
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(datastore.verify_video("a", false), Ok(VideoStatus::Missing));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn renames_and_removes_through_derivations() {
+        let dir = temp_dir();
+        let mut datastore = datastore(&dir);
+        assert_eq!(
+            datastore.remove_video("a").unwrap_err(),
+            DatastoreError::HasDerived {
+                name: "a".to_string(),
+                children: vec!["b".to_string()],
+            }
+        );
+        assert_eq!(
+            datastore.rename_video("a", "b"),
+            Err(DatastoreError::NameTaken("b".to_string()))
+        );
+
+        datastore.rename_video("a", "root").unwrap();
+        datastore.rename_video("b", "middle").unwrap();
+        assert_eq!(
+            datastore.videos["middle"]
+                .derived_from
+                .as_ref()
+                .unwrap()
+                .parent,
+            "root"
+        );
+        assert_eq!(
+            datastore.videos["c"].derived_from.as_ref().unwrap().parent,
+            "middle"
+        );
+        assert_eq!(
+            datastore.tree_idxs,
+            BTreeMap::from([(
+                "root".to_string(),
+                ["c".to_string(), "middle".to_string()].into()
+            )])
+        );
+
+        datastore.remove_video("c").unwrap();
+        datastore.remove_video("middle").unwrap();
+        assert_eq!(datastore.tree_idxs["root"].len(), 0);
+        datastore.remove_video("root").unwrap();
+        assert!(datastore.videos.is_empty());
+        assert!(datastore.tree_idxs.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checks_sources_and_their_copies() {
+        let dir = temp_dir();
+        let mut datastore = datastore(&dir);
+        let other = dir.join("d.mp4");
+        std::fs::write(&other, "d").unwrap();
+        datastore
+            .videos
+            .insert("d".to_string(), video(&other, None));
+
+        let spec: Spec = format!(
+            r#"Iter=Range(0, 1, 1/24);
+            Render=vid<{}>[t];
+            Output="out.mp4""#,
+            dir.join("b.mp4").display()
+        )
+        .parse()
+        .unwrap();
+        let names: Vec<&str> = datastore
+            .videos_read_by(&spec)
+            .into_iter()
+            .map(|name| name.as_str())
+            .collect();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn relinks_only_to_the_same_file() {
+        let dir = temp_dir();
+        let mut datastore = datastore(&dir);
+        let moved = dir.join("moved.mp4");
+        std::fs::copy(dir.join("a.mp4"), &moved).unwrap();
+        let moved = moved.to_string_lossy().to_string();
+
+        assert!(matches!(
+            datastore.relink_video("a", &dir.join("b.mp4").to_string_lossy()),
+            Err(DatastoreError::PathTaken { .. })
+        ));
+        // "c" has different contents of the same size
+        let old_path = datastore.videos["c"].path.clone();
+        assert!(matches!(
+            datastore.relink_video("c", &moved),
+            Err(DatastoreError::DifferentFile { .. })
+        ));
+        assert_eq!(datastore.videos["c"].path, old_path);
+
+        datastore.relink_video("a", &moved).unwrap();
+        assert_eq!(datastore.videos["a"].path, moved);
+        assert_eq!(
+            datastore.verify_video("a", false),
+            Ok(VideoStatus::Unchanged)
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
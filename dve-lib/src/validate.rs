@@ -0,0 +1,838 @@
+use crate::{
+    ArrayData, ArrayValue, Arrays, DataExpr, Datastore, F2FType, FrameExpr, Range, Spec, TExpr,
+};
+use num_rational::Rational64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiagnosticKind {
+    /// The range does not contain a whole number of steps
+    UnalignedRange {
+        range: Range,
+    },
+    /// The range has a non-positive step or ends before it starts
+    EmptyRange {
+        range: Range,
+    },
+    UnknownSource {
+        source: String,
+    },
+    SourceOutOfBounds {
+        source: String,
+        requested: Range,
+        available: Range,
+    },
+    EmptyMatch,
+    CaseStepMismatch {
+        case: Range,
+        expected: Rational64,
+    },
+    CaseOutsideDomain {
+        case: Range,
+        domain: Range,
+    },
+    CaseGap {
+        start: Rational64,
+        end: Rational64,
+    },
+    CaseOverlap {
+        start: Rational64,
+        end: Rational64,
+    },
+    F2fArity {
+        func: F2FType,
+        min: usize,
+        /// `None` when any number of sources past `min` is accepted
+        max: Option<usize>,
+        found: usize,
+    },
+    F2fArgCount {
+        func: F2FType,
+        min: usize,
+        max: usize,
+        found: usize,
+    },
+    /// A `Grid` has fewer cells than sources
+    GridTooSmall {
+        cells: usize,
+        sources: usize,
+    },
+    F2fArgType {
+        func: F2FType,
+        index: usize,
+        expected: &'static str,
+    },
+    BadTimeExpr {
+        expr: TExpr,
+        reason: &'static str,
+    },
+    BadCrop {
+        t: Rational64,
+        reason: &'static str,
+    },
+    /// The datastore has no frame size for a source that needs one
+    UnknownDims {
+        source: String,
+    },
+    UnknownArray {
+        name: String,
+    },
+    /// The array is still file backed; `Spec::load_arrays` hasn't been called
+    ArrayNotLoaded {
+        name: String,
+    },
+    BadOutputConfig {
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Path to the offending expression, e.g. `render.match[2].sources[0]`
+    pub location: String,
+    pub kind: DiagnosticKind,
+}
+
+impl Diagnostic {
+    fn error(location: &str, kind: DiagnosticKind) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            location: location.to_string(),
+            kind,
+        }
+    }
+
+    fn warning(location: &str, kind: DiagnosticKind) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            location: location.to_string(),
+            kind,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+fn is_aligned(range: &Range) -> bool {
+    *range.step.numer() != 0 && *((range.end - range.start) / range.step).denom() == 1
+}
+
+impl Spec {
+    /// Statically check a spec against a datastore before planning.
+    ///
+    /// Returns every problem found; the spec is safe to plan if none of them are errors.
+    pub fn validate(&self, datastore: &Datastore) -> Vec<Diagnostic> {
+        let mut out = vec![];
+
+        if self.iter.step <= Rational64::new(0, 1) || self.iter.end < self.iter.start {
+            out.push(Diagnostic::error(
+                "iter",
+                DiagnosticKind::EmptyRange {
+                    range: self.iter.clone(),
+                },
+            ));
+            return out;
+        }
+        if !is_aligned(&self.iter) {
+            out.push(Diagnostic::error(
+                "iter",
+                DiagnosticKind::UnalignedRange {
+                    range: self.iter.clone(),
+                },
+            ));
+        }
+
+        let ctx = Ctx {
+            datastore,
+            arrays: &self.arrays,
+        };
+        validate_expr(&self.render, &self.iter, "render", &ctx, &mut out);
+        for reason in self.output_config.problems(&self.output) {
+            out.push(Diagnostic::error(
+                "output",
+                DiagnosticKind::BadOutputConfig { reason },
+            ));
+        }
+        out
+    }
+}
+
+struct Ctx<'a> {
+    datastore: &'a Datastore,
+    arrays: &'a Arrays,
+}
+
+fn validate_expr(
+    expr: &FrameExpr,
+    domain: &Range,
+    location: &str,
+    ctx: &Ctx,
+    out: &mut Vec<Diagnostic>,
+) {
+    match expr {
+        FrameExpr::MatchT(cases) => validate_cases(cases, domain, location, ctx, out),
+        FrameExpr::F2fFunction {
+            func,
+            sources,
+            args,
+        } => {
+            validate_f2f(func, sources, args, domain, location, ctx, out);
+            for (i, source) in sources.iter().enumerate() {
+                let source_location = format!("{}.sources[{}]", location, i);
+                validate_expr(source, domain, &source_location, ctx, out);
+            }
+        }
+        FrameExpr::SourceFunction {
+            source, t, args, ..
+        } => {
+            validate_args(args, location, ctx, out);
+            if !validate_texpr(t, location, out) {
+                return;
+            }
+
+            let video = match ctx.datastore.find_video_by_path(source) {
+                Some((_, video)) => video,
+                None => {
+                    out.push(Diagnostic::error(
+                        location,
+                        DiagnosticKind::UnknownSource {
+                            source: source.clone(),
+                        },
+                    ));
+                    return;
+                }
+            };
+
+            let (first, last) = t.bounds(domain);
+            if first < video.range.start || last >= video.range.end {
+                out.push(Diagnostic::error(
+                    location,
+                    DiagnosticKind::SourceOutOfBounds {
+                        source: source.clone(),
+                        requested: t.range(domain),
+                        available: video.range.clone(),
+                    },
+                ));
+            }
+        }
+    }
+}
+
+/// Returns false if `t` can't be evaluated at all
+fn validate_texpr(t: &TExpr, location: &str, out: &mut Vec<Diagnostic>) -> bool {
+    let reason = match t {
+        TExpr::Const(_) | TExpr::T => return true,
+        TExpr::Add(texpr, _) | TExpr::Sub(texpr, _) | TExpr::Mul(texpr, _) => {
+            return validate_texpr(texpr, location, out)
+        }
+        TExpr::Mod(texpr, d) | TExpr::Floor(texpr, d) => {
+            if !validate_texpr(texpr, location, out) {
+                return false;
+            }
+            if *d > Rational64::new(0, 1) {
+                return true;
+            }
+            "divisor must be positive"
+        }
+        TExpr::Remap(texpr, keyframes) => {
+            if !validate_texpr(texpr, location, out) {
+                return false;
+            }
+            if keyframes.is_empty() {
+                "needs at least one keyframe"
+            } else if keyframes.windows(2).any(|w| w[0].0 >= w[1].0) {
+                "keyframes must be strictly increasing"
+            } else {
+                return true;
+            }
+        }
+    };
+
+    out.push(Diagnostic::error(
+        location,
+        DiagnosticKind::BadTimeExpr {
+            expr: t.clone(),
+            reason,
+        },
+    ));
+    false
+}
+
+fn validate_cases(
+    cases: &[(Range, Box<FrameExpr>)],
+    domain: &Range,
+    location: &str,
+    ctx: &Ctx,
+    out: &mut Vec<Diagnostic>,
+) {
+    if cases.is_empty() {
+        out.push(Diagnostic::error(location, DiagnosticKind::EmptyMatch));
+        return;
+    }
+
+    let mut covered: Vec<&Range> = vec![];
+    for (i, (case_range, case_expr)) in cases.iter().enumerate() {
+        let case_location = format!("{}.match[{}]", location, i);
+
+        if case_range.step != domain.step {
+            out.push(Diagnostic::error(
+                &case_location,
+                DiagnosticKind::CaseStepMismatch {
+                    case: case_range.clone(),
+                    expected: domain.step,
+                },
+            ));
+        }
+        if case_range.end < case_range.start {
+            out.push(Diagnostic::error(
+                &case_location,
+                DiagnosticKind::EmptyRange {
+                    range: case_range.clone(),
+                },
+            ));
+            continue;
+        }
+        if !is_aligned(case_range) {
+            out.push(Diagnostic::error(
+                &case_location,
+                DiagnosticKind::UnalignedRange {
+                    range: case_range.clone(),
+                },
+            ));
+        }
+        if case_range.start < domain.start || case_range.end > domain.end {
+            out.push(Diagnostic::error(
+                &case_location,
+                DiagnosticKind::CaseOutsideDomain {
+                    case: case_range.clone(),
+                    domain: domain.clone(),
+                },
+            ));
+        }
+
+        covered.push(case_range);
+        validate_expr(case_expr, case_range, &case_location, ctx, out);
+    }
+
+    // Cases are half-open; they should tile the domain without gaps or overlaps
+    covered.sort_by_key(|r| r.start);
+    let mut cursor = domain.start;
+    for case_range in covered {
+        if case_range.start > cursor {
+            out.push(Diagnostic::warning(
+                location,
+                DiagnosticKind::CaseGap {
+                    start: cursor,
+                    end: case_range.start,
+                },
+            ));
+        } else if case_range.start < cursor {
+            out.push(Diagnostic::error(
+                location,
+                DiagnosticKind::CaseOverlap {
+                    start: case_range.start,
+                    end: std::cmp::min(cursor, case_range.end),
+                },
+            ));
+        }
+        cursor = std::cmp::max(cursor, case_range.end);
+    }
+    if cursor < domain.end {
+        out.push(Diagnostic::warning(
+            location,
+            DiagnosticKind::CaseGap {
+                start: cursor,
+                end: domain.end,
+            },
+        ));
+    }
+}
+
+fn validate_args(args: &[DataExpr], location: &str, ctx: &Ctx, out: &mut Vec<Diagnostic>) {
+    for (i, arg) in args.iter().enumerate() {
+        if let DataExpr::ArrayIdx(name, idx) = arg {
+            let arg_location = format!("{}.args[{}]", location, i);
+            match ctx.arrays.get(name) {
+                None => out.push(Diagnostic::error(
+                    &arg_location,
+                    DiagnosticKind::UnknownArray { name: name.clone() },
+                )),
+                Some(decl) if !decl.is_loaded() => out.push(Diagnostic::error(
+                    &arg_location,
+                    DiagnosticKind::ArrayNotLoaded { name: name.clone() },
+                )),
+                Some(_) => {
+                    validate_texpr(idx, &arg_location, out);
+                }
+            }
+        }
+    }
+}
+
+/// Whether `arg` can only ever produce values accepted by `accepts`
+fn arg_values_match(arg: &DataExpr, ctx: &Ctx, accepts: fn(&ArrayValue) -> bool) -> bool {
+    match arg {
+        DataExpr::ArrayIdx(name, _) => match ctx.arrays.get(name).map(|d| &d.data) {
+            Some(ArrayData::Inline(values)) => values.iter().all(accepts),
+            // missing or unloaded arrays are reported by validate_args
+            _ => true,
+        },
+        arg => accepts(&arg.eval(Rational64::new(0, 1), ctx.arrays)),
+    }
+}
+
+fn validate_f2f(
+    func: &F2FType,
+    sources: &[FrameExpr],
+    args: &[DataExpr],
+    domain: &Range,
+    location: &str,
+    ctx: &Ctx,
+    out: &mut Vec<Diagnostic>,
+) {
+    validate_args(args, location, ctx, out);
+
+    let (expected_sources, required_args, arg_types) = f2f_signature(func);
+
+    let (min_sources, max_sources) = match expected_sources {
+        Some(n) => (n, Some(n)),
+        None => (1, None),
+    };
+    if sources.len() < min_sources || max_sources.is_some_and(|max| sources.len() > max) {
+        out.push(Diagnostic::error(
+            location,
+            DiagnosticKind::F2fArity {
+                func: func.clone(),
+                min: min_sources,
+                max: max_sources,
+                found: sources.len(),
+            },
+        ));
+    }
+
+    if args.len() < required_args || args.len() > arg_types.len() {
+        out.push(Diagnostic::error(
+            &format!("{}.args", location),
+            DiagnosticKind::F2fArgCount {
+                func: func.clone(),
+                min: required_args,
+                max: arg_types.len(),
+                found: args.len(),
+            },
+        ));
+    }
+
+    for (index, (arg, (expected, accepts))) in args.iter().zip(arg_types).enumerate() {
+        if !arg_values_match(arg, ctx, accepts) {
+            out.push(Diagnostic::error(
+                &format!("{}.args[{}]", location, index),
+                DiagnosticKind::F2fArgType {
+                    func: func.clone(),
+                    index,
+                    expected,
+                },
+            ));
+        }
+    }
+
+    // layouts are fixed for the whole clip, so these can't come from an array
+    let constant_args: &[usize] = match func {
+        F2FType::Grid => &[0, 1, 2, 4, 5],
+        F2FType::PictureInPicture => &[2, 3, 4, 5],
+        F2FType::Transition => &[1, 2],
+        F2FType::Crop => &[1],
+        F2FType::Redact => &[2],
+        F2FType::Fade => &[1, 2],
+        _ => &[],
+    };
+    for (index, arg) in args.iter().enumerate() {
+        if constant_args.contains(&index) && !matches!(arg, DataExpr::ConstNum(_)) {
+            out.push(Diagnostic::error(
+                &format!("{}.args[{}]", location, index),
+                DiagnosticKind::F2fArgType {
+                    func: func.clone(),
+                    index,
+                    expected: "a constant",
+                },
+            ));
+        }
+    }
+
+    if let (F2FType::Crop, Some(rects), Some(source)) = (func, args.first(), sources.first()) {
+        validate_crop(rects, source, domain, location, ctx, out);
+    }
+
+    if let F2FType::Grid = func {
+        if let (Some(DataExpr::ConstNum(rows)), Some(DataExpr::ConstNum(cols))) =
+            (args.first(), args.get(1))
+        {
+            let cells = (rows * cols).to_integer() as usize;
+            if cells < sources.len() {
+                out.push(Diagnostic::error(
+                    location,
+                    DiagnosticKind::GridTooSmall {
+                        cells,
+                        sources: sources.len(),
+                    },
+                ));
+            }
+        }
+    }
+}
+
+/// Check every crop rectangle lies inside the frame, reporting only the first bad one
+fn validate_crop(
+    rects: &DataExpr,
+    source: &FrameExpr,
+    domain: &Range,
+    location: &str,
+    ctx: &Ctx,
+    out: &mut Vec<Diagnostic>,
+) {
+    if let DataExpr::ArrayIdx(name, _) = rects {
+        if !ctx.arrays.get(name).is_some_and(|a| a.is_loaded()) {
+            return;
+        }
+    }
+    let dims = crate::source_dims(ctx.datastore, source);
+    if let (None, FrameExpr::SourceFunction { source, .. }) = (dims, source) {
+        if ctx.datastore.find_video_by_path(source).is_some() {
+            out.push(Diagnostic::error(
+                location,
+                DiagnosticKind::UnknownDims {
+                    source: source.clone(),
+                },
+            ));
+        }
+    }
+
+    for t in domain.frame_times() {
+        let rect = match rects.eval(t, ctx.arrays) {
+            ArrayValue::Boxes(boxes) if !boxes.is_empty() => boxes[0].clone(),
+            _ => continue,
+        };
+        let reason = if rect.w <= 0.0 || rect.h <= 0.0 {
+            "is empty"
+        } else if rect.x < 0.0 || rect.y < 0.0 || rect.x + rect.w > 1.0 || rect.y + rect.h > 1.0 {
+            "extends outside the frame"
+        } else if dims.is_some_and(|(w, h)| rect.w * (w as f64) < 2.0 || rect.h * (h as f64) < 2.0)
+        {
+            "is smaller than 2x2 source pixels"
+        } else {
+            continue;
+        };
+        out.push(Diagnostic::error(
+            &format!("{}.args[0]", location),
+            DiagnosticKind::BadCrop { t, reason },
+        ));
+        return;
+    }
+}
+
+type ArgType = (&'static str, fn(&ArrayValue) -> bool);
+
+/// Number of sources (`None` for any), number of required args, and the type of every accepted
+/// arg
+fn f2f_signature(func: &F2FType) -> (Option<usize>, usize, Vec<ArgType>) {
+    let string: ArgType = ("a string", |v| matches!(v, ArrayValue::Str(_)));
+    let bool: ArgType = ("a boolean", |v| matches!(v, ArrayValue::Bool(_)));
+    let positive: ArgType = (
+        "a positive whole number",
+        |v| matches!(v, ArrayValue::Num(n) if n.is_integer() && *n.numer() > 0),
+    );
+    let whole: ArgType = (
+        "a whole number",
+        |v| matches!(v, ArrayValue::Num(n) if n.is_integer() && *n.numer() >= 0),
+    );
+
+    // insets stay where they last were, and adjustments hold their default, over frames where an
+    // array is null
+    let number: ArgType = ("a number or an array of numbers", |v| {
+        matches!(v, ArrayValue::Num(_) | ArrayValue::Null)
+    });
+    let fraction: ArgType = (
+        "a number between 0 and 1",
+        |v| matches!(v, ArrayValue::Num(n) if *n >= Rational64::from_integer(0) && *n <= Rational64::from_integer(1)),
+    );
+
+    match func {
+        F2FType::Quadrents => (Some(4), 0, vec![]),
+        F2FType::Grid => (
+            None,
+            2,
+            vec![positive, positive, whole, string, positive, positive],
+        ),
+        F2FType::PictureInPicture => (
+            Some(2),
+            4,
+            vec![number, number, fraction, fraction, fraction, whole, string],
+        ),
+        F2FType::Transition => (
+            Some(2),
+            3,
+            vec![
+                (
+                    "an xfade transition name",
+                    |v| matches!(v, ArrayValue::Str(s) if crate::TRANSITIONS.contains(&s.as_str())),
+                ),
+                ("a number", |v| matches!(v, ArrayValue::Num(_))),
+                (
+                    "a positive number",
+                    |v| matches!(v, ArrayValue::Num(n) if *n > Rational64::from_integer(0)),
+                ),
+            ],
+        ),
+        F2FType::Adjust => (Some(1), 0, vec![number, number, number]),
+        F2FType::Grayscale => (Some(1), 0, vec![]),
+        F2FType::Sharpen => (Some(1), 0, vec![number]),
+        F2FType::Blur => (Some(1), 1, vec![number]),
+        F2FType::Rotate => (Some(1), 1, vec![number]),
+        F2FType::Flip => (
+            Some(1),
+            1,
+            vec![(
+                "\"horizontal\" or \"vertical\"",
+                |v| matches!(v, ArrayValue::Str(s) if s == "horizontal" || s == "vertical"),
+            )],
+        ),
+        F2FType::Fade => (
+            Some(1),
+            3,
+            vec![
+                (
+                    "\"in\" or \"out\"",
+                    |v| matches!(v, ArrayValue::Str(s) if s == "in" || s == "out"),
+                ),
+                ("a number", |v| matches!(v, ArrayValue::Num(_))),
+                (
+                    "a positive number",
+                    |v| matches!(v, ArrayValue::Num(n) if *n > Rational64::from_integer(0)),
+                ),
+            ],
+        ),
+        F2FType::Redact => (
+            Some(1),
+            1,
+            vec![
+                ("an array of boxes", |v| {
+                    matches!(v, ArrayValue::Boxes(_) | ArrayValue::Null)
+                }),
+                (
+                    "one of \"blur\", \"pixelate\" or \"fill\"",
+                    |v| matches!(v, ArrayValue::Str(s) if ["blur", "pixelate", "fill"].contains(&s.as_str())),
+                ),
+                positive,
+            ],
+        ),
+        F2FType::Crop => (
+            Some(1),
+            1,
+            vec![
+                ("an array of boxes", |v| {
+                    matches!(v, ArrayValue::Boxes(_) | ArrayValue::Null)
+                }),
+                whole,
+            ],
+        ),
+        F2FType::Filter => (
+            Some(1),
+            1,
+            vec![("a string or an array of strings", |v| {
+                matches!(v, ArrayValue::Str(_) | ArrayValue::Null)
+            })],
+        ),
+        F2FType::BoundingBoxes => (
+            Some(1),
+            1,
+            vec![
+                ("an array of boxes", |v| {
+                    matches!(v, ArrayValue::Boxes(_) | ArrayValue::Null)
+                }),
+                string,
+                bool,
+            ],
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Codec, OutputConfig, SourceTiming, SourceType, StreamParams, TExpr, Video};
+    use std::collections::BTreeMap;
+
+    fn r(start: i64, end: i64) -> Range {
+        Range {
+            start: Rational64::new(start, 1),
+            end: Rational64::new(end, 1),
+            step: Rational64::new(1, 24),
+        }
+    }
+
+    fn datastore() -> Datastore {
+        let mut videos = BTreeMap::new();
+        videos.insert(
+            "clip".to_string(),
+            Video {
+                path: "videos/clip.mp4".to_string(),
+                ffprobe_path: "videos/clip.ffprobe.json".to_string(),
+                range: r(0, 600),
+                gops: vec![],
+                stream: StreamParams {
+                    codec: Some(Codec::H264),
+                    dims: Some((1920, 1080)),
+                    ..Default::default()
+                },
+                audio: vec![],
+                timing: SourceTiming::default(),
+                frame_times: None,
+                derived_from: None,
+                file: None,
+            },
+        );
+        Datastore {
+            version: crate::DATASTORE_VERSION,
+            videos,
+            tree_idxs: BTreeMap::new(),
+        }
+    }
+
+    fn read(offset: i64) -> FrameExpr {
+        FrameExpr::SourceFunction {
+            func: SourceType::ReadFrame,
+            source: "videos/clip.mp4".to_string(),
+            t: TExpr::Add(Box::new(TExpr::T), Rational64::new(offset, 1)),
+            args: vec![],
+        }
+    }
+
+    fn kinds(spec: &Spec) -> Vec<DiagnosticKind> {
+        spec.validate(&datastore())
+            .into_iter()
+            .map(|d| d.kind)
+            .collect()
+    }
+
+    #[test]
+    fn detects_gaps_and_overlaps() {
+        let spec = Spec {
+            iter: r(0, 30),
+            render: FrameExpr::MatchT(vec![
+                (r(0, 10), Box::new(read(0))),
+                (r(5, 20), Box::new(read(0))),
+            ]),
+            output: "out.mp4".to_string(),
+            arrays: BTreeMap::new(),
+            output_config: OutputConfig::default(),
+        };
+        assert_eq!(
+            kinds(&spec),
+            vec![
+                DiagnosticKind::CaseOverlap {
+                    start: Rational64::new(5, 1),
+                    end: Rational64::new(10, 1),
+                },
+                DiagnosticKind::CaseGap {
+                    start: Rational64::new(20, 1),
+                    end: Rational64::new(30, 1),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn detects_bad_sources_and_arity() {
+        let spec = Spec {
+            iter: r(0, 30),
+            render: FrameExpr::F2fFunction {
+                func: F2FType::Filter,
+                sources: vec![read(590)],
+                args: vec![DataExpr::ConstNum(Rational64::new(1, 1))],
+            },
+            output: "out.mp4".to_string(),
+            arrays: BTreeMap::new(),
+            output_config: OutputConfig::default(),
+        };
+        let diagnostics = spec.validate(&datastore());
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics.iter().all(|d| d.is_error()));
+        assert!(matches!(
+            diagnostics[0].kind,
+            DiagnosticKind::F2fArgType { index: 0, .. }
+        ));
+        assert!(matches!(
+            diagnostics[1].kind,
+            DiagnosticKind::SourceOutOfBounds { .. }
+        ));
+        assert_eq!(diagnostics[1].location, "render.sources[0]");
+    }
+
+    #[test]
+    fn reads_up_to_the_end_of_a_source() {
+        let spec = |offset| Spec {
+            iter: r(0, 30),
+            render: read(offset),
+            output: "out.mp4".to_string(),
+            arrays: BTreeMap::new(),
+            output_config: OutputConfig::default(),
+        };
+        assert_eq!(kinds(&spec(570)), vec![]);
+        assert!(matches!(
+            kinds(&spec(571))[..],
+            [DiagnosticKind::SourceOutOfBounds { .. }]
+        ));
+    }
+
+    #[test]
+    fn detects_crops_outside_the_frame() {
+        let spec: Spec = r#"array track = inline(0, 1, [[box(0.1, 0.1, 0.2, 0.2)], null, [box(0.9, 0.5, 0.2, 0.2)]]);
+            Iter=Range(0, 3, 1/24);
+            Render=Crop(vid<videos/clip.mp4>[t], track[t], 12);
+            Output="out.mp4""#
+            .parse()
+            .unwrap();
+        let diagnostics = spec.validate(&datastore());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].location, "render.args[0]");
+        assert_eq!(
+            diagnostics[0].kind,
+            DiagnosticKind::BadCrop {
+                t: Rational64::new(2, 1),
+                reason: "extends outside the frame",
+            }
+        );
+    }
+
+    #[test]
+    fn checks_output_config_against_its_container() {
+        let spec: Spec = r#"Iter=Range(0, 3, 1/24);
+            Render=vid<videos/clip.mp4>[t];
+            Output="out.mp4"{width=1921, height=1080, codec="vp9", crf=70, container="webm"}"#
+            .parse()
+            .unwrap();
+        let reasons: Vec<String> = spec
+            .validate(&datastore())
+            .into_iter()
+            .map(|d| match d.kind {
+                DiagnosticKind::BadOutputConfig { reason } if d.location == "output" => reason,
+                kind => panic!("unexpected diagnostic {:?}", kind),
+            })
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                "1921x1080 is not a positive, even resolution",
+                "VP9 crf must be at most 63",
+                "output out.mp4 doesn't end in .webm",
+            ]
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use clap::{Parser, Subcommand};
 use log::*;
+use num_rational::Rational64;
+use num_traits::ToPrimitive;
 use std::collections::BTreeMap;
 use std::time::Instant;
 
@@ -60,6 +62,8 @@ struct BenchmarkCmd {
 #[derive(Parser, Debug, Clone, clap::ValueEnum, PartialEq)]
 enum OptimizerLevel {
     Unopt,
+    /// Also smart cuts sources whose codec, frame size and pixel format match the spec's output,
+    /// which is 1280x720 H.264 yuv420p unless its `Output` sets them
     Heuristic,
 }
 
@@ -68,6 +72,7 @@ struct PlanCmd {
     #[clap(long)]
     datastore: String,
 
+    /// Spec to plan, either serde JSON or a `.v2v` text file
     #[clap(long)]
     spec: String,
 
@@ -76,6 +81,15 @@ struct PlanCmd {
 
     #[clap(long)]
     run: bool,
+
+    /// Directory of outputs kept between runs, so unchanged parts of the plan aren't rendered
+    /// again. Must be outside /scratch, which is cleared before benchmarks.
+    #[clap(long)]
+    cache_dir: Option<String>,
+
+    /// Size limit of the cache directory, the least recently used outputs are evicted past it
+    #[clap(long, default_value_t = 10240, requires = "cache_dir")]
+    cache_size_mb: u64,
 }
 
 #[derive(Parser, Debug)]
@@ -86,14 +100,69 @@ struct AddVideoCmd {
     #[clap(long)]
     name: String,
 
+    /// Name of the video this one was made from, e.g. by transcoding or drawing on it
     #[clap(long)]
     parent_video: Option<String>,
 
+    /// Parent time shown at this video's start, in seconds (`1001/24000` style fractions work)
+    #[clap(long, default_value = "0", requires = "parent_video")]
+    parent_offset: Rational64,
+
+    /// What was drawn over the parent, so plain reads of the parent never use this video
+    #[clap(long, requires = "parent_video")]
+    overlay: Option<String>,
+
     #[clap(long)]
     video_path: String,
 
+    /// Existing `ffprobe -show_frames -show_streams` output. When left out the video is
+    /// profiled from its packets and the probe is cached next to the datastore.
     #[clap(long)]
-    ffprobe_json: String,
+    ffprobe_json: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct DatastoreCmd {
+    #[clap(long)]
+    datastore: String,
+
+    #[command(subcommand)]
+    cmd: DatastoreSubcmd,
+}
+
+#[derive(Subcommand, Debug)]
+enum DatastoreSubcmd {
+    /// List every video with its codec, size and length
+    List,
+    /// Show a video's range, timing, streams and GOPs
+    Inspect {
+        name: String,
+    },
+    /// Remove a video, which must not have videos derived from it
+    Remove {
+        name: String,
+    },
+    Rename {
+        name: String,
+        new_name: String,
+    },
+    /// Point a video at its file's new location
+    Relink {
+        name: String,
+        path: String,
+        /// Profile the file again if it isn't the one that was profiled, instead of refusing
+        #[clap(long)]
+        reprofile: bool,
+    },
+    /// Check every video's file against its size, modification time and hash when profiled
+    Verify {
+        /// Hash every file, not just ones whose modification time changed
+        #[clap(long)]
+        contents: bool,
+        /// Profile changed files again without asking
+        #[clap(long)]
+        reprofile: bool,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -101,6 +170,45 @@ enum ArgCmd {
     Benchmark(BenchmarkCmd),
     Plan(PlanCmd),
     AddVideo(AddVideoCmd),
+    Datastore(DatastoreCmd),
+}
+
+/// Load a spec from either a `.v2v` text file or serde JSON, along with any arrays it declares
+fn load_spec(path: &str) -> Spec {
+    let text = std::fs::read_to_string(path).unwrap();
+    let mut spec: Spec = if path.ends_with(".v2v") {
+        match text.parse() {
+            Ok(spec) => spec,
+            Err(err) => {
+                error!("Failed to parse {path}: {err}");
+                std::process::exit(1);
+            }
+        }
+    } else {
+        serde_json::from_str(&text).unwrap()
+    };
+
+    let base_dir = std::path::Path::new(path)
+        .parent()
+        .unwrap_or(std::path::Path::new("."));
+    if let Err(err) = spec.load_arrays(base_dir) {
+        error!("Failed to load {path}: {err}");
+        std::process::exit(1);
+    }
+    spec
+}
+
+/// Log every diagnostic for a spec and report whether it is safe to plan
+fn check_spec(spec_name: &str, spec: &Spec, datastore: &Datastore) -> bool {
+    let diagnostics = spec.validate(datastore);
+    for diagnostic in &diagnostics {
+        if diagnostic.is_error() {
+            error!("{spec_name}: {diagnostic}");
+        } else {
+            warn!("{spec_name}: {diagnostic}");
+        }
+    }
+    !diagnostics.iter().any(|d| d.is_error())
 }
 
 fn cmd_benchmark(cmd: BenchmarkCmd) {
@@ -113,24 +221,24 @@ fn cmd_benchmark(cmd: BenchmarkCmd) {
         for entry in std::fs::read_dir("custom_specs").unwrap() {
             let entry = entry.unwrap();
             let spec_name = entry.file_name().into_string().unwrap();
-            let spec = std::fs::read_to_string(format!("custom_specs/{}", spec_name)).unwrap();
-            let spec: Spec = serde_json::from_str(&spec).unwrap();
+            let spec = load_spec(&format!("custom_specs/{}", spec_name));
             eval_specs.push((spec_name.to_string(), spec));
         }
     } else {
         for spec_name in ["S1", "S2", "S3", "S4", "S5", "S6", "S7", "S8"] {
-            let spec = std::fs::read_to_string(format!("specs/{}.json", spec_name)).unwrap();
-            let spec: Spec = serde_json::from_str(&spec).unwrap();
+            let spec = load_spec(&format!("specs/{}.json", spec_name));
             eval_specs.push((spec_name.to_string(), spec));
         }
     }
 
     let eval_specs = eval_specs;
 
+    type SpecFn<T> = Box<dyn Fn(&Spec, &Datastore) -> T>;
+
     struct OptimizationLevel {
         name: &'static str,
-        exec_fn: Box<dyn Fn(&Spec, &Datastore)>,
-        plan_fn: Box<dyn Fn(&Spec, &Datastore) -> String>,
+        exec_fn: SpecFn<()>,
+        plan_fn: SpecFn<String>,
     }
 
     let eval_query = |query_name: &str,
@@ -221,6 +329,14 @@ fn cmd_benchmark(cmd: BenchmarkCmd) {
                 .collect(),
         };
 
+        if !eval_query_set
+            .iter()
+            .all(|q| check_spec(&eval_spec_name, q, &datastore))
+        {
+            error!("Refusing to benchmark invalid spec {eval_spec_name}");
+            std::process::exit(1);
+        }
+
         {
             let mut measures = eval_query(
                 &eval_spec_name,
@@ -243,8 +359,23 @@ fn cmd_plan(cmd: PlanCmd) {
     let datastore = Datastore::load(std::path::Path::new(&cmd.datastore));
     debug!("Loaded datastore!");
 
-    let spec = std::fs::read_to_string(cmd.spec).unwrap();
-    let spec: Spec = serde_json::from_str(&spec).unwrap();
+    let spec = load_spec(&cmd.spec);
+
+    // only sizes and modification times, hashing is left to `datastore verify`
+    for name in datastore.videos_read_by(&spec) {
+        if let Ok(status @ (VideoStatus::Changed(_) | VideoStatus::Missing)) =
+            datastore.check_video(name)
+        {
+            warn!(
+                "{name} no longer matches what was profiled ({status}), so cuts from it may be wrong. Run `datastore verify` to check it."
+            );
+        }
+    }
+
+    if !check_spec(&cmd.spec, &spec, &datastore) {
+        error!("Refusing to plan invalid spec {}", cmd.spec);
+        std::process::exit(1);
+    }
 
     let plan = plan_query(&spec, &datastore);
 
@@ -253,11 +384,55 @@ fn cmd_plan(cmd: PlanCmd) {
         OptimizerLevel::Heuristic => plan.optimize_heuristic(&datastore),
     };
 
-    println!("{}", opt_plan);
+    let parallel = cmd.opt_level != OptimizerLevel::Unopt;
+    match &cmd.cache_dir {
+        Some(cache_dir) => {
+            let cache = match OpCache::open(
+                std::path::Path::new(cache_dir),
+                cmd.cache_size_mb * 1024 * 1024,
+            ) {
+                Ok(cache) => cache,
+                Err(err) => {
+                    error!("Unable to open cache {cache_dir}: {err}");
+                    std::process::exit(1);
+                }
+            };
+            let opt_plan = opt_plan.use_cache(&cache);
+            println!("{}", opt_plan);
+            if cmd.run {
+                opt_plan.run_cached(parallel, &cache);
+            }
+        }
+        None => {
+            println!("{}", opt_plan);
+            if cmd.run {
+                opt_plan.run(parallel);
+            }
+        }
+    }
+}
+
+/// Probes of the videos in a datastore are kept next to it as `<name>.ffprobe.json`
+fn probe_path(datastore: &str, name: &str) -> std::path::PathBuf {
+    let datastore_dir = std::path::Path::new(datastore)
+        .parent()
+        .unwrap_or(std::path::Path::new("."));
+    datastore_dir.join(format!("{}.ffprobe.json", name))
+}
 
-    if cmd.run {
-        opt_plan.run(cmd.opt_level != OptimizerLevel::Unopt);
+/// Path of the probe for the video being added. The video is only profiled again if the probe
+/// was made from a different file, or the file changed since.
+fn cached_probe(cmd: &AddVideoCmd) -> String {
+    let probe_path = probe_path(&cmd.datastore, &cmd.name);
+    let fresh = probe_is_current(&probe_path, &cmd.video_path);
+    if fresh {
+        debug!("Using cached probe {}", probe_path.display());
+    } else {
+        debug!("Probing {}...", cmd.video_path);
+        profile_video(&cmd.video_path, &probe_path);
+        debug!("Probing done!");
     }
+    probe_path.to_string_lossy().to_string()
 }
 
 fn cmd_add_video(cmd: AddVideoCmd) {
@@ -269,27 +444,56 @@ fn cmd_add_video(cmd: AddVideoCmd) {
     } else {
         debug!("No datastore found, creating new one...");
         Datastore {
+            version: DATASTORE_VERSION,
             videos: BTreeMap::new(),
             tree_idxs: BTreeMap::new(),
         }
     };
 
+    let report = |err: DatastoreError| -> ! {
+        match err {
+            DatastoreError::NameTaken(_) => {
+                error!("{err}, use `datastore remove` or `datastore verify` to replace it")
+            }
+            _ => error!("{err}"),
+        }
+        std::process::exit(1);
+    };
+    // checked before probing too, so a clash doesn't overwrite the existing video's probe
+    if datastore.videos.contains_key(&cmd.name) {
+        report(DatastoreError::NameTaken(cmd.name.clone()));
+    }
+    if let Some(parent) = &cmd.parent_video {
+        if !datastore.videos.contains_key(parent) {
+            report(DatastoreError::NoSuchVideo(parent.clone()));
+        }
+    }
+
+    let ffprobe_path = match &cmd.ffprobe_json {
+        Some(path) => path.clone(),
+        None => cached_probe(&cmd),
+    };
     let video_source = VideoSource {
         name: cmd.name.clone(),
         path: cmd.video_path.clone(),
-        ffprobe_path: cmd.ffprobe_json.clone(),
-    };
-
-    let (baseline_path, parent) = match cmd.parent_video {
-        Some(root_video) => {
-            let parent = datastore.videos.get(&root_video).unwrap();
-            (parent.path.clone(), Some(root_video))
-        }
-        None => (video_source.path.clone(), None),
+        ffprobe_path,
     };
 
     debug!("Profiling video...");
-    datastore.add_new_video(&video_source);
+    let added = match cmd.parent_video {
+        Some(parent) => datastore.add_derived_video(
+            &video_source,
+            Derivation {
+                parent,
+                offset: cmd.parent_offset,
+                overlay: cmd.overlay,
+            },
+        ),
+        None => datastore.add_new_video(&video_source),
+    };
+    if let Err(err) = added {
+        report(err);
+    }
     debug!("Profiling video done!");
 
     debug!("Saving datastore...");
@@ -297,6 +501,232 @@ fn cmd_add_video(cmd: AddVideoCmd) {
     debug!("Saved datastore!");
 }
 
+fn cmd_datastore(cmd: DatastoreCmd) {
+    let datastore_path = std::path::Path::new(&cmd.datastore);
+    if !datastore_path.exists() {
+        error!("No datastore at {}", cmd.datastore);
+        std::process::exit(1);
+    }
+    let mut datastore = Datastore::load(datastore_path);
+    let check = |result: Result<(), DatastoreError>| {
+        if let Err(err) = result {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    match cmd.cmd {
+        DatastoreSubcmd::List => {
+            for (name, video) in &datastore.videos {
+                let codec = video.stream.codec.map_or("unsupported", |c| c.name());
+                let dims = match video.stream.dims {
+                    Some((w, h)) => format!("{w}x{h}"),
+                    None => "?".to_string(),
+                };
+                let length = (video.range.end - video.range.start).to_f64().unwrap();
+                println!("{name}\t{codec}\t{dims}\t{length:.3}s\t{}", video.path);
+            }
+            return;
+        }
+        DatastoreSubcmd::Inspect { name } => {
+            check(inspect(&datastore, &name));
+            return;
+        }
+        DatastoreSubcmd::Remove { name } => {
+            let removed = datastore.remove_video(&name);
+            // so a video added under this name later is profiled afresh
+            let probe = probe_path(&cmd.datastore, &name);
+            if removed
+                .as_ref()
+                .is_ok_and(|video| std::path::Path::new(&video.ffprobe_path) == probe)
+            {
+                if let Err(err) = std::fs::remove_file(&probe) {
+                    warn!("Unable to remove {}: {err}", probe.display());
+                }
+            }
+            check(removed.map(|_| ()))
+        }
+        DatastoreSubcmd::Rename { name, new_name } => {
+            check(datastore.rename_video(&name, &new_name));
+            let (probe, new_probe) = (
+                probe_path(&cmd.datastore, &name),
+                probe_path(&cmd.datastore, &new_name),
+            );
+            let video = datastore.videos.get_mut(&new_name).unwrap();
+            if std::path::Path::new(&video.ffprobe_path) == probe {
+                match std::fs::rename(&probe, &new_probe) {
+                    Ok(()) => video.ffprobe_path = new_probe.to_string_lossy().to_string(),
+                    Err(err) => warn!("Unable to move {}: {err}", probe.display()),
+                }
+            }
+        }
+        DatastoreSubcmd::Relink {
+            name,
+            path,
+            reprofile,
+        } => match datastore.relink_video(&name, &path) {
+            Err(DatastoreError::DifferentFile { status, .. }) if reprofile => {
+                info!("{path} is {status}, profiling it again");
+                datastore.videos.get_mut(&name).unwrap().path = path;
+                reprofile_video(&mut datastore, &cmd.datastore, &name);
+            }
+            result => check(result),
+        },
+        DatastoreSubcmd::Verify {
+            contents,
+            reprofile,
+        } => {
+            let mut stale = vec![];
+            let mut missing = 0;
+            let names: Vec<String> = datastore.videos.keys().cloned().collect();
+            for name in names {
+                let status = datastore.verify_video(&name, contents).unwrap();
+                println!("{name}: {status}");
+                match status {
+                    VideoStatus::Unchanged => {}
+                    VideoStatus::Touched => datastore.restamp_video(&name).unwrap(),
+                    VideoStatus::Changed(_) | VideoStatus::Unstamped => stale.push(name),
+                    VideoStatus::Missing => missing += 1,
+                }
+            }
+            if missing > 0 {
+                warn!("{missing} videos are missing, use `datastore relink` if they moved");
+            }
+
+            let reprofile = !stale.is_empty()
+                && (reprofile
+                    || confirm(&format!(
+                        "Profile {} changed or unstamped videos again?",
+                        stale.len()
+                    )));
+            if reprofile {
+                for name in &stale {
+                    reprofile_video(&mut datastore, &cmd.datastore, name);
+                }
+            } else if !stale.is_empty() {
+                error!(
+                    "{} videos need profiling again, run `datastore verify --reprofile`",
+                    stale.len()
+                );
+            }
+            datastore.save(datastore_path);
+            if missing > 0 || (!stale.is_empty() && !reprofile) {
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+    datastore.save(datastore_path);
+}
+
+fn inspect(datastore: &Datastore, name: &str) -> Result<(), DatastoreError> {
+    let status = datastore.verify_video(name, false)?;
+    let video = &datastore.videos[name];
+    println!("{name}");
+    println!("  path: {} ({status})", video.path);
+    println!("  probe: {}", video.ffprobe_path);
+    if let Some(derivation) = &video.derived_from {
+        print!(
+            "  derived from: {} at {}s",
+            derivation.parent, derivation.offset
+        );
+        match &derivation.overlay {
+            Some(overlay) => println!(", with {overlay} drawn over it"),
+            None => println!(),
+        }
+    }
+    let derived = datastore.derived_videos(name);
+    if !derived.is_empty() {
+        let derived: Vec<&str> = derived.into_iter().map(|s| s.as_str()).collect();
+        println!("  derived videos: {}", derived.join(", "));
+    }
+
+    let range = &video.range;
+    println!(
+        "  range: {}s up to {}s every {}s ({} frames)",
+        range.start,
+        range.end,
+        range.step,
+        (range.end - range.start) / range.step
+    );
+    if video.timing.start != Rational64::from_integer(0) || !video.timing.edits.is_empty() {
+        println!(
+            "  first frame at {}s in the file, {} edit list entries",
+            video.timing.start,
+            video.timing.edits.len()
+        );
+    }
+    if video.frame_times.is_some() {
+        println!("  variable frame rate, read on the range's frame grid");
+    }
+
+    let stream = &video.stream;
+    print!(
+        "  video: {}",
+        stream.codec.map_or("unsupported codec", |c| c.name())
+    );
+    if let Some(profile) = &stream.profile {
+        print!(" {profile}");
+    }
+    if let Some(level) = stream.level {
+        print!(" level {level}");
+    }
+    if let Some((w, h)) = stream.dims {
+        print!(", {w}x{h}");
+    }
+    if let Some(pix_fmt) = &stream.pix_fmt {
+        print!(", {pix_fmt}");
+    }
+    if let Some(bit_rate) = stream.bit_rate {
+        print!(", {} kb/s", bit_rate / 1000);
+    }
+    if stream.rotation != 0 {
+        print!(", rotated {}°", stream.rotation);
+    }
+    println!();
+    for audio in &video.audio {
+        print!("  audio: {}", audio.codec);
+        if let Some(sample_rate) = audio.sample_rate {
+            print!(", {sample_rate} Hz");
+        }
+        if let Some(channels) = audio.channels {
+            print!(", {channels} channels");
+        }
+        println!();
+    }
+
+    match video.gop_stats() {
+        Some(gops) => println!(
+            "  gops: {} of {} to {} frames, {:.1} on average, {} open",
+            gops.count, gops.shortest, gops.longest, gops.mean, gops.open
+        ),
+        None => println!("  gops: none"),
+    }
+    Ok(())
+}
+
+/// Profile a video in the datastore again, writing a fresh probe next to the datastore
+fn reprofile_video(datastore: &mut Datastore, datastore_path: &str, name: &str) {
+    let probe_path = probe_path(datastore_path, name);
+    let video_path = datastore.videos[name].path.clone();
+    info!("Profiling {video_path} again...");
+    profile_video(&video_path, &probe_path);
+    datastore
+        .reprofile_video(name, &probe_path.to_string_lossy())
+        .unwrap();
+}
+
+/// Ask a yes/no question on the terminal, or answer no when there's nobody to ask
+fn confirm(question: &str) -> bool {
+    use std::io::IsTerminal;
+    if !std::io::stdin().is_terminal() {
+        return false;
+    }
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
 fn main() {
     pretty_env_logger::init();
     let args = Args::parse();
@@ -306,5 +736,6 @@ fn main() {
         ArgCmd::Benchmark(cmd) => cmd_benchmark(cmd),
         ArgCmd::Plan(cmd) => cmd_plan(cmd),
         ArgCmd::AddVideo(cmd) => cmd_add_video(cmd),
+        ArgCmd::Datastore(cmd) => cmd_datastore(cmd),
     }
 }